@@ -59,14 +59,14 @@ fn main() {
         let t = thread::spawn(move || {
             let mut i = 0;
             loop {
-                if let Ok(_) = rx.try_recv() {
+                if rx.try_recv().is_ok() {
                     break;
                 }
 
                 let r = rng.next_f64();
                 if r < WRITE_PROBABILITY {
                     let key = (id * i).to_string();
-                    cask.put(key, &vec);
+                    cask.put(key, &vec).unwrap();
                 } else {
                     let key = ((base_value + (id * i)) * r as usize).to_string();
                     cask.get(key);
@@ -0,0 +1,485 @@
+use std::cmp;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{Cursor, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use regex::Regex;
+
+use data::{BlobRef, FileHeader, FileKind};
+use errors::{Error, Result};
+use log::find_files;
+use util::{get_file_handle, read_full, ChecksumType};
+
+const BLOB_FILE_EXTENSION: &str = "cask.blob";
+
+const BLOB_CHUNK_SIZE: usize = 64 * 1024;
+// key_size(4) + value_size(8), preceded by the checksum
+const BLOB_RECORD_HEADER_SIZE: usize = 12;
+
+/// An append-only log of blob files. Every value is stored as a record holding its key followed by
+/// the value split into checksummed chunks, which allows values to be written and read in a
+/// streaming fashion without ever holding them in memory.
+pub struct BlobLog {
+    path: PathBuf,
+    sync: bool,
+    size_threshold: u64,
+    checksum: ChecksumType,
+    next_file_id: u32,
+    active_blob_writer: Option<BlobWriter>,
+}
+
+impl BlobLog {
+    pub fn open(path: &Path, sync: bool, size_threshold: usize, checksum: ChecksumType) -> BlobLog {
+        let files = find_blob_files(path);
+
+        BlobLog {
+            path: path.to_path_buf(),
+            sync,
+            size_threshold: size_threshold as u64,
+            checksum,
+            next_file_id: files.last().map_or(0, |file_id| file_id + 1),
+            active_blob_writer: None,
+        }
+    }
+
+    pub fn write<R: Read>(&mut self, key: &[u8], reader: &mut R) -> Result<BlobRef> {
+        let rotate = self.active_blob_writer
+            .as_ref()
+            .is_none_or(|w| w.blob_file_pos >= self.size_threshold);
+
+        if rotate {
+            let blob_writer =
+                BlobWriter::new(&self.path, self.next_file_id, self.sync, self.checksum)?;
+            info!("Created new active blob file {:?}", blob_writer.blob_file_path);
+
+            self.next_file_id += 1;
+            self.active_blob_writer = Some(blob_writer);
+        }
+
+        self.active_blob_writer.as_mut().unwrap().write(key, reader)
+    }
+
+    pub fn active_file_id(&self) -> Option<u32> {
+        self.active_blob_writer.as_ref().map(|w| w.file_id)
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(ref mut blob_writer) = self.active_blob_writer {
+            blob_writer.blob_file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn records(&self, file_id: u32) -> Result<BlobRecords> {
+        BlobRecords::open(&get_blob_file_path(&self.path, file_id), file_id)
+    }
+
+    pub fn remove_file(&self, file_id: u32) -> Result<()> {
+        fs::remove_file(get_blob_file_path(&self.path, file_id))?;
+        Ok(())
+    }
+}
+
+struct BlobWriter {
+    sync: bool,
+    checksum: ChecksumType,
+    file_id: u32,
+    blob_file_path: PathBuf,
+    blob_file: File,
+    blob_file_pos: u64,
+}
+
+impl BlobWriter {
+    fn new(path: &Path, file_id: u32, sync: bool, checksum: ChecksumType) -> Result<BlobWriter> {
+        let blob_file_path = get_blob_file_path(path, file_id);
+        let mut blob_file = get_file_handle(&blob_file_path, true);
+
+        let header = FileHeader::new(FileKind::Blob, checksum);
+        header.write_bytes(&mut blob_file)?;
+
+        Ok(BlobWriter {
+            sync,
+            checksum,
+            file_id,
+            blob_file_path,
+            blob_file,
+            blob_file_pos: header.size(),
+        })
+    }
+
+    fn write<R: Read>(&mut self, key: &[u8], reader: &mut R) -> Result<BlobRef> {
+        let record_pos = self.blob_file_pos;
+
+        match self.write_record(key, reader) {
+            Ok(blob_ref) => Ok(blob_ref),
+            Err(err) => {
+                // discard the partially written record so that the file can still be appended to
+                self.blob_file.set_len(record_pos)?;
+                self.blob_file.seek(SeekFrom::Start(record_pos))?;
+                Err(err)
+            }
+        }
+    }
+
+    fn write_record<R: Read>(&mut self, key: &[u8], reader: &mut R) -> Result<BlobRef> {
+        let checksum = self.checksum;
+        let record_header_size = checksum.size() + BLOB_RECORD_HEADER_SIZE;
+
+        let record_pos = self.blob_file_pos;
+        let value_pos = record_pos + record_header_size as u64 + key.len() as u64;
+
+        // the record header can only be written after the value size is known
+        self.blob_file.seek(SeekFrom::Start(value_pos))?;
+
+        let mut chunk = vec![0u8; BLOB_CHUNK_SIZE];
+        let mut value_size = 0;
+
+        loop {
+            let n = read_full(reader, &mut chunk)?;
+            if n == 0 {
+                break;
+            }
+
+            checksum.write(&mut self.blob_file, checksum.checksum(&chunk[..n]))?;
+            self.blob_file.write_all(&chunk[..n])?;
+            value_size += n as u64;
+
+            if n < chunk.len() {
+                break;
+            }
+        }
+
+        let record_end = self.blob_file.stream_position()?;
+
+        let mut cursor = Cursor::new(Vec::with_capacity(record_header_size + key.len()));
+        cursor.set_position(checksum.size() as u64);
+        cursor.write_u32::<LittleEndian>(key.len() as u32)?;
+        cursor.write_u64::<LittleEndian>(value_size)?;
+        cursor.write_all(key)?;
+
+        let hash = checksum.checksum(&cursor.get_ref()[checksum.size()..]);
+        cursor.set_position(0);
+        checksum.write(&mut cursor, hash)?;
+
+        self.blob_file.seek(SeekFrom::Start(record_pos))?;
+        self.blob_file.write_all(&cursor.into_inner())?;
+        self.blob_file.seek(SeekFrom::Start(record_end))?;
+
+        if self.sync {
+            self.blob_file.sync_data()?;
+        }
+
+        self.blob_file_pos = record_end;
+
+        Ok(BlobRef {
+            file_id: self.file_id,
+            value_pos,
+            value_size,
+        })
+    }
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        if self.sync {
+            self.blob_file.sync_data().unwrap();
+        }
+    }
+}
+
+/// Reads a value stored in a blob file, verifying the checksum of each chunk as it is read.
+pub struct BlobReader {
+    blob_file: File,
+    checksum: ChecksumType,
+    blob_ref: BlobRef,
+    pos: u64,
+    chunk: Vec<u8>,
+    chunk_index: Option<u64>,
+}
+
+impl BlobReader {
+    pub fn open(path: &Path, blob_ref: BlobRef) -> Result<BlobReader> {
+        let mut blob_file = File::open(get_blob_file_path(path, blob_ref.file_id))?;
+        let header = FileHeader::from_read(FileKind::Blob, &mut blob_file)?;
+
+        Ok(BlobReader {
+            blob_file,
+            checksum: header.checksum,
+            blob_ref,
+            pos: 0,
+            chunk: Vec::with_capacity(BLOB_CHUNK_SIZE),
+            chunk_index: None,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.blob_ref.value_size
+    }
+
+    fn load_chunk(&mut self, chunk_index: u64) -> io::Result<()> {
+        let chunk_start = chunk_index * BLOB_CHUNK_SIZE as u64;
+        let chunk_size = cmp::min(BLOB_CHUNK_SIZE as u64,
+                                  self.blob_ref.value_size - chunk_start) as usize;
+        let chunk_pos = self.blob_ref.value_pos +
+                        chunk_index * (self.checksum.size() + BLOB_CHUNK_SIZE) as u64;
+
+        self.blob_file.seek(SeekFrom::Start(chunk_pos))?;
+        let checksum = self.checksum.read(&mut self.blob_file)?;
+
+        self.chunk_index = None;
+        self.chunk.resize(chunk_size, 0);
+        self.blob_file.read_exact(&mut self.chunk)?;
+
+        if self.checksum.checksum(&self.chunk) != checksum {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Invalid checksum for blob chunk at {} in blob \
+                                               file {}",
+                                              chunk_pos,
+                                              self.blob_ref.file_id)));
+        }
+
+        self.chunk_index = Some(chunk_index);
+
+        Ok(())
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.blob_ref.value_size {
+            return Ok(0);
+        }
+
+        let chunk_index = self.pos / BLOB_CHUNK_SIZE as u64;
+        if self.chunk_index != Some(chunk_index) {
+            self.load_chunk(chunk_index)?;
+        }
+
+        let offset = (self.pos % BLOB_CHUNK_SIZE as u64) as usize;
+        let n = cmp::min(buf.len(), self.chunk.len() - offset);
+        buf[..n].copy_from_slice(&self.chunk[offset..offset + n]);
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl Seek for BlobReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => self.blob_ref.value_size as i64 + n,
+            SeekFrom::Current(n) => self.pos as i64 + n,
+        };
+
+        if pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "invalid seek to a negative position"));
+        }
+
+        self.pos = pos as u64;
+
+        Ok(self.pos)
+    }
+}
+
+/// Iterates over the records stored in a blob file, yielding the key and the reference to the
+/// value of each record.
+pub struct BlobRecords {
+    blob_file: File,
+    blob_file_pos: u64,
+    blob_file_size: u64,
+    checksum: ChecksumType,
+    file_id: u32,
+}
+
+impl BlobRecords {
+    fn open(blob_file_path: &Path, file_id: u32) -> Result<BlobRecords> {
+        let mut blob_file = File::open(blob_file_path)?;
+        let blob_file_size = blob_file.metadata()?.len();
+        let header = FileHeader::from_read(FileKind::Blob, &mut blob_file)?;
+
+        Ok(BlobRecords {
+            blob_file,
+            blob_file_pos: header.size(),
+            blob_file_size,
+            checksum: header.checksum,
+            file_id,
+        })
+    }
+
+    fn read_record(&mut self) -> Result<(Vec<u8>, BlobRef)> {
+        self.blob_file.seek(SeekFrom::Start(self.blob_file_pos))?;
+
+        let checksum_size = self.checksum.size();
+
+        let mut header = vec![0u8; checksum_size + BLOB_RECORD_HEADER_SIZE];
+        self.blob_file.read_exact(&mut header)?;
+
+        let mut cursor = Cursor::new(&header[..]);
+        let checksum = self.checksum.read(&mut cursor)?;
+        let key_size = cursor.read_u32::<LittleEndian>()?;
+        let value_size = cursor.read_u64::<LittleEndian>()?;
+
+        let mut key = vec![0u8; key_size as usize];
+        self.blob_file.read_exact(&mut key)?;
+
+        let hash = {
+            let mut hasher = self.checksum.hasher();
+            hasher.update(&header[checksum_size..]);
+            hasher.update(&key);
+            hasher.get()
+        };
+
+        if hash != checksum {
+            return Err(Error::InvalidChecksum {
+                expected: checksum,
+                found: hash,
+            });
+        }
+
+        let value_pos = self.blob_file_pos + header.len() as u64 + key_size as u64;
+        let chunks = value_size.div_ceil(BLOB_CHUNK_SIZE as u64);
+
+        self.blob_file_pos = value_pos + value_size + chunks * checksum_size as u64;
+
+        Ok((key,
+            BlobRef {
+            file_id: self.file_id,
+            value_pos,
+            value_size,
+        }))
+    }
+}
+
+impl Iterator for BlobRecords {
+    type Item = Result<(Vec<u8>, BlobRef)>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, BlobRef)>> {
+        if self.blob_file_pos >= self.blob_file_size {
+            None
+        } else {
+            Some(self.read_record())
+        }
+    }
+}
+
+enum ValueSource {
+    Inline(Cursor<Vec<u8>>),
+    Blob(BlobReader),
+}
+
+/// A reader over a value stored in the database, values stored in blob files are streamed from
+/// disk as they are read.
+pub struct ValueReader(ValueSource);
+
+impl ValueReader {
+    pub fn inline(value: Vec<u8>) -> ValueReader {
+        ValueReader(ValueSource::Inline(Cursor::new(value)))
+    }
+
+    pub fn blob(blob_reader: BlobReader) -> ValueReader {
+        ValueReader(ValueSource::Blob(blob_reader))
+    }
+
+    /// Returns the size of the value in bytes.
+    pub fn len(&self) -> u64 {
+        match self.0 {
+            ValueSource::Inline(ref cursor) => cursor.get_ref().len() as u64,
+            ValueSource::Blob(ref blob_reader) => blob_reader.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0 {
+            ValueSource::Inline(ref mut cursor) => cursor.read(buf),
+            ValueSource::Blob(ref mut blob_reader) => blob_reader.read(buf),
+        }
+    }
+}
+
+impl Seek for ValueReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self.0 {
+            ValueSource::Inline(ref mut cursor) => cursor.seek(pos),
+            ValueSource::Blob(ref mut blob_reader) => blob_reader.seek(pos),
+        }
+    }
+}
+
+pub fn read_blob(path: &Path, blob_ref: BlobRef) -> Result<Vec<u8>> {
+    let mut blob_reader = BlobReader::open(path, blob_ref)?;
+    let mut value = Vec::with_capacity(blob_ref.value_size as usize);
+    blob_reader.read_to_end(&mut value)?;
+    Ok(value)
+}
+
+pub fn get_blob_file_path(path: &Path, file_id: u32) -> PathBuf {
+    let file_id = format!("{:010}", file_id);
+    path.join(file_id).with_extension(BLOB_FILE_EXTENSION)
+}
+
+// `lazy_static` 0.2 initializes statics with the deprecated `ONCE_INIT`
+#[allow(deprecated)]
+pub fn find_blob_files(path: &Path) -> Vec<u32> {
+    lazy_static! {
+        static ref RE: Regex =
+            Regex::new(&format!("(\\d+)\\.{}$", BLOB_FILE_EXTENSION)).unwrap();
+    }
+
+    find_files(path, &RE)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::{Cursor, SeekFrom};
+
+    use blob::{BlobLog, BlobReader, BLOB_CHUNK_SIZE};
+    use util::{ChecksumType, TestDir};
+
+    #[test]
+    fn test_streaming() {
+        let test_dir = TestDir::new("blob-streaming");
+        let path = test_dir.path();
+
+        let value: Vec<u8> = (0..BLOB_CHUNK_SIZE * 3 + 17).map(|i| i as u8).collect();
+
+        let mut blob_log = BlobLog::open(path, false, 1024, ChecksumType::XxHash64);
+        let blob_ref = blob_log.write(b"key", &mut Cursor::new(&value)).unwrap();
+        let empty = blob_log.write(b"empty", &mut Cursor::new(Vec::new())).unwrap();
+
+        let records: Vec<_> = blob_log.records(blob_ref.file_id)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(records, vec![(b"key".to_vec(), blob_ref)]);
+
+        assert_eq!(blob_ref.value_size, value.len() as u64);
+        assert_eq!(empty.value_size, 0);
+        // the first blob file went over the size threshold
+        assert!(empty.file_id > blob_ref.file_id);
+
+        let mut blob_reader = BlobReader::open(path, blob_ref).unwrap();
+        let mut buf = Vec::new();
+        blob_reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, value);
+
+        let pos = BLOB_CHUNK_SIZE as u64 * 2 - 3;
+        blob_reader.seek(SeekFrom::Start(pos)).unwrap();
+        let mut buf = [0u8; 6];
+        blob_reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, &value[pos as usize..pos as usize + 6]);
+    }
+}
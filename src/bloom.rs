@@ -0,0 +1,89 @@
+use std::cmp;
+use std::mem;
+
+/// With 10 bits per key and 7 hash functions about 1% of lookups of absent keys are false
+/// positives.
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+const MIN_CAPACITY: usize = 1024;
+
+/// A Bloom filter over the 64-bit hashes of keys. The bits probed for a key are derived from the
+/// two halves of its hash.
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    len: usize,
+    capacity: usize,
+}
+
+impl BloomFilter {
+    /// Creates a filter sized for `capacity` keys, beyond which its false positive rate grows.
+    pub fn new(capacity: usize) -> BloomFilter {
+        let capacity = cmp::max(capacity, MIN_CAPACITY);
+        let words = (capacity * BITS_PER_KEY).div_ceil(64);
+
+        BloomFilter {
+            bits: vec![0; words],
+            num_bits: words as u64 * 64,
+            len: 0,
+            capacity,
+        }
+    }
+
+    /// The number of keys inserted in the filter.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.bits.capacity() * mem::size_of::<u64>()
+    }
+
+    pub fn insert(&mut self, hash: u64) {
+        for bit in self.probes(hash) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    /// Whether the key of `hash` might have been inserted, false if it definitely wasn't.
+    pub fn contains(&self, hash: u64) -> bool {
+        self.probes(hash).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    fn probes(&self, hash: u64) -> impl Iterator<Item = u64> {
+        let (h1, h2) = (hash & 0xffff_ffff, hash >> 32);
+        let num_bits = self.num_bits;
+        (0..HASHES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bloom::BloomFilter;
+    use util::xxhash64;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(10000);
+
+        for i in 0..10000 {
+            filter.insert(xxhash64(format!("key{}", i).as_bytes()));
+        }
+
+        assert_eq!(filter.len(), 10000);
+
+        for i in 0..10000 {
+            assert!(filter.contains(xxhash64(format!("key{}", i).as_bytes())));
+        }
+
+        let false_positives = (10000..20000)
+            .filter(|i| filter.contains(xxhash64(format!("key{}", i).as_bytes())))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
@@ -1,329 +1,3589 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry as HashMapEntry;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::iter;
+use std::mem;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::vec;
 use std::vec::Vec;
 
-use data::{Entry, Hint, SequenceNumber};
-use log::{Log, LogWriter};
-use stats::Stats;
+use blob;
+use blob::{read_blob, BlobLog, BlobReader, ValueReader};
+use bloom::BloomFilter;
+use compression;
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use compression::{Codec, Codecs};
+use data::{BlobRef, Entry, FileHeader, FileKind, Hint, SequenceNumber, BLOB_REF_SIZE,
+           MAX_KEY_SIZE, MAX_VALUE_SIZE};
+use encryption::{Cipher, Keys};
+use errors::{Error, Result};
+use file_cache::{ValueRef, DEFAULT_MAX_OPEN_FILES};
+use index;
+use index::{CompactMap, IndexMode, SpillRun};
+use log;
+use log::{HintsStatus, Log, LogOptions, DEFAULT_SIZE_THRESHOLD, DEFAULT_WRITE_BUFFER_SIZE};
+use stats::{Stats, StatsSnapshot};
+use util::{xxhash32, xxhash64, ChecksumReader, ChecksumType};
+use value_cache::ValueCache;
 
 const COMPACTION_CHECK_FREQUENCY: u64 = 60;
 const FRAGMENTATION_THRESHOLD: f64 = 0.6;
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
+const INDEX_SHARDS: usize = 16;
+const CHECKPOINT_FILE_NAME: &str = "cask.index";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IndexEntry {
+    pub file_id: u32,
+    pub entry_pos: u64,
+    pub entry_size: u64,
+    pub sequence: SequenceNumber,
+    pub blob_ref: Option<BlobRef>,
+    pub value_size: u64,
+}
+
+impl IndexEntry {
+    /// The entry of `hint`, read from data file `file_id`.
+    fn from_hint(hint: &Hint, file_id: u32) -> IndexEntry {
+        IndexEntry {
+            file_id,
+            entry_pos: hint.entry_pos,
+            entry_size: hint.entry_size,
+            sequence: hint.sequence,
+            blob_ref: hint.blob_ref,
+            value_size: hint.value_len(),
+        }
+    }
+
+    pub fn write_bytes<W: Write>(&self, key: &[u8], writer: &mut W) -> Result<()> {
+        writer.write_u32::<LittleEndian>(key.len() as u32)?;
+        writer.write_all(key)?;
+        writer.write_u32::<LittleEndian>(self.file_id)?;
+        writer.write_u64::<LittleEndian>(self.entry_pos)?;
+        writer.write_u64::<LittleEndian>(self.entry_size)?;
+        writer.write_u64::<LittleEndian>(self.sequence)?;
+        writer.write_u64::<LittleEndian>(self.value_size)?;
+
+        match self.blob_ref {
+            Some(ref blob_ref) => {
+                writer.write_u8(1)?;
+                writer.write_all(&blob_ref.to_bytes())?;
+            }
+            None => writer.write_u8(0)?,
+        }
+
+        Ok(())
+    }
+
+    pub fn from_read<R: Read>(reader: &mut R) -> Result<(Vec<u8>, IndexEntry)> {
+        // the key is read as it comes rather than into a buffer of the size read, which might be
+        // corrupt
+        let key_size = reader.read_u32::<LittleEndian>()? as u64;
+        let mut key = Vec::new();
+        if reader.take(key_size).read_to_end(&mut key)? as u64 != key_size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated key").into());
+        }
+
+        let file_id = reader.read_u32::<LittleEndian>()?;
+        let entry_pos = reader.read_u64::<LittleEndian>()?;
+        let entry_size = reader.read_u64::<LittleEndian>()?;
+        let sequence = reader.read_u64::<LittleEndian>()?;
+        let value_size = reader.read_u64::<LittleEndian>()?;
+
+        let blob_ref = if reader.read_u8()? != 0 {
+            let mut bytes = [0u8; BLOB_REF_SIZE];
+            reader.read_exact(&mut bytes)?;
+            Some(BlobRef::from_bytes(&bytes)?)
+        } else {
+            None
+        };
+
+        Ok((key,
+            IndexEntry {
+                file_id,
+                entry_pos,
+                entry_size,
+                sequence,
+                blob_ref,
+                value_size,
+            }))
+    }
+}
+
+/// The data files loaded into the index of a read-only database, see `Cask::refresh`.
+struct LoadedFiles {
+    /// The data files older than the watermark are indexed by the checkpoint the index was loaded
+    /// from.
+    watermark: u32,
+    /// The data files without a complete hint file might still be written to, their entries are
+    /// read from the data file, up to the given position.
+    files: HashMap<u32, Option<u64>>,
+}
+
+/// A snapshot of the index written by `Cask::checkpoint`.
+struct Checkpoint {
+    sequence: SequenceNumber,
+    /// The data files older than the watermark are fully indexed by the checkpoint, the entries of
+    /// newer files must be replayed.
+    watermark: u32,
+}
+
+/// Writes a checkpoint of `index` for the database at `path`, returning the number of entries
+/// written. The index is written shard by shard, see `Index::checkpoint_shard`, to a temporary
+/// file which replaces the previous checkpoint once synced.
+fn write_checkpoint(path: &Path,
+                    checksum: ChecksumType,
+                    checkpoint: &Checkpoint,
+                    index: &Index)
+                    -> Result<u64> {
+    let checkpoint_file_path = path.join(CHECKPOINT_FILE_NAME);
+    let temp_file_path = log::get_temp_file_path(&checkpoint_file_path);
+    let mut stats = Stats::new();
+    let mut len = 0;
+
+    {
+        let mut checkpoint_file = BufWriter::new(File::create(&temp_file_path)?);
+        let mut hasher = checksum.hasher();
+        let mut buf = Vec::new();
+
+        FileHeader::new(FileKind::Index, checksum).write_bytes(&mut buf)?;
+        buf.write_u64::<LittleEndian>(checkpoint.sequence)?;
+        buf.write_u32::<LittleEndian>(checkpoint.watermark)?;
+        buf.write_u32::<LittleEndian>(index.shards.len() as u32)?;
+
+        for idx in 0..index.shards.len() {
+            let (mut entries, shard_stats) = index.checkpoint_shard(idx, checkpoint.watermark)?;
+            index.read_keys(&mut entries)?;
+            stats.merge(&shard_stats);
+            len += entries.len() as u64;
+
+            buf.write_u64::<LittleEndian>(entries.len() as u64)?;
+
+            for (key, index_entry) in entries {
+                index_entry.write_bytes(&key, &mut buf)?;
+
+                if buf.len() >= DEFAULT_WRITE_BUFFER_SIZE {
+                    hasher.update(&buf);
+                    checkpoint_file.write_all(&buf)?;
+                    buf.clear();
+                }
+            }
+        }
+
+        stats.write_bytes(&mut buf)?;
+        hasher.update(&buf);
+        checkpoint_file.write_all(&buf)?;
+
+        checksum.write(&mut checkpoint_file, hasher.get())?;
+        checkpoint_file.flush()?;
+        checkpoint_file.get_ref().sync_all()?;
+    }
+
+    fs::rename(&temp_file_path, &checkpoint_file_path)?;
+    log::sync_dir(path)?;
+
+    Ok(len)
+}
+
+/// Loads the checkpoint of the database at `path` into `index`, if any. The index must be
+/// discarded if the checkpoint fails to load.
+fn read_checkpoint(path: &Path, index: &Index) -> Result<Option<Checkpoint>> {
+    let checkpoint_file_path = path.join(CHECKPOINT_FILE_NAME);
+
+    if !checkpoint_file_path.is_file() {
+        return Ok(None);
+    }
+
+    info!("Loading index checkpoint: {:?}", checkpoint_file_path);
+
+    let mut checkpoint_file = File::open(&checkpoint_file_path)?;
+    let header = FileHeader::from_read(FileKind::Index, &mut checkpoint_file)?;
+
+    if !header.is_current() {
+        return Err(Error::InvalidFileHeader);
+    }
+
+    let mut hasher = header.checksum.hasher();
+    header.write_bytes(&mut hasher)?;
+
+    let mut reader = ChecksumReader::new(BufReader::new(checkpoint_file), hasher);
+
+    let sequence = reader.read_u64::<LittleEndian>()?;
+    let watermark = reader.read_u32::<LittleEndian>()?;
+    let shards = reader.read_u32::<LittleEndian>()?;
+
+    for _ in 0..shards {
+        let len = reader.read_u64::<LittleEndian>()?;
+
+        for _ in 0..len {
+            let (key, index_entry) = IndexEntry::from_read(&mut reader)?;
+            index.load(key, index_entry);
+        }
+    }
+
+    let mut stats = Stats::from_read(&mut reader)?;
+
+    let hash = reader.checksum();
+    let checksum = header.checksum.read(&mut reader.into_inner())?;
+
+    if hash != checksum {
+        return Err(Error::InvalidChecksum {
+            expected: checksum,
+            found: hash,
+        });
+    }
+
+    // the live stats were accounted in the shards of the entries
+    stats.clear_live();
+    index.shards[0].stats.lock().unwrap().merge(&stats);
+
+    Ok(Some(Checkpoint {
+        sequence,
+        watermark,
+    }))
+}
+
+/// Keys, or stored keys, along with their index entry.
+type KeyedEntries = Vec<(Vec<u8>, IndexEntry)>;
+
+/// Adds the keys and entries of a shard to `all_entries`.
+fn shard_entries(entries: &ShardEntries, all_entries: &mut KeyedEntries) -> Result<()> {
+    match entries.map {
+        IndexMap::Full(ref map) => {
+            all_entries.extend(map.iter()
+                .map(|(key, index_entry)| (key.clone(), index_entry.clone())))
+        }
+        IndexMap::Compact(ref map) => {
+            all_entries.extend(map.iter().map(|(key, index_entry)| (key.to_vec(), index_entry)))
+        }
+    }
+
+    for spilled in &entries.runs {
+        for entry in spilled.iter() {
+            all_entries.push(entry?);
+        }
+    }
+
+    Ok(())
+}
+
+/// The key stored in compact maps for `key`, its fingerprint in hash-only mode, which is written
+/// to `fingerprint`.
+fn stored_key<'a>(mode: IndexMode, key: &'a [u8], fingerprint: &'a mut [u8; 8]) -> &'a [u8] {
+    match mode {
+        IndexMode::HashOnly => {
+            LittleEndian::write_u64(fingerprint, xxhash64(key));
+            fingerprint
+        }
+        _ => key,
+    }
+}
+
+/// The hash of a key for the Bloom filter of the index, given the key stored in compact maps.
+fn filter_hash(mode: IndexMode, stored_key: &[u8]) -> u64 {
+    match mode {
+        IndexMode::HashOnly => LittleEndian::read_u64(stored_key),
+        _ => xxhash64(stored_key),
+    }
+}
+
+/// The entries of an index shard kept in memory, see `IndexMode`.
+enum IndexMap {
+    Full(HashMap<Vec<u8>, IndexEntry>),
+    /// Holds keys, or their fingerprint in hash-only mode.
+    Compact(CompactMap),
+}
+
+/// Where the entry of a key is held, see `Index::find`.
+#[derive(Clone, Copy)]
+enum Location {
+    /// In the map of a full index.
+    Map,
+    /// A record of a compact map.
+    Record(u64),
+    /// A record of a spilled run, given its position in `ShardEntries.runs`.
+    Spilled(usize, u64),
+}
+
+/// A change to the entry of a key, see `Index::modify`.
+enum Change {
+    Keep,
+    Insert(IndexEntry),
+    Remove,
+}
+
+/// A run of entries spilled to disk, see `ShardEntries`.
+struct SpilledRun {
+    run: SpillRun,
+    /// The records of the run whose entry was since replaced or removed.
+    removed: HashSet<u64>,
+}
+
+impl SpilledRun {
+    fn len(&self) -> usize {
+        self.run.len() - self.removed.len()
+    }
+
+    /// Returns the keys and entries of the records which weren't removed, in key order.
+    fn iter<'a>(&'a self) -> impl Iterator<Item = Result<(Vec<u8>, IndexEntry)>> + 'a {
+        self.run
+            .iter()
+            .filter(move |record| match *record {
+                Ok((record, _, _)) => !self.removed.contains(&record),
+                Err(_) => true,
+            })
+            .map(|record| record.map(|(_, key, index_entry)| (key, index_entry)))
+    }
+}
+
+/// The runs written by `ShardEntries::spill`, replacing the last `merged` runs of the shard.
+struct Spill {
+    run: Option<SpillRun>,
+    merged: usize,
+}
+
+/// Merges two iterators of entries sorted by key, passing errors through as they come.
+fn merge_entries<A, B>(a: A, b: B) -> impl Iterator<Item = Result<(Vec<u8>, IndexEntry)>>
+    where A: Iterator<Item = Result<(Vec<u8>, IndexEntry)>>,
+          B: Iterator<Item = Result<(Vec<u8>, IndexEntry)>>
+{
+    let mut a = a.peekable();
+    let mut b = b.peekable();
+
+    iter::from_fn(move || {
+        let a_first = match (a.peek(), b.peek()) {
+            (Some(&Ok((ref a_key, _))), Some(&Ok((ref b_key, _)))) => a_key <= b_key,
+            (Some(_), None) |
+            (Some(&Err(_)), _) => true,
+            (None, _) |
+            (_, Some(&Err(_))) => false,
+        };
+
+        if a_first { a.next() } else { b.next() }
+    })
+}
+
+/// The entries of an index shard. Once the entries kept in memory exceed the memory budget of the
+/// shard they are spilled to disk into a new run, and the runs of similar sizes are merged, so
+/// that every entry is rewritten a logarithmic number of times and lookups only search a few runs.
+struct ShardEntries {
+    mode: IndexMode,
+    map: IndexMap,
+    /// The size of the keys of a full map.
+    key_bytes: usize,
+    /// The spilled runs, from the oldest and largest to the newest.
+    runs: Vec<SpilledRun>,
+    /// Spilling is only retried once the entries kept in memory exceed this size, after it failed.
+    spill_backoff: usize,
+    /// Holds the keys of the shard, and of entries since removed until it is rebuilt.
+    filter: Option<BloomFilter>,
+    /// The number of keys removed since the filter was built.
+    filter_removed: usize,
+}
+
+impl ShardEntries {
+    fn new(mode: IndexMode) -> ShardEntries {
+        ShardEntries {
+            mode,
+            map: ShardEntries::new_map(mode),
+            key_bytes: 0,
+            runs: Vec::new(),
+            spill_backoff: 0,
+            filter: None,
+            filter_removed: 0,
+        }
+    }
+
+    fn new_map(mode: IndexMode) -> IndexMap {
+        match mode {
+            IndexMode::Full => IndexMap::Full(HashMap::new()),
+            IndexMode::Compact => IndexMap::Compact(CompactMap::new(xxhash64)),
+            IndexMode::HashOnly => IndexMap::Compact(CompactMap::new(LittleEndian::read_u64)),
+        }
+    }
+
+    fn len(&self) -> usize {
+        let len = match self.map {
+            IndexMap::Full(ref map) => map.len(),
+            IndexMap::Compact(ref map) => map.len(),
+        };
+
+        len + self.spilled_len()
+    }
+
+    fn spilled_len(&self) -> usize {
+        self.runs.iter().map(|spilled| spilled.len()).sum()
+    }
+
+    /// The memory used by the entries kept in memory, which is approximate for full maps.
+    fn map_memory_size(&self) -> usize {
+        match self.map {
+            IndexMap::Full(ref map) => {
+                map.capacity() * (mem::size_of::<(Vec<u8>, IndexEntry)>() + 1) + self.key_bytes
+            }
+            IndexMap::Compact(ref map) => map.memory_size(),
+        }
+    }
+
+    fn memory_size(&self) -> usize {
+        let runs: usize = self.runs
+            .iter()
+            .map(|spilled| {
+                spilled.run.memory_size() +
+                spilled.removed.capacity() * (mem::size_of::<u64>() + 1)
+            })
+            .sum();
+
+        self.map_memory_size() + runs +
+        self.filter.as_ref().map_or(0, |filter| filter.memory_size())
+    }
+
+    /// Builds a Bloom filter over the keys of the shard, sized for twice as many keys.
+    fn build_filter(&self) -> Result<BloomFilter> {
+        let mut filter = BloomFilter::new(self.len() * 2);
+
+        match self.map {
+            IndexMap::Full(ref map) => {
+                for key in map.keys() {
+                    filter.insert(xxhash64(key));
+                }
+            }
+            IndexMap::Compact(ref map) => {
+                for (key, _) in map.iter() {
+                    filter.insert(filter_hash(self.mode, key));
+                }
+            }
+        }
+
+        for spilled in &self.runs {
+            for entry in spilled.iter() {
+                filter.insert(filter_hash(self.mode, &entry?.0));
+            }
+        }
+
+        Ok(filter)
+    }
+
+    /// Whether the Bloom filter holds more keys than it was sized for, or too many removed keys.
+    fn needs_filter_rebuild(&self) -> bool {
+        self.filter.as_ref().is_some_and(|filter| {
+            filter.len() > filter.capacity() || self.filter_removed > filter.len() / 2
+        })
+    }
+
+    /// Adds an entry for `key` to the map.
+    fn insert(&mut self, key: Cow<[u8]>, index_entry: IndexEntry) {
+        match self.map {
+            IndexMap::Full(ref mut map) => {
+                let key_len = key.len();
+                if map.insert(key.into_owned(), index_entry).is_none() {
+                    self.key_bytes += key_len;
+                }
+            }
+            IndexMap::Compact(ref mut map) => {
+                map.insert(stored_key(self.mode, &key, &mut [0u8; 8]), &index_entry)
+            }
+        }
+    }
+
+    /// Applies `change` to the entry of `key` found at `location`, if any. Only keys which weren't
+    /// found are added to the Bloom filter, the others are already in it.
+    fn change(&mut self, key: Cow<[u8]>, location: Option<Location>, change: Change) {
+        if let (&Change::Remove, Some(_)) = (&change, location) {
+            self.filter_removed += 1;
+        }
+
+        match (change, location) {
+            (Change::Keep, _) |
+            (Change::Remove, None) => {}
+            (Change::Insert(index_entry), None) => {
+                if let Some(ref mut filter) = self.filter {
+                    filter.insert(xxhash64(&key));
+                }
+                self.insert(key, index_entry);
+            }
+            (Change::Insert(index_entry), Some(Location::Map)) => self.insert(key, index_entry),
+            (Change::Insert(index_entry), Some(Location::Record(record))) => {
+                if let IndexMap::Compact(ref mut map) = self.map {
+                    map.set(record, &index_entry);
+                }
+            }
+            (Change::Insert(index_entry), Some(Location::Spilled(run, record))) => {
+                self.runs[run].removed.insert(record);
+                self.insert(key, index_entry);
+            }
+            (Change::Remove, Some(Location::Map)) => {
+                if let IndexMap::Full(ref mut map) = self.map {
+                    map.remove(&*key);
+                    self.key_bytes -= key.len();
+                }
+            }
+            (Change::Remove, Some(Location::Record(record))) => {
+                if let IndexMap::Compact(ref mut map) = self.map {
+                    map.remove(record);
+                }
+            }
+            (Change::Remove, Some(Location::Spilled(run, record))) => {
+                self.runs[run].removed.insert(record);
+            }
+        }
+    }
+
+    /// Writes the entries kept in memory to a new run in the directory at `path`, merged with the
+    /// newest runs as long as they aren't more than twice as large as the run being written. The
+    /// entries are left untouched, see `finish_spill`.
+    fn spill(&self, path: &Path) -> Result<Spill> {
+        let mut entries: KeyedEntries = match self.map {
+            IndexMap::Full(ref map) => {
+                map.iter().map(|(key, index_entry)| (key.clone(), index_entry.clone())).collect()
+            }
+            IndexMap::Compact(ref map) => {
+                map.iter().map(|(key, index_entry)| (key.to_vec(), index_entry)).collect()
+            }
+        };
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut run = SpillRun::create(&index::new_spill_file_path(path),
+                                       entries.into_iter().map(Ok))?;
+        let mut merged = 0;
+
+        for spilled in self.runs.iter().rev() {
+            let len = run.as_ref().map_or(0, |run| run.len());
+            if spilled.len() > len * 2 {
+                break;
+            }
+
+            let entries = run.iter().flat_map(|run| {
+                run.iter().map(|record| record.map(|(_, key, index_entry)| (key, index_entry)))
+            });
+            run = SpillRun::create(&index::new_spill_file_path(path),
+                                   merge_entries(spilled.iter(), entries))?;
+            merged += 1;
+        }
+
+        Ok(Spill {
+            run,
+            merged,
+        })
+    }
+
+    /// Replaces the entries kept in memory and the runs merged by `spill` with the new run.
+    fn finish_spill(&mut self, spill: Spill) {
+        let runs = self.runs.len() - spill.merged;
+        self.runs.truncate(runs);
+
+        if let Some(run) = spill.run {
+            info!("Spilled index entries, {} entries in {} runs",
+                  run.len() + self.spilled_len(),
+                  self.runs.len() + 1);
+
+            self.runs.push(SpilledRun {
+                run,
+                removed: HashSet::new(),
+            });
+        }
+
+        self.map = ShardEntries::new_map(self.mode);
+        self.key_bytes = 0;
+        self.spill_backoff = 0;
+    }
+}
+
+struct IndexShard {
+    entries: RwLock<ShardEntries>,
+    /// The stats of the entries of the shard, merged on read, see `Index::stats`.
+    stats: Mutex<Stats>,
+    /// Serializes the writers of the shard so that the entries of a key are indexed in the order
+    /// of their sequence numbers.
+    writer: Mutex<()>,
+}
+
+/// The in-memory index, sharded by key. Readers only lock the shard of a key while copying its
+/// `IndexEntry` and writers of different shards don't contend with each other.
+///
+/// In hash-only mode the keys of the entries with the fingerprint of a key are read from the data
+/// files of `log`, while the shard is locked. Once the entries of a shard exceed its share of
+/// `memory_budget` they are spilled to disk, in the database directory.
+struct Index {
+    shards: Vec<IndexShard>,
+    mode: IndexMode,
+    memory_budget: Option<usize>,
+    log: Arc<Log>,
+}
+
+impl Index {
+    fn new(mode: IndexMode, memory_budget: Option<usize>, log: Arc<Log>) -> Index {
+        Index {
+            shards: (0..INDEX_SHARDS)
+                .map(|_| {
+                    IndexShard {
+                        entries: RwLock::new(ShardEntries::new(mode)),
+                        stats: Mutex::new(Stats::new()),
+                        writer: Mutex::new(()),
+                    }
+                })
+                .collect(),
+            mode,
+            memory_budget,
+            log,
+        }
+    }
+
+    /// Finds the entry of `key` in `entries`, reading the keys of the entries with the same
+    /// fingerprint in hash-only mode.
+    fn find(&self, entries: &ShardEntries, key: &[u8]) -> Result<Option<(Location, IndexEntry)>> {
+        if let Some(ref filter) = entries.filter {
+            if !filter.contains(xxhash64(key)) {
+                return Ok(None);
+            }
+        }
+
+        let mut fingerprint = [0u8; 8];
+        let stored_key = stored_key(self.mode, key, &mut fingerprint);
+
+        match entries.map {
+            IndexMap::Full(ref map) => {
+                if let Some(index_entry) = map.get(key) {
+                    return Ok(Some((Location::Map, index_entry.clone())));
+                }
+            }
+            IndexMap::Compact(ref map) => {
+                for record in map.find(stored_key) {
+                    let index_entry = map.get(record);
+                    if self.is_entry_of(key, &index_entry)? {
+                        return Ok(Some((Location::Record(record), index_entry)));
+                    }
+                }
+            }
+        }
+
+        for (idx, spilled) in entries.runs.iter().enumerate().rev() {
+            for record in spilled.run.find(stored_key) {
+                let record = record?;
+                if spilled.removed.contains(&record) {
+                    continue;
+                }
+
+                let index_entry = spilled.run.get(record)?;
+                if self.is_entry_of(key, &index_entry)? {
+                    return Ok(Some((Location::Spilled(idx, record), index_entry)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Whether `index_entry`, found by the stored key of `key`, is the entry of `key`. In hash-only
+    /// mode its key is read from the data file, since other keys may have the same fingerprint.
+    fn is_entry_of(&self, key: &[u8], index_entry: &IndexEntry) -> Result<bool> {
+        if self.mode != IndexMode::HashOnly {
+            return Ok(true);
+        }
+
+        match self.log.read_key(index_entry.file_id, index_entry.entry_pos) {
+            // the data file was compacted by the writer of a read-only database, the fingerprint is
+            // trusted until the index is refreshed
+            Err(ref err) if is_not_found(err) => Ok(true),
+            result => Ok(result? == key),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &IndexShard {
+        &self.shards[xxhash32(key) as usize % INDEX_SHARDS]
+    }
+
+    /// Locks the writers of the shard of `key`, the lock must be held from the moment an entry for
+    /// `key` is given a sequence number until it is indexed.
+    fn lock_writer<'a>(&'a self, key: &[u8]) -> MutexGuard<'a, ()> {
+        self.shard(key).writer.lock().unwrap()
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<IndexEntry>> {
+        let entries = self.shard(key).entries.read().unwrap();
+        Ok(self.find(&entries, key)?.map(|(_, index_entry)| index_entry))
+    }
+
+    /// Returns the current entries of the stored key `stored_key` in shard `idx`, there can be
+    /// several with the same fingerprint in hash-only mode.
+    fn find_stored(&self, idx: usize, stored_key: &[u8]) -> Result<Vec<IndexEntry>> {
+        let entries = self.shards[idx].entries.read().unwrap();
+
+        let mut found = match entries.map {
+            IndexMap::Full(ref map) => map.get(stored_key).into_iter().cloned().collect(),
+            IndexMap::Compact(ref map) => {
+                map.find(stored_key).map(|record| map.get(record)).collect::<Vec<_>>()
+            }
+        };
+
+        for spilled in &entries.runs {
+            for record in spilled.run.find(stored_key) {
+                let record = record?;
+                if !spilled.removed.contains(&record) {
+                    found.push(spilled.run.get(record)?);
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Returns the stored keys and entries of every shard along with the shard they belong to,
+    /// see `Cask::iter`, in file order.
+    fn snapshot(&self) -> Result<Vec<(usize, Vec<u8>, IndexEntry)>> {
+        let mut snapshot = Vec::with_capacity(self.len());
+        for (idx, shard) in self.shards.iter().enumerate() {
+            let mut all_entries = Vec::new();
+            shard_entries(&shard.entries.read().unwrap(), &mut all_entries)?;
+            snapshot.extend(all_entries.into_iter()
+                .map(|(stored_key, index_entry)| (idx, stored_key, index_entry)));
+        }
+
+        // reading in file order turns random reads into mostly sequential ones
+        snapshot.sort_by_key(|(_, _, index_entry)| {
+            (index_entry.file_id, index_entry.entry_pos)
+        });
+
+        Ok(snapshot)
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.entries.read().unwrap().len()).sum()
+    }
+
+    /// The memory used by the index in bytes, and the number of entries spilled to disk.
+    fn memory_size(&self) -> (usize, usize) {
+        self.shards.iter().fold((0, 0), |(memory_size, spilled), shard| {
+            let entries = shard.entries.read().unwrap();
+            (memory_size + entries.memory_size(), spilled + entries.spilled_len())
+        })
+    }
+
+    /// Builds a Bloom filter over the keys of every shard, so that lookups of most absent keys
+    /// don't have to read keys from the data files or spilled runs.
+    fn build_filters(&self) {
+        for shard in &self.shards {
+            let _writer = shard.writer.lock().unwrap();
+            self.build_filter(shard);
+        }
+    }
+
+    /// Builds the Bloom filter of `shard`, replacing the current one. The writer of the shard must
+    /// be locked, the filter is built while readers can still look entries up. The filter is
+    /// dropped if the spilled entries can't be read, lookups then search all entries.
+    fn build_filter(&self, shard: &IndexShard) {
+        let filter = shard.entries.read().unwrap().build_filter();
+
+        let mut entries = shard.entries.write().unwrap();
+        match filter {
+            Ok(filter) => entries.filter = Some(filter),
+            Err(err) => {
+                warn!("Failed to build the Bloom filter of the index: {}", err);
+                entries.filter = None;
+            }
+        }
+        entries.filter_removed = 0;
+    }
+
+    /// Spills the entries of `shard` kept in memory to disk if they exceed its share of the memory
+    /// budget. The writer of the shard must be locked, the runs are written while readers can
+    /// still look entries up. Once spilling fails it is only retried when the entries kept in
+    /// memory doubled.
+    fn spill(&self, shard: &IndexShard) {
+        let memory_budget = match self.memory_budget {
+            Some(memory_budget) => memory_budget / INDEX_SHARDS,
+            None => return,
+        };
+
+        let (spill, memory_size) = {
+            let entries = shard.entries.read().unwrap();
+            let memory_size = entries.map_memory_size();
+
+            if memory_size <= cmp::max(memory_budget, entries.spill_backoff) {
+                return;
+            }
+
+            (entries.spill(&self.log.path), memory_size)
+        };
+
+        let mut entries = shard.entries.write().unwrap();
+        match spill {
+            Ok(spill) => entries.finish_spill(spill),
+            Err(err) => {
+                warn!("Failed to spill index entries: {}", err);
+                entries.spill_backoff = memory_size * 2;
+            }
+        }
+    }
+
+    /// Changes the entry of `key` to the one returned by `f`, which is given the current entry,
+    /// returning the current entry. The writer of the shard of `key` must be locked. Nothing is
+    /// changed if the current entry can't be looked up.
+    fn modify<F>(&self, key: Cow<[u8]>, f: F) -> Result<Option<IndexEntry>>
+        where F: FnOnce(Option<&IndexEntry>, &mut Stats) -> Change
+    {
+        let shard = self.shard(&key);
+
+        // keys are read from the data files with a read lock, the entry can't change meanwhile
+        // since the writer of the shard is locked
+        let found = match self.mode {
+            IndexMode::HashOnly => self.find(&shard.entries.read().unwrap(), &key)?,
+            _ => None,
+        };
+
+        let (current, rebuild_filter) = {
+            let mut stats = shard.stats.lock().unwrap();
+            let mut entries = shard.entries.write().unwrap();
+
+            let found = match self.mode {
+                IndexMode::HashOnly => found,
+                _ => self.find(&entries, &key)?,
+            };
+
+            let (location, current) = match found {
+                Some((location, current)) => (Some(location), Some(current)),
+                None => (None, None),
+            };
+
+            let change = f(current.as_ref(), &mut stats);
+            entries.change(key, location, change);
+
+            (current, entries.needs_filter_rebuild())
+        };
+
+        if rebuild_filter {
+            self.build_filter(shard);
+        }
+
+        self.spill(shard);
+
+        Ok(current)
+    }
+
+    fn insert(&self, key: Vec<u8>, index_entry: IndexEntry) -> Result<Option<IndexEntry>> {
+        self.modify(Cow::Owned(key), |current, stats| {
+            match current {
+                Some(current) => stats.replace_entry(current, &index_entry),
+                None => stats.add_entry(&index_entry),
+            }
+            Change::Insert(index_entry)
+        })
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<Option<IndexEntry>> {
+        self.modify(Cow::Borrowed(key), |current, stats| {
+            match current {
+                Some(current) => {
+                    stats.remove_entry(current);
+                    Change::Remove
+                }
+                None => Change::Keep,
+            }
+        })
+    }
+
+    /// Inserts an entry loaded from a checkpoint, whose keys are unique, only accounting it in the
+    /// live stats since the stats of its data file are loaded with the checkpoint.
+    fn load(&self, key: Vec<u8>, index_entry: IndexEntry) {
+        let shard = self.shard(&key);
+        let _writer = shard.writer.lock().unwrap();
+        shard.stats.lock().unwrap().add_live_entry(&index_entry);
+        shard.entries.write().unwrap().insert(Cow::Owned(key), index_entry);
+
+        self.spill(shard);
+    }
+
+    /// Returns the stats of all shards.
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::new();
+        for shard in &self.shards {
+            stats.merge(&shard.stats.lock().unwrap());
+        }
+        stats
+    }
+
+    /// Accounts a blob file, see `Stats::add_blob_file`.
+    fn add_blob_file(&self, file_id: u32) {
+        self.shards[0].stats.lock().unwrap().add_blob_file(file_id);
+    }
+
+    fn remove_blob_file(&self, file_id: u32) {
+        for shard in &self.shards {
+            shard.stats.lock().unwrap().remove_blob_file(file_id);
+        }
+    }
+
+    /// Returns all keys and their entries. The keys are fingerprints in hash-only mode, see
+    /// `read_keys`.
+    #[cfg(test)]
+    fn entries(&self) -> KeyedEntries {
+        let mut all_entries = Vec::with_capacity(self.len());
+        for shard in &self.shards {
+            shard_entries(&shard.entries.read().unwrap(), &mut all_entries).unwrap();
+        }
+        all_entries
+    }
+
+    /// Returns the entries of shard `idx` in the data files older than `watermark`, along with the
+    /// stats of the shard, see `Cask::checkpoint`. The writers of the shard are locked so that the
+    /// stats match the entries. Entries written since the checkpoint started are left out, along
+    /// with their stats, as they are replayed when the checkpoint is loaded. The keys are
+    /// fingerprints in hash-only mode, see `read_keys`.
+    fn checkpoint_shard(&self,
+                        idx: usize,
+                        watermark: u32)
+                        -> Result<(KeyedEntries, Stats)> {
+        let shard = &self.shards[idx];
+        let _writer = shard.writer.lock().unwrap();
+
+        let mut all_entries = Vec::new();
+        shard_entries(&shard.entries.read().unwrap(), &mut all_entries)?;
+
+        let mut stats = shard.stats.lock().unwrap().clone();
+        stats.retain_files(watermark);
+
+        all_entries.retain(|(_, index_entry)| {
+            if index_entry.file_id < watermark {
+                return true;
+            }
+
+            if let Some(ref blob_ref) = index_entry.blob_ref {
+                stats.unaccount_blob(blob_ref);
+            }
+            false
+        });
+
+        Ok((all_entries, stats))
+    }
+
+    /// Replaces the fingerprints returned by `entries` in hash-only mode with the keys read from
+    /// the data files, which must not be compacted meanwhile.
+    fn read_keys(&self, entries: &mut [(Vec<u8>, IndexEntry)]) -> Result<()> {
+        if self.mode != IndexMode::HashOnly {
+            return Ok(());
+        }
+
+        // reading in file order turns random reads into mostly sequential ones
+        entries.sort_by_key(|(_, index_entry)| (index_entry.file_id, index_entry.entry_pos));
+
+        for &mut (ref mut key, ref index_entry) in entries.iter_mut() {
+            *key = self.log.read_key(index_entry.file_id, index_entry.entry_pos)?;
+        }
+
+        Ok(())
+    }
+
+    fn update(&self, hint: Hint, file_id: u32) -> Result<()> {
+        let index_entry = IndexEntry::from_hint(&hint, file_id);
+
+        let _writer = self.lock_writer(&hint.key);
+
+        self.modify(Cow::Borrowed(&hint.key), |current, stats| {
+            match current {
+                Some(current) if current.sequence > hint.sequence => {
+                    stats.add_entry(&index_entry);
+                    stats.remove_entry(&index_entry);
+                    Change::Keep
+                }
+                Some(current) if hint.deleted => {
+                    stats.remove_entry(current);
+                    Change::Remove
+                }
+                Some(current) => {
+                    stats.replace_entry(current, &index_entry);
+                    Change::Insert(index_entry)
+                }
+                None if hint.deleted => Change::Keep,
+                None => {
+                    stats.add_entry(&index_entry);
+                    Change::Insert(index_entry)
+                }
+            }
+        })?;
+
+        Ok(())
+    }
+
+    /// Points the entry of the key of `hint`, copied from data file `old_file_id` to `file_id` by
+    /// a compaction, to the copy. Keys written or deleted since the copy keep their current entry,
+    /// which is told apart by its sequence number, and the copy is accounted as dead.
+    fn relocate(&self, hint: Hint, file_id: u32, old_file_id: u32) -> Result<()> {
+        let index_entry = IndexEntry::from_hint(&hint, file_id);
+
+        let _writer = self.lock_writer(&hint.key);
+
+        self.modify(Cow::Borrowed(&hint.key), |current, stats| {
+            match current {
+                Some(current) if !hint.deleted && current.file_id == old_file_id &&
+                                 current.sequence == hint.sequence => {
+                    stats.replace_entry(current, &index_entry);
+                    Change::Insert(index_entry)
+                }
+                None if hint.deleted => Change::Keep,
+                _ => {
+                    stats.add_entry(&index_entry);
+                    stats.remove_entry(&index_entry);
+                    Change::Keep
+                }
+            }
+        })?;
+
+        Ok(())
+    }
+}
+
+struct CaskInner {
+    current_sequence: AtomicU64,
+    codecs: Codecs,
+    index: Index,
+    log: Arc<Log>,
+    value_cache: Option<ValueCache>,
+    /// Only set for read-only databases.
+    loaded_files: Option<Mutex<LoadedFiles>>,
+}
+
+impl CaskInner {
+    fn next_sequence(&self) -> SequenceNumber {
+        self.current_sequence.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Looks up the index entry of `key` and reads it with `f`. Files are removed after being
+    /// compacted, once the index points to the compacted file, so reads of files that were removed
+    /// since the lookup are retried with the updated index entry.
+    ///
+    /// Read-only databases only learn of compactions by loading the files written since their
+    /// index was loaded, so a read of a removed file refreshes the whole index once, as
+    /// `Cask::refresh`, and is then retried: the read also sees every later write of the writer.
+    fn read<T, F>(&self, key: &[u8], mut f: F) -> Option<T>
+        where F: FnMut(&IndexEntry) -> Result<Option<T>>
+    {
+        let mut refreshed = false;
+
+        loop {
+            let index_entry = self.lookup(key)?;
+
+            match f(&index_entry) {
+                Err(ref err) if is_not_found(err) &&
+                                self.lookup(key).as_ref() != Some(&index_entry) => {}
+                // in read-only databases the file was compacted by the writer, into a data file
+                // which isn't loaded yet
+                Err(ref err) if is_not_found(err) && self.loaded_files.is_some() &&
+                                !refreshed => {
+                    self.refresh();
+                    refreshed = true;
+                }
+                Err(err) => {
+                    warn!("Failed to read the value of key {:?}: {}", key, err);
+                    return None;
+                }
+                Ok(value) => return value,
+            }
+        }
+    }
+
+    /// Looks up the index entry of `key`. Lookups which fail, e.g. because a key can't be read from
+    /// its data file in hash-only mode, are logged and reported as missing.
+    fn lookup(&self, key: &[u8]) -> Option<IndexEntry> {
+        self.index.get(key).unwrap_or_else(|err| {
+            warn!("Failed to look up key {:?}: {}", key, err);
+            None
+        })
+    }
+
+    /// Loads the entries written since the index of a read-only database was last loaded.
+    fn refresh(&self) {
+        if let Some(ref loaded_files) = self.loaded_files {
+            let sequence = refresh_index(&self.log, &self.index, &mut loaded_files.lock().unwrap());
+            self.current_sequence.fetch_max(sequence + 1, Ordering::SeqCst);
+        }
+    }
+
+    fn read_index_entry<'a>(&self,
+                            key: &[u8],
+                            index_entry: &IndexEntry)
+                            -> Result<Option<Entry<'a>>> {
+        let entry = self.log.read_entry(index_entry.file_id, index_entry.entry_pos)?;
+        check_key(&entry, key)?;
+        if entry.deleted {
+            warn!("Index pointed to dead entry: Entry {{ key: {:?}, sequence: {} }} at file: {}",
+                  entry.key,
+                  entry.sequence,
+                  index_entry.file_id);
+            Ok(None)
+        } else {
+            Ok(Some(entry))
+        }
+    }
+
+    fn value(&self, entry: Entry) -> Result<Vec<u8>> {
+        match entry.blob_ref() {
+            Some(blob_ref) => read_blob(&self.log.path, blob_ref),
+            None if entry.compressed => compression::decompress(&self.codecs, &entry.value),
+            None => Ok(entry.value.into_owned()),
+        }
+    }
+
+    fn read_value(&self, key: &[u8], index_entry: &IndexEntry) -> Result<Option<Vec<u8>>> {
+        match self.read_index_entry(key, index_entry)? {
+            Some(entry) => Ok(Some(self.value(entry)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the value of `key` from `index_entry`, or from the value cache if it holds the value
+    /// of that entry.
+    fn read_cached_value(&self, key: &[u8], index_entry: &IndexEntry) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.cached_value(key, index_entry) {
+            return Ok(Some(value.to_vec()));
+        }
+
+        let value = self.read_value(key, index_entry)?;
+        if let Some(ref value) = value {
+            self.cache_value(key, index_entry, value);
+        }
+
+        Ok(value)
+    }
+
+    /// Returns the value of `key` held by the value cache for `index_entry`, if any.
+    fn cached_value(&self, key: &[u8], index_entry: &IndexEntry) -> Option<Arc<[u8]>> {
+        self.value_cache
+            .as_ref()
+            .and_then(|value_cache| value_cache.get(key, index_entry.sequence))
+    }
+
+    /// Caches the value of `key` read from `index_entry`, if values are cached.
+    fn cache_value(&self, key: &[u8], index_entry: &IndexEntry, value: &[u8]) {
+        if let Some(ref value_cache) = self.value_cache {
+            value_cache.insert(key, index_entry.sequence, value);
+        }
+    }
+
+    /// Removes the cached value of `key`, must be called once its entry changed.
+    fn uncache(&self, key: &[u8]) {
+        if let Some(ref value_cache) = self.value_cache {
+            value_cache.remove(key);
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.read(key, |index_entry| self.read_cached_value(key, index_entry))
+    }
+
+    fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<Option<Vec<u8>>> {
+        let mut reads: Vec<_> = keys.iter()
+            .enumerate()
+            .filter_map(|(i, key)| self.lookup(key.as_ref()).map(|index_entry| (i, index_entry)))
+            .collect();
+
+        // reading in file order turns random reads into mostly sequential ones
+        reads.sort_by_key(|(_, index_entry)| (index_entry.file_id, index_entry.entry_pos));
+
+        let mut values = vec![None; keys.len()];
+        for (i, index_entry) in reads {
+            values[i] = match self.read_cached_value(keys[i].as_ref(), &index_entry) {
+                // the file was compacted since the lookup
+                Err(ref err) if is_not_found(err) => self.get(keys[i].as_ref()),
+                Err(err) => {
+                    warn!("Failed to read the value of key {:?}: {}", keys[i].as_ref(), err);
+                    None
+                }
+                Ok(value) => value,
+            };
+        }
+
+        values
+    }
+
+    /// Values borrowed from memory-mapped data files are not cached, they're read without copies.
+    fn get_ref(&self, key: &[u8]) -> Option<ValueRef> {
+        self.read(key, |index_entry| {
+            if let Some(value) = self.cached_value(key, index_entry) {
+                return Ok(Some(ValueRef::Cached(value)));
+            }
+
+            let (file_id, entry_pos) = (index_entry.file_id, index_entry.entry_pos);
+
+            if let Some(value) = self.log.read_value_ref(file_id, key, entry_pos)? {
+                return Ok(Some(value));
+            }
+
+            let value = self.read_value(key, index_entry)?;
+            if let Some(ref value) = value {
+                self.cache_value(key, index_entry, value);
+            }
+
+            Ok(value.map(ValueRef::from))
+        })
+    }
+
+    fn get_into(&self, key: &[u8], buf: &mut Vec<u8>) -> bool {
+        self.read(key, |index_entry| {
+            if let Some(value) = self.cached_value(key, index_entry) {
+                buf.clear();
+                buf.extend_from_slice(&value);
+                return Ok(Some(()));
+            }
+
+            let (file_id, entry_pos) = (index_entry.file_id, index_entry.entry_pos);
+
+            if let Some(value) = self.log.read_value_ref(file_id, key, entry_pos)? {
+                buf.clear();
+                buf.extend_from_slice(&value);
+                return Ok(Some(()));
+            }
+
+            let value = {
+                let entry = self.log.read_entry_into(file_id, entry_pos, buf)?;
+                check_key(&entry, key)?;
+                match entry.value {
+                    // the value was read into `buf` as is
+                    Cow::Borrowed(_) if !entry.blob && !entry.compressed => None,
+                    _ => Some(self.value(entry)?),
+                }
+            };
+
+            if let Some(value) = value {
+                *buf = value;
+            }
+
+            self.cache_value(key, index_entry, buf);
+
+            Ok(Some(()))
+        })
+            .is_some()
+    }
+
+    fn get_reader(&self, key: &[u8]) -> Option<ValueReader> {
+        self.read(key, |index_entry| {
+            let entry = match self.read_index_entry(key, index_entry)? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+
+            let value_reader = match entry.blob_ref() {
+                Some(blob_ref) => ValueReader::blob(BlobReader::open(&self.log.path, blob_ref)?),
+                None => ValueReader::inline(self.value(entry)?),
+            };
+
+            Ok(Some(value_reader))
+        })
+    }
+
+    /// Starts a checkpoint of the index. Writers are locked while the active data file is
+    /// rotated so that every entry of the previous data files is indexed, later entries are
+    /// written to the new active data file.
+    fn start_checkpoint(&self) -> Checkpoint {
+        let _writers: Vec<_> =
+            self.index.shards.iter().map(|shard| shard.writer.lock().unwrap()).collect();
+
+        let watermark = self.log.rotate();
+        let sequence = self.current_sequence.load(Ordering::SeqCst) - 1;
+
+        Checkpoint {
+            sequence,
+            watermark,
+        }
+    }
+
+    fn put(&self, key: Vec<u8>, value: &[u8], compressed: bool) -> Result<()> {
+        let _writer = self.index.lock_writer(&key);
+
+        let index_entry = {
+            let sequence = self.next_sequence();
+            let entry = if compressed {
+                Entry::compressed(sequence, &*key, value)
+            } else {
+                Entry::new(sequence, &*key, value)
+            };
+            self.append_entry(&entry)
+        };
+
+        self.uncache(&key);
+        self.index.insert(key, index_entry)?;
+
+        Ok(())
+    }
+
+    fn put_blob(&self, key: Vec<u8>, blob_ref: &BlobRef) -> Result<()> {
+        let _writer = self.index.lock_writer(&key);
+        self.append_blob(key, blob_ref)
+    }
+
+    /// Points `key` to the blob at `new_blob_ref`, unless it no longer points to the blob at
+    /// `blob_ref`.
+    fn relocate_blob(&self, key: Vec<u8>, blob_ref: BlobRef, new_blob_ref: &BlobRef) -> Result<()> {
+        let _writer = self.index.lock_writer(&key);
+
+        if self.index.get(&key)?.and_then(|e| e.blob_ref) == Some(blob_ref) {
+            self.append_blob(key, new_blob_ref)?;
+        }
+
+        Ok(())
+    }
+
+    fn append_blob(&self, key: Vec<u8>, blob_ref: &BlobRef) -> Result<()> {
+        let index_entry = {
+            let entry = Entry::blob(self.next_sequence(), &*key, blob_ref);
+            self.append_entry(&entry)
+        };
+
+        self.uncache(&key);
+        self.index.insert(key, index_entry)?;
+
+        Ok(())
+    }
+
+    fn append_entry(&self, entry: &Entry) -> IndexEntry {
+        let (file_id, file_pos, entry_size) = self.log.append_entry(entry);
+
+        IndexEntry {
+            file_id,
+            entry_pos: file_pos,
+            entry_size,
+            sequence: entry.sequence,
+            blob_ref: entry.blob_ref(),
+            value_size: entry.value_len(),
+        }
+    }
+
+    fn delete(&self, key: &[u8]) -> Result<()> {
+        let _writer = self.index.lock_writer(key);
+
+        self.uncache(key);
+
+        if self.index.remove(key)?.is_some() {
+            let entry = Entry::deleted(self.next_sequence(), key);
+            let _ = self.log.append_entry(&entry);
+        }
+
+        Ok(())
+    }
+}
+
+/// Progress of opening a database, reported to the callback set with `CaskOptions::open_progress`
+/// before any hint file is loaded and after every data file whose hints were loaded.
+#[derive(Clone, Debug, Default)]
+pub struct OpenProgress {
+    /// The number of data files whose hints were loaded.
+    pub files_processed: usize,
+    /// The number of data files whose hints are loaded, the data files covered by an index
+    /// checkpoint are not.
+    pub files_total: usize,
+    /// The size in bytes of the data files whose hints were loaded.
+    pub bytes_scanned: u64,
+    /// The size in bytes of all data files whose hints are loaded.
+    pub bytes_total: u64,
+    /// The number of hint files which were missing or corrupt and had to be re-created from their
+    /// data file.
+    pub hints_rebuilt: usize,
+    /// The number of hint files left unfinished by a crash, as their data file was still being
+    /// written to, which were finished from their data file.
+    pub hints_finished: usize,
+    /// The time elapsed since the database started opening.
+    pub elapsed: Duration,
+}
+
+/// Summary of how a database was opened, see `Cask::open_summary`.
+#[derive(Clone, Debug, Default)]
+pub struct OpenSummary {
+    /// The time it took to open the database and recover its index.
+    pub elapsed: Duration,
+    /// Whether the index was loaded from a checkpoint.
+    pub checkpoint: bool,
+    /// The number of data files whose hints were loaded.
+    pub files_loaded: usize,
+    /// The size in bytes of the data files whose hints were loaded.
+    pub bytes_scanned: u64,
+    /// The ids of the data files whose hint file had to be re-created.
+    pub recreated_hint_files: Vec<u32>,
+    /// The ids of the data files whose hint file was left unfinished and had to be finished.
+    pub finished_hint_files: Vec<u32>,
+    /// The number of keys in the database once opened.
+    pub keys: usize,
+}
+
+#[derive(Clone)]
+struct OpenProgressCallback(Arc<dyn Fn(&OpenProgress) + Send + Sync>);
+
+impl fmt::Debug for OpenProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OpenProgressCallback")
+    }
+}
+
+/// Options and flags which can be used to configure how a `Cask` database is opened.
+#[derive(Clone, Debug)]
+pub struct CaskOptions {
+    sync: bool,
+    max_key_size: usize,
+    max_value_size: usize,
+    blob_threshold: Option<usize>,
+    compression: Option<Arc<dyn Codec>>,
+    compression_threshold: usize,
+    codecs: Codecs,
+    keys: Keys,
+    checksum: ChecksumType,
+    max_open_files: usize,
+    mmap: bool,
+    write_buffer_size: usize,
+    size_threshold: usize,
+    preallocate: bool,
+    max_free_files: usize,
+    open_threads: usize,
+    checkpoint_interval: Option<Duration>,
+    open_progress: Option<OpenProgressCallback>,
+    index_mode: IndexMode,
+    index_memory_budget: Option<usize>,
+    value_cache_size: Option<usize>,
+    bloom_filter: bool,
+}
+
+impl Default for CaskOptions {
+    fn default() -> CaskOptions {
+        CaskOptions {
+            sync: false,
+            max_key_size: MAX_KEY_SIZE,
+            max_value_size: MAX_VALUE_SIZE,
+            blob_threshold: None,
+            compression: None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            codecs: Codecs::new(),
+            keys: Keys::new(),
+            checksum: ChecksumType::default(),
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            mmap: false,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            size_threshold: DEFAULT_SIZE_THRESHOLD,
+            preallocate: false,
+            max_free_files: 0,
+            open_threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            checkpoint_interval: None,
+            open_progress: None,
+            index_mode: IndexMode::default(),
+            index_memory_budget: None,
+            value_cache_size: None,
+            bloom_filter: false,
+        }
+    }
+}
+
+impl CaskOptions {
+    pub fn new() -> CaskOptions {
+        CaskOptions::default()
+    }
+
+    /// Whether every write should be synced to disk before returning.
+    pub fn sync(&mut self, sync: bool) -> &mut CaskOptions {
+        self.sync = sync;
+        self
+    }
+
+    /// The maximum size of a key in bytes, `put` fails with `Error::KeyTooLarge` for larger keys.
+    /// The limit is capped at the maximum key size supported by the file format.
+    pub fn max_key_size(&mut self, max_key_size: usize) -> &mut CaskOptions {
+        self.max_key_size = cmp::min(max_key_size, MAX_KEY_SIZE);
+        self
+    }
+
+    /// The maximum size of a value in bytes, `put` fails with `Error::ValueTooLarge` for larger
+    /// values. The limit is capped at the maximum value size supported by the file format.
+    pub fn max_value_size(&mut self, max_value_size: usize) -> &mut CaskOptions {
+        self.max_value_size = cmp::min(max_value_size, MAX_VALUE_SIZE);
+        self
+    }
+
+    /// Values larger than `blob_threshold` bytes are stored in separate blob files and only a
+    /// reference to them is kept in the data files, so compacting a data file doesn't copy them.
+    /// Blob files are compacted separately. Blob files are not encrypted, see `encryption`.
+    pub fn blob_threshold(&mut self, blob_threshold: usize) -> &mut CaskOptions {
+        self.blob_threshold = Some(blob_threshold);
+        self
+    }
+
+    /// Compresses values with `codec` before writing them. Values which don't get any smaller are
+    /// stored uncompressed. The codec is also registered to decompress values, see
+    /// `register_codec`.
+    pub fn compression<C: Codec + 'static>(&mut self, codec: C) -> &mut CaskOptions {
+        let codec = Arc::new(codec);
+        self.codecs.register(codec.clone());
+        self.compression = Some(codec);
+        self
+    }
+
+    /// Only values of at least `compression_threshold` bytes are compressed.
+    pub fn compression_threshold(&mut self, compression_threshold: usize) -> &mut CaskOptions {
+        self.compression_threshold = compression_threshold;
+        self
+    }
+
+    /// Registers a codec that can be used to decompress values. All codecs enabled through cargo
+    /// features are registered by default, custom codecs used to write values must be registered
+    /// when opening the database to be able to read them.
+    pub fn register_codec<C: Codec + 'static>(&mut self, codec: C) -> &mut CaskOptions {
+        self.codecs.register(Arc::new(codec));
+        self
+    }
+
+    /// Encrypts values with `cipher` before writing them to data files. The cipher is registered
+    /// under `key_id`, which is stored with every value it encrypts, and a key must not be used by
+    /// more than one database. Keys are not encrypted, and values can't be stored in blob files:
+    /// `put_stream` and `put` of values larger than `blob_threshold` fail.
+    ///
+    /// Values which are not encrypted fail to read, so a database written without encryption must
+    /// be upgraded with `upgrade` to encrypt its values. To rotate keys open the database with a
+    /// new key id and register the previous key with `register_key`, data files are rewritten
+    /// under the new key as they are compacted.
+    pub fn encryption<C: Cipher + 'static>(&mut self,
+                                           key_id: u32,
+                                           cipher: C)
+                                           -> &mut CaskOptions {
+        self.keys.set_current(key_id, Arc::new(cipher));
+        self
+    }
+
+    /// Registers a key that can be used to decrypt values written with a previous key.
+    pub fn register_key<C: Cipher + 'static>(&mut self,
+                                             key_id: u32,
+                                             cipher: C)
+                                             -> &mut CaskOptions {
+        self.keys.register(key_id, Arc::new(cipher));
+        self
+    }
+
+    /// The checksum algorithm used to verify the entries of new files. It is recorded in the header
+    /// of every file, so files written with another algorithm can still be read.
+    pub fn checksum(&mut self, checksum: ChecksumType) -> &mut CaskOptions {
+        self.checksum = checksum;
+        self
+    }
+
+    /// The maximum number of data files kept open for reads, the least recently used file is
+    /// closed when the limit is reached.
+    pub fn max_open_files(&mut self, max_open_files: usize) -> &mut CaskOptions {
+        self.max_open_files = max_open_files;
+        self
+    }
+
+    /// Whether data files should be memory mapped for reads. The active data file is read with
+    /// regular reads until it is rotated.
+    pub fn mmap(&mut self, mmap: bool) -> &mut CaskOptions {
+        self.mmap = mmap;
+        self
+    }
+
+    /// The size of the buffers used to write data and hint files. Buffered writes are flushed when
+    /// the buffer is full, when they are read, when the active data file is rotated and when the
+    /// database is closed, or after every write if `sync` is set.
+    pub fn write_buffer_size(&mut self, write_buffer_size: usize) -> &mut CaskOptions {
+        self.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    /// The size of data files, past which the active data file is closed and a new one is created.
+    /// Defaults to 2000 MiB.
+    pub fn size_threshold(&mut self, size_threshold: usize) -> &mut CaskOptions {
+        self.size_threshold = size_threshold;
+        self
+    }
+
+    /// Whether new data files should be preallocated up to `size_threshold`, which avoids
+    /// fragmenting them as they grow. Data files are truncated to their logical end when closed.
+    pub fn preallocate(&mut self, preallocate: bool) -> &mut CaskOptions {
+        self.preallocate = preallocate;
+        self
+    }
+
+    /// The maximum number of data files removed by compaction that are kept to be reused for new
+    /// data files instead of being deleted. Reused files keep their allocated space and are
+    /// truncated to their logical end when closed, or when the database is next opened after a
+    /// crash. Defaults to 0.
+    pub fn max_free_files(&mut self, max_free_files: usize) -> &mut CaskOptions {
+        self.max_free_files = max_free_files;
+        self
+    }
+
+    /// The number of threads used to load hint files when the database is opened, or to recreate
+    /// them from data files. Defaults to the available parallelism.
+    pub fn open_threads(&mut self, open_threads: usize) -> &mut CaskOptions {
+        self.open_threads = open_threads;
+        self
+    }
+
+    /// How often the background compaction thread should write a checkpoint of the index, if it
+    /// changed since the last one, see `Cask::checkpoint`. Disabled by default.
+    pub fn checkpoint_interval(&mut self, checkpoint_interval: Duration) -> &mut CaskOptions {
+        self.checkpoint_interval = Some(checkpoint_interval);
+        self
+    }
+
+    /// How the index stores keys in memory, see `IndexMode`. `IndexMode::Compact` packs keys in an
+    /// arena, `IndexMode::HashOnly` only keeps a fingerprint of every key and reads keys from the
+    /// data files on lookups and writes. Defaults to `IndexMode::Full`.
+    pub fn index_mode(&mut self, index_mode: IndexMode) -> &mut CaskOptions {
+        self.index_mode = index_mode;
+        self
+    }
+
+    /// The memory the index may use in bytes. Beyond it, index entries are spilled to sorted runs
+    /// on disk, which keeps the database working with slower lookups. Unlimited by default.
+    pub fn index_memory_budget(&mut self, index_memory_budget: usize) -> &mut CaskOptions {
+        self.index_memory_budget = Some(index_memory_budget);
+        self
+    }
+
+    /// Caches values read in memory, up to `value_cache_size` bytes, evicting the least recently
+    /// read values. Values which `get_ref` and `get_into` borrow from memory-mapped data files are
+    /// read without copies and aren't cached. Disabled by default.
+    pub fn value_cache_size(&mut self, value_cache_size: usize) -> &mut CaskOptions {
+        self.value_cache_size = Some(value_cache_size);
+        self
+    }
+
+    /// Whether to keep a Bloom filter over all keys, built once the index is loaded, so that
+    /// lookups of absent keys rarely read from disk in `IndexMode::HashOnly` or once the index was
+    /// spilled to disk. Disabled by default.
+    pub fn bloom_filter(&mut self, bloom_filter: bool) -> &mut CaskOptions {
+        self.bloom_filter = bloom_filter;
+        self
+    }
+
+    /// Calls `open_progress` while the database is opened to report the progress of loading its
+    /// hint files, see `OpenProgress`.
+    pub fn open_progress<F>(&mut self, open_progress: F) -> &mut CaskOptions
+        where F: Fn(&OpenProgress) + Send + Sync + 'static
+    {
+        self.open_progress = Some(OpenProgressCallback(Arc::new(open_progress)));
+        self
+    }
+
+    /// Opens the database at `path` with the options specified by `self`.
+    pub fn open(&self, path: &str) -> Cask {
+        Cask::open_with_options(path, self.clone())
+    }
+
+    /// Opens the database at `path` read-only with the options specified by `self`, so that it can
+    /// be read while another process writes to it. No lock is taken and no file is created, so the
+    /// index is never spilled to disk and no compaction thread is started. The index is loaded as
+    /// of when the database is opened, see `Cask::refresh`, except that a read of a data file
+    /// removed by a compaction of the writer refreshes it before the read is retried.
+    pub fn open_read_only(&self, path: &str) -> Result<Cask> {
+        Cask::open_read_only_with_options(path, self.clone())
+    }
+
+    /// Upgrades all data and hint files of the database at `path` to the current file format, see
+    /// `Cask::upgrade`. Upgraded files are written with the checksum algorithm and encryption key
+    /// specified by `self`, and any encrypted values must be readable with the registered keys.
+    /// With an encryption key, data files holding plaintext values are rewritten as well.
+    pub fn upgrade(&self, path: &str) -> Result<()> {
+        info!("Upgrading database: {:?}", &path);
+
+        // upgraded data files are rewritten so the positions of their entries change
+        let checkpoint_file_path = Path::new(path).join(CHECKPOINT_FILE_NAME);
+        if checkpoint_file_path.exists() {
+            fs::remove_file(checkpoint_file_path)?;
+        }
+
+        log::upgrade(Path::new(path), &self.keys, self.checksum)?;
+        info!("Upgraded database: {:?}", &path);
+        Ok(())
+    }
+}
+
+/// Closes a database once its last handle is dropped, see `Cask`.
+struct Shutdown {
+    dropped: Arc<AtomicBool>,
+    log: Arc<Log>,
+    /// The background compaction thread, joined when the database is dropped.
+    compaction_thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        self.dropped.store(true, Ordering::SeqCst);
+        self.log.close();
+
+        // the thread holds the database open until it exits
+        let compaction_thread = self.compaction_thread.lock().unwrap().take();
+        if let Some(compaction_thread) = compaction_thread {
+            compaction_thread.thread().unpark();
+            if compaction_thread.join().is_err() {
+                warn!("Background compaction thread panicked");
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Cask {
+    path: PathBuf,
+    options: CaskOptions,
+    dropped: Arc<AtomicBool>,
+    inner: Arc<CaskInner>,
+    blob_log: Arc<Mutex<BlobLog>>,
+    /// Serializes compactions and checkpoints.
+    compaction: Arc<Mutex<()>>,
+    /// Shared by the handles of the database, which is closed once the last of them is dropped.
+    /// The handle of the compaction thread doesn't hold it, so that it doesn't keep the database
+    /// open.
+    shutdown: Option<Arc<Shutdown>>,
+    open_summary: Arc<OpenSummary>,
+}
+
+impl Cask {
+    pub fn open(path: &str, sync: bool) -> Cask {
+        CaskOptions::new().sync(sync).open(path)
+    }
+
+    /// Opens the database at `path` read-only, see `CaskOptions::open_read_only`.
+    pub fn open_read_only(path: &str) -> Result<Cask> {
+        CaskOptions::new().open_read_only(path)
+    }
+
+    fn new(options: CaskOptions,
+           log: Arc<Log>,
+           index: Index,
+           sequence: SequenceNumber,
+           open_summary: OpenSummary,
+           loaded_files: Option<LoadedFiles>)
+           -> Cask {
+        let blob_log = BlobLog::open(&log.path,
+                                     options.sync,
+                                     DEFAULT_SIZE_THRESHOLD,
+                                     options.checksum);
+        let codecs = options.codecs.clone();
+        let value_cache = options.value_cache_size.map(ValueCache::new);
+        let dropped = Arc::new(AtomicBool::new(false));
+
+        Cask {
+            path: log.path.clone(),
+            options,
+            blob_log: Arc::new(Mutex::new(blob_log)),
+            dropped: dropped.clone(),
+            compaction: Arc::new(Mutex::new(())),
+            shutdown: Some(Arc::new(Shutdown {
+                dropped,
+                log: log.clone(),
+                compaction_thread: Mutex::new(None),
+            })),
+            open_summary: Arc::new(open_summary),
+            inner: Arc::new(CaskInner {
+                current_sequence: AtomicU64::new(sequence + 1),
+                codecs,
+                log,
+                index,
+                value_cache,
+                loaded_files: loaded_files.map(Mutex::new),
+            }),
+        }
+    }
+
+    fn open_read_only_with_options(path: &str, options: CaskOptions) -> Result<Cask> {
+        info!("Opening database read-only: {:?}", &path);
+        let start = Instant::now();
+
+        let log = Log::open_read_only(path,
+                                      options.keys.clone(),
+                                      options.max_open_files,
+                                      options.size_threshold)?;
+        let log = Arc::new(log);
+        // spilled runs would be written to the database directory
+        let mut index = Index::new(options.index_mode, None, log.clone());
+
+        let checkpoint = match read_checkpoint(&log.path, &index) {
+            Ok(checkpoint) => checkpoint,
+            Err(err) => {
+                warn!("Failed to load index checkpoint, replaying all data files: {}", err);
+                None
+            }
+        };
+
+        let mut open_summary = OpenSummary {
+            checkpoint: checkpoint.is_some(),
+            ..OpenSummary::default()
+        };
+
+        let (watermark, checkpoint_sequence) = match checkpoint {
+            Some(checkpoint) => (checkpoint.watermark, checkpoint.sequence),
+            None => {
+                index = Index::new(options.index_mode, None, log.clone());
+                (0, 0)
+            }
+        };
+
+        let mut loaded_files = LoadedFiles {
+            watermark,
+            files: HashMap::new(),
+        };
+        let sequence = cmp::max(checkpoint_sequence,
+                                refresh_index(&log, &index, &mut loaded_files));
+
+        if options.bloom_filter {
+            index.build_filters();
+        }
+
+        open_summary.files_loaded = loaded_files.files.len();
+        open_summary.keys = index.len();
+        open_summary.elapsed = start.elapsed();
+
+        info!("Opened database read-only: {:?}", &path);
+        info!("Loaded {} keys from {} data files in {:?}",
+              open_summary.keys,
+              open_summary.files_loaded,
+              open_summary.elapsed);
+
+        Ok(Cask::new(options, log, index, sequence, open_summary, Some(loaded_files)))
+    }
+
+    fn open_with_options(path: &str, options: CaskOptions) -> Cask {
+        info!("Opening database: {:?}", &path);
+        let start = Instant::now();
+
+        let log = Arc::new(Log::open(path,
+                                     LogOptions {
+                                         sync: options.sync,
+                                         keys: options.keys.clone(),
+                                         checksum: options.checksum,
+                                         max_open_files: options.max_open_files,
+                                         mmap: options.mmap,
+                                         write_buffer_size: options.write_buffer_size,
+                                         size_threshold: options.size_threshold,
+                                         preallocate: options.preallocate,
+                                         max_free_files: options.max_free_files,
+                                     }));
+        if let Err(err) = index::remove_spill_files(&log.path) {
+            warn!("Failed to remove spill files: {}", err);
+        }
+        let mut index = Index::new(options.index_mode, options.index_memory_budget, log.clone());
+
+        let checkpoint = match read_checkpoint(&log.path, &index) {
+            Ok(Some(ref checkpoint)) if checkpoint.watermark > log.active_file_id() => {
+                warn!("Ignoring index checkpoint newer than the data files");
+                None
+            }
+            Ok(checkpoint) => checkpoint,
+            Err(err) => {
+                warn!("Failed to load index checkpoint, replaying all hint files: {}", err);
+                None
+            }
+        };
+
+        let mut open_summary = OpenSummary {
+            checkpoint: checkpoint.is_some(),
+            ..OpenSummary::default()
+        };
+
+        let (watermark, checkpoint_sequence) = match checkpoint {
+            Some(checkpoint) => (checkpoint.watermark, checkpoint.sequence),
+            None => {
+                index = Index::new(options.index_mode, options.index_memory_budget, log.clone());
+                (0, 0)
+            }
+        };
+
+        let sequence = cmp::max(checkpoint_sequence,
+                                load_index(&log,
+                                           &index,
+                                           &LoadOptions {
+                                               threads: options.open_threads,
+                                               watermark,
+                                               start,
+                                               open_progress: options.open_progress.as_ref(),
+                                           },
+                                           &mut open_summary));
+
+        if options.bloom_filter {
+            index.build_filters();
+        }
+
+        for file_id in blob::find_blob_files(&log.path) {
+            index.add_blob_file(file_id);
+        }
+
+        open_summary.keys = index.len();
+        open_summary.elapsed = start.elapsed();
+
+        info!("Opened database: {:?}", &path);
+        info!("Current sequence number: {:?}", sequence);
+        info!("Loaded {} keys from {} data files in {:?}, re-created {} and finished {} hint files",
+              open_summary.keys,
+              open_summary.files_loaded,
+              open_summary.elapsed,
+              open_summary.recreated_hint_files.len(),
+              open_summary.finished_hint_files.len());
+
+        let cask = Cask::new(options, log, index, sequence, open_summary, None);
+
+        let caskt = Cask { shutdown: None, ..cask.clone() };
+        let compaction_thread = thread::spawn(move || {
+            let mut last_checkpoint = (Instant::now(), sequence);
+
+            loop {
+                let wake_up = Instant::now() + Duration::new(COMPACTION_CHECK_FREQUENCY, 0);
+                while !caskt.dropped.load(Ordering::SeqCst) {
+                    let now = Instant::now();
+                    if now >= wake_up {
+                        break;
+                    }
+                    thread::park_timeout(wake_up - now);
+                }
+
+                info!("Compaction thread wake up");
+
+                if caskt.dropped.load(Ordering::SeqCst) {
+                    info!("Cask has been dropped, background compaction thread is exiting");
+                    break;
+                }
+
+                caskt.compact();
+
+                if let Some(checkpoint_interval) = caskt.options.checkpoint_interval {
+                    let sequence = caskt.inner.current_sequence.load(Ordering::SeqCst) - 1;
+
+                    if last_checkpoint.0.elapsed() >= checkpoint_interval &&
+                       last_checkpoint.1 != sequence {
+                        match caskt.checkpoint() {
+                            Ok(()) => last_checkpoint = (Instant::now(), sequence),
+                            Err(err) => warn!("Failed to write index checkpoint: {}", err),
+                        }
+                    }
+                }
+            }
+        });
+        if let Some(ref shutdown) = cask.shutdown {
+            *shutdown.compaction_thread.lock().unwrap() = Some(compaction_thread);
+        }
+
+        cask
+    }
+
+    /// Upgrades all data and hint files of the database at `path` to the current file format. The
+    /// database must not be open while it is being upgraded. Databases with encrypted values must
+    /// be upgraded with `CaskOptions::upgrade`.
+    pub fn upgrade(path: &str) -> Result<()> {
+        CaskOptions::new().upgrade(path)
+    }
+
+    fn compact_file_aux(&self, file_id: u32) -> Option<u32> {
+        if file_id == self.inner.log.active_file_id() {
+            return None;
+        }
+
+        let hints = self.inner.log.hints(file_id);
+
+        hints.and_then(|hints| {
+            let new_file_id = self.inner.log.new_file_id();
+
+            info!("Compacting data file: {} into: {}", file_id, new_file_id);
+
+            let mut log_writer = self.inner.log.new_writer(new_file_id);
+            let mut deletes = HashMap::new();
+            let mut lookup_error = None;
+
+            {
+                let inserts = hints.filter(|hint| {
+                    let index_entry = match self.inner.index.get(&hint.key) {
+                        Ok(index_entry) => index_entry,
+                        Err(err) => {
+                            lookup_error.get_or_insert(err);
+                            return false;
+                        }
+                    };
+
+                    if hint.deleted {
+                        if index_entry.is_none() {
+                            match deletes.entry(hint.key.to_vec()) {
+                                HashMapEntry::Occupied(mut o) => {
+                                    if *o.get() < hint.sequence {
+                                        o.insert(hint.sequence);
+                                    }
+                                }
+                                HashMapEntry::Vacant(e) => {
+                                    e.insert(hint.sequence);
+                                }
+                            }
+                        }
+
+                        false
+
+                    } else {
+                        index_entry.is_some() && index_entry.unwrap().sequence == hint.sequence
+                    }
+                });
+
+                for hint in inserts {
+                    match self.inner.log.read_entry(file_id, hint.entry_pos) {
+                        Ok(entry) => {
+                            log_writer.write(&entry);
+                        }
+                        Err(err) => {
+                            warn!("Failed to read data file {}, aborting compaction: {}",
+                                  file_id,
+                                  err);
+                            drop(log_writer);
+                            self.inner.log.discard_file(new_file_id);
+                            return None;
+                        }
+                    }
+                }
+            }
+
+            if let Some(err) = lookup_error {
+                warn!("Failed to look up the keys of data file {}, aborting compaction: {}",
+                      file_id,
+                      err);
+                drop(log_writer);
+                self.inner.log.discard_file(new_file_id);
+                return None;
+            }
+
+            for (key, sequence) in deletes {
+                log_writer.write(&Entry::deleted(sequence, key));
+            }
+
+            Some(new_file_id)
+        })
+    }
+
+    pub fn compact_file(&self, file_id: u32) {
+        if self.is_read_only() {
+            return;
+        }
+
+        let _compaction = self.compaction.lock().unwrap();
+        let new_file_id = self.compact_file_aux(file_id);
+
+        if let Some(new_file_id) = new_file_id {
+            self.finish_compaction(file_id, new_file_id);
+        }
+    }
+
+    /// Points the index to the entries of `file_id` copied to `new_file_id`, which then replaces
+    /// it. Keys written or deleted since they were copied keep their current entry.
+    fn finish_compaction(&self, file_id: u32, new_file_id: u32) {
+        let hints = match self.inner.log.hints(new_file_id) {
+            Some(hints) => hints,
+            None => return,
+        };
+
+        let mut failed = 0;
+        for hint in hints {
+            if let Err(err) = self.inner.index.relocate(hint, new_file_id, file_id) {
+                warn!("Failed to index an entry of data file {}: {}", new_file_id, err);
+                failed += 1;
+            }
+        }
+
+        // the entries which failed to be indexed still point to the compacted file
+        if failed > 0 {
+            warn!("Keeping data file {} along with {}, {} entries weren't indexed",
+                  file_id,
+                  new_file_id,
+                  failed);
+            self.inner.log.add_file(new_file_id);
+            return;
+        }
+
+        self.inner.log.swap_file(file_id, new_file_id);
+
+        info!("Finished compacting data file: {} into: {}",
+              file_id,
+              new_file_id);
+    }
+
+    pub fn compact_blob_file(&self, file_id: u32) {
+        if self.is_read_only() {
+            return;
+        }
+
+        let _compaction = self.compaction.lock().unwrap();
+
+        if Some(file_id) == self.blob_log.lock().unwrap().active_file_id() {
+            return;
+        }
+
+        let records = self.blob_log.lock().unwrap().records(file_id);
+
+        let records = match records {
+            Ok(records) => records,
+            Err(err) => {
+                warn!("Failed to open blob file {} for compaction: {}", file_id, err);
+                self.inner.index.remove_blob_file(file_id);
+                return;
+            }
+        };
+
+        info!("Compacting blob file: {}", file_id);
+
+        for record in records {
+            let (key, blob_ref) = match record {
+                Ok(record) => record,
+                Err(err) => {
+                    warn!("Failed to read blob file {}, aborting compaction: {}",
+                          file_id,
+                          err);
+                    return;
+                }
+            };
+
+            let live = match self.inner.index.get(&key) {
+                Ok(index_entry) => index_entry.and_then(|e| e.blob_ref) == Some(blob_ref),
+                Err(err) => {
+                    warn!("Failed to look up key {:?}, aborting compaction: {}", key, err);
+                    return;
+                }
+            };
+
+            if !live {
+                continue;
+            }
+
+            let new_blob_ref = BlobReader::open(&self.path, blob_ref).and_then(|mut blob_reader| {
+                self.blob_log.lock().unwrap().write(&key, &mut blob_reader)
+            });
+
+            let new_blob_ref = match new_blob_ref {
+                Ok(new_blob_ref) => new_blob_ref,
+                Err(err) => {
+                    warn!("Failed to copy blob {:?}, aborting compaction: {}", blob_ref, err);
+                    return;
+                }
+            };
+
+            // the key might have been updated while its value was being copied
+            if let Err(err) = self.inner.relocate_blob(key, blob_ref, &new_blob_ref) {
+                warn!("Failed to relocate blob {:?}, aborting compaction: {}", blob_ref, err);
+                return;
+            }
+        }
+
+        // the relocated values and the references to them must be durable before the blob file is
+        // removed
+        if let Err(err) = self.blob_log.lock().unwrap().sync() {
+            warn!("Failed to sync blob files, aborting compaction: {}", err);
+            return;
+        }
+
+        self.inner.log.sync();
+        self.inner.index.remove_blob_file(file_id);
+
+        if let Err(err) = self.blob_log.lock().unwrap().remove_file(file_id) {
+            warn!("Failed to remove compacted blob file {}: {}", file_id, err);
+            return;
+        }
+
+        info!("Finished compacting blob file: {}", file_id);
+    }
+
+    pub fn compact(&self) {
+        if self.is_read_only() {
+            return;
+        }
+
+        let (iter, blob_iter) = {
+            let stats = self.inner.index.stats();
+            (stats.fragmentation(), stats.blob_fragmentation())
+        };
+
+        for &(file_id, fragmentation) in iter.iter().filter(|e| e.1 >= FRAGMENTATION_THRESHOLD) {
+
+            info!("File {} has fragmentation factor of {}%, adding for compaction",
+                  file_id,
+                  fragmentation * 100.0);
+
+            self.compact_file(file_id);
+        }
+
+        for &(file_id, fragmentation) in blob_iter.iter()
+            .filter(|e| e.1 >= FRAGMENTATION_THRESHOLD) {
+
+            info!("Blob file {} has fragmentation factor of {}%, adding for compaction",
+                  file_id,
+                  fragmentation * 100.0);
+
+            self.compact_blob_file(file_id);
+        }
+    }
+
+    /// Returns the value of `key`. Values which can't be read, e.g. because their blob file is
+    /// corrupt, are logged and reported as missing.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<Vec<u8>> {
+        self.inner.get(key.as_ref())
+    }
+
+    /// Returns the values of `keys`, in the same order. All keys are looked up first and the values
+    /// are then read in the order they are stored on disk.
+    pub fn get_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<Option<Vec<u8>>> {
+        self.inner.get_many(keys)
+    }
+
+    /// Returns the value of `key` without copying it when data files are memory mapped, see
+    /// `CaskOptions::mmap`. Otherwise the value is read as with `get`.
+    pub fn get_ref<K: AsRef<[u8]>>(&self, key: K) -> Option<ValueRef> {
+        self.inner.get_ref(key.as_ref())
+    }
+
+    /// Calls `f` with the value of `key` without copying it when possible, see `get_ref`.
+    pub fn get_with<K, F, T>(&self, key: K, f: F) -> Option<T>
+        where K: AsRef<[u8]>,
+              F: FnOnce(&[u8]) -> T
+    {
+        self.get_ref(key).map(|value| f(&value))
+    }
+
+    /// Reads the value of `key` into `buf`, replacing its contents and reusing its allocation.
+    /// Returns whether the key was found, `buf` is left untouched otherwise.
+    pub fn get_into<K: AsRef<[u8]>>(&self, key: K, buf: &mut Vec<u8>) -> bool {
+        self.inner.get_into(key.as_ref(), buf)
+    }
+
+    /// Returns whether `key` exists, from the index only. Values are never read, but keys are read
+    /// from the data files in `IndexMode::HashOnly` and from spilled entries once the index
+    /// exceeds `index_memory_budget`, see `bloom_filter`.
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        self.inner.lookup(key.as_ref()).is_some()
+    }
+
+    /// Returns the size of the value of `key` in bytes, i.e. the length of the value returned by
+    /// `get`, from the index only as `contains_key`.
+    pub fn value_len<K: AsRef<[u8]>>(&self, key: K) -> Option<u64> {
+        self.inner.lookup(key.as_ref()).map(|index_entry| index_entry.value_size)
+    }
+
+    /// Returns the number of keys in the database.
+    pub fn len(&self) -> usize {
+        self.inner.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn put<K: Into<Vec<u8>>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        let key = key.into();
+        let value = value.as_ref();
+
+        if key.len() > self.options.max_key_size {
+            return Err(Error::KeyTooLarge(key.len()));
+        }
+
+        if value.len() > self.options.max_value_size {
+            return Err(Error::ValueTooLarge(value.len()));
+        }
+
+        match self.options.blob_threshold {
+            Some(blob_threshold) if value.len() > blob_threshold => {
+                if self.options.keys.encrypts() {
+                    return Err(Error::UnencryptedBlob);
+                }
+
+                let blob_ref = self.blob_log.lock().unwrap().write(&key, &mut Cursor::new(value))?;
+                self.inner.put_blob(key, &blob_ref)?;
+            }
+            _ => {
+                let compressed = match self.options.compression {
+                    Some(ref codec) if value.len() >= self.options.compression_threshold => {
+                        compression::compress(&**codec, value)?
+                    }
+                    _ => None,
+                };
+
+                match compressed {
+                    Some(compressed) => self.inner.put(key, &compressed, true)?,
+                    None => self.inner.put(key, value, false)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes a checkpoint of the index, so that the next time the database is opened the index is
+    /// loaded from the checkpoint and only the data files written since are replayed. The active
+    /// data file is rotated and the index is copied shard by shard, the writes to a shard are
+    /// blocked while it is copied.
+    pub fn checkpoint(&self) -> Result<()> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        let _compaction = self.compaction.lock().unwrap();
+
+        let checkpoint = self.inner.start_checkpoint();
+        self.inner.log.sync_files(checkpoint.watermark)?;
+
+        info!("Writing index checkpoint up to data file {}", checkpoint.watermark);
+
+        let len =
+            write_checkpoint(&self.path, self.options.checksum, &checkpoint, &self.inner.index)?;
+
+        info!("Wrote index checkpoint with {} entries", len);
+
+        Ok(())
+    }
+
+    /// Returns a summary of how the database was opened: how long recovering the index took, the
+    /// data files whose hint file had to be re-created and the number of keys loaded.
+    pub fn open_summary(&self) -> &OpenSummary {
+        &self.open_summary
+    }
+
+    /// Returns a snapshot of the database statistics.
+    pub fn stats(&self) -> StatsSnapshot {
+        let mut stats = self.inner.index.stats().snapshot();
+        let (index_memory, index_spilled_entries) = self.inner.index.memory_size();
+        stats.index_memory = index_memory as u64;
+        stats.index_spilled_entries = index_spilled_entries as u64;
+        if let Some(ref value_cache) = self.inner.value_cache {
+            let (hits, misses) = value_cache.hits_and_misses();
+            stats.value_cache_hits = hits;
+            stats.value_cache_misses = misses;
+            stats.value_cache_bytes = value_cache.size() as u64;
+        }
+        stats
+    }
+
+    /// Returns a reader over the value of `key`. Values written with `put_stream` are streamed from
+    /// disk as they are read, instead of being loaded into memory.
+    pub fn get_reader<K: AsRef<[u8]>>(&self, key: K) -> Option<ValueReader> {
+        self.inner.get_reader(key.as_ref())
+    }
+
+    /// Returns an iterator over the keys and values of the database, as of the snapshot of the
+    /// index taken by this call, which is held in memory. Entries are read in file order, so keys
+    /// aren't sorted. Entries relocated by compactions meanwhile are read from their new location,
+    /// entries which can't be read are logged and skipped.
+    pub fn iter<'a>(&'a self) -> Result<Iter<'a>> {
+        Ok(Iter {
+            inner: &self.inner,
+            entries: self.inner.index.snapshot()?.into_iter(),
+        })
+    }
+
+    /// Stores the value read from `reader` under `key`. The value is written to a blob file in
+    /// chunks so it never has to be held in memory, and it is not rewritten when the data file
+    /// holding its key is compacted.
+    pub fn put_stream<K: Into<Vec<u8>>, R: Read>(&self, key: K, mut reader: R) -> Result<()> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        if self.options.keys.encrypts() {
+            return Err(Error::UnencryptedBlob);
+        }
+
+        let key = key.into();
+
+        if key.len() > self.options.max_key_size {
+            return Err(Error::KeyTooLarge(key.len()));
+        }
+
+        let blob_ref = self.blob_log.lock().unwrap().write(&key, &mut reader)?;
+
+        self.inner.put_blob(key, &blob_ref)
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        if self.is_read_only() {
+            return Err(Error::ReadOnly);
+        }
+
+        self.inner.delete(key.as_ref())
+    }
+
+    /// Whether the database was opened with `open_read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self.inner.loaded_files.is_some()
+    }
+
+    /// Loads the entries written by other processes since a read-only database was opened or last
+    /// refreshed, reading the data files written since and the entries appended to data files
+    /// without a complete hint file. Does nothing if the database isn't read-only.
+    ///
+    /// Reads of data files which were compacted by the writer since refresh the database first, so
+    /// reads might see entries written after the last explicit refresh.
+    pub fn refresh(&self) {
+        self.inner.refresh();
+    }
+}
+
+/// An iterator over the keys and values of a database, see `Cask::iter`.
+pub struct Iter<'a> {
+    inner: &'a CaskInner,
+    entries: vec::IntoIter<(usize, Vec<u8>, IndexEntry)>,
+}
+
+impl<'a> Iter<'a> {
+    /// Reads the key and value of `index_entry`, whose stored key is `stored_key`. Entries of
+    /// recycled data files are reported as missing files.
+    fn read(&self,
+            stored_key: &[u8],
+            index_entry: &IndexEntry)
+            -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let entry = self.inner.log.read_entry(index_entry.file_id, index_entry.entry_pos)?;
+
+        let mut fingerprint = [0u8; 8];
+        if self::stored_key(self.inner.index.mode, &entry.key, &mut fingerprint) != stored_key {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Data file was recycled").into());
+        }
+
+        if entry.deleted {
+            warn!("Index pointed to dead entry: Entry {{ key: {:?}, sequence: {} }} at file: {}",
+                  entry.key,
+                  entry.sequence,
+                  index_entry.file_id);
+            return Ok(None);
+        }
+
+        let key = entry.key.to_vec();
+        Ok(Some((key, self.inner.value(entry)?)))
+    }
+
+    /// Returns the entry of `stored_key` in shard `idx` which replaced `index_entry`, once its
+    /// data file was compacted. Relocated entries keep their sequence number, which tells apart
+    /// the keys with the same fingerprint in hash-only mode.
+    fn relocated(&self,
+                 idx: usize,
+                 stored_key: &[u8],
+                 index_entry: &IndexEntry)
+                 -> Result<Option<IndexEntry>> {
+        let hash_only = self.inner.index.mode == IndexMode::HashOnly;
+
+        Ok(self.inner
+            .index
+            .find_stored(idx, stored_key)?
+            .into_iter()
+            .find(|current| {
+                current != index_entry && (!hash_only || current.sequence == index_entry.sequence)
+            }))
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        while let Some((idx, stored_key, mut index_entry)) = self.entries.next() {
+            let mut refreshed = false;
+
+            loop {
+                let err = match self.read(&stored_key, &index_entry) {
+                    Ok(Some(entry)) => return Some(entry),
+                    Ok(None) => break,
+                    Err(err) => err,
+                };
+
+                if !is_not_found(&err) {
+                    warn!("Failed to read the entry at {} in file {}: {}",
+                          index_entry.entry_pos,
+                          index_entry.file_id,
+                          err);
+                    break;
+                }
+
+                // the data file was compacted since the snapshot, in read-only databases into a
+                // data file which might not be loaded yet
+                match self.relocated(idx, &stored_key, &index_entry) {
+                    Ok(Some(relocated)) => index_entry = relocated,
+                    Ok(None) if self.inner.loaded_files.is_some() && !refreshed => {
+                        self.inner.refresh();
+                        refreshed = true;
+                    }
+                    // the key was deleted since the snapshot
+                    Ok(None) => break,
+                    Err(err) => {
+                        warn!("Failed to look up a relocated entry: {}", err);
+                        break;
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Loads the entries of the data files starting at `loaded_files.watermark` that weren't loaded
+/// into `index` yet, returning the highest sequence number found. Data files without a complete
+/// hint file are read from the data file, which is resumed on the next refresh. Data files which
+/// can't be read yet, such as a data file whose header isn't written yet, are loaded on the next
+/// refresh.
+fn refresh_index(log: &Log, index: &Index, loaded_files: &mut LoadedFiles) -> SequenceNumber {
+    let files = log.refresh_files();
+    loaded_files.files.retain(|file_id, _| files.binary_search(file_id).is_ok());
+
+    let watermark = loaded_files.watermark;
+    let mut sequence = 0;
+
+    for file_id in files.into_iter().filter(|&file_id| file_id >= watermark) {
+        let data_file_pos = match loaded_files.files.get(&file_id) {
+            Some(&None) => continue,
+            Some(&Some(data_file_pos)) => Some(data_file_pos),
+            None => None,
+        };
+
+        if data_file_pos.is_none() {
+            if let Some(hints) = log.hints(file_id) {
+                for hint in hints {
+                    sequence = cmp::max(sequence, hint.sequence);
+                    update_index(index, hint, file_id);
+                }
+
+                loaded_files.files.insert(file_id, None);
+                continue;
+            }
+        }
+
+        let mut scan_hints = match log.scan_hints(file_id, data_file_pos) {
+            Ok(scan_hints) => scan_hints,
+            Err(err) => {
+                warn!("Failed to read data file {}, retrying on the next refresh: {}",
+                      file_id,
+                      err);
+                continue;
+            }
+        };
+
+        for hint in scan_hints.by_ref() {
+            sequence = cmp::max(sequence, hint.sequence);
+            update_index(index, hint, file_id);
+        }
+
+        loaded_files.files.insert(file_id, Some(scan_hints.data_file_pos()));
+    }
+
+    sequence
+}
+
+/// Indexes the entry of `hint` when loading the index, entries which can't be indexed are logged.
+fn update_index(index: &Index, hint: Hint, file_id: u32) {
+    if let Err(err) = index.update(hint, file_id) {
+        warn!("Failed to index an entry of data file {}: {}", file_id, err);
+    }
+}
+
+/// How the index is loaded, see `load_index`.
+struct LoadOptions<'a> {
+    /// The number of threads parsing hint files.
+    threads: usize,
+    /// The first data file whose hints are loaded.
+    watermark: u32,
+    /// When the database started opening.
+    start: Instant,
+    open_progress: Option<&'a OpenProgressCallback>,
+}
+
+/// Loads the hints of every data file starting at the watermark into `index`, returning the
+/// highest sequence number found.
+/// Hint files are parsed by up to `threads` threads but merged in file order, so that entries are
+/// resolved as if the files were loaded sequentially.
+fn load_index(log: &Log,
+              index: &Index,
+              options: &LoadOptions,
+              open_summary: &mut OpenSummary)
+              -> SequenceNumber {
+    let LoadOptions { threads, watermark, start, open_progress } = *options;
+
+    let files: Vec<u32> = log.files().into_iter().filter(|&file_id| file_id >= watermark).collect();
+    let file_sizes: Vec<u64> = files.iter()
+        .map(|&file_id| {
+            fs::metadata(log::get_data_file_path(&log.path, file_id)).map(|m| m.len()).unwrap_or(0)
+        })
+        .collect();
+
+    let mut progress = OpenProgress {
+        files_total: files.len(),
+        bytes_total: file_sizes.iter().sum(),
+        elapsed: start.elapsed(),
+        ..OpenProgress::default()
+    };
+
+    if let Some(open_progress) = open_progress {
+        (open_progress.0)(&progress);
+    }
+
+    let mut sequence = 0;
+
+    {
+        let mut merge = |(hints, status): (Vec<Hint>, HintsStatus), idx: usize| {
+            for hint in hints {
+                sequence = cmp::max(sequence, hint.sequence);
+                update_index(index, hint, files[idx]);
+            }
+
+            progress.files_processed += 1;
+            progress.bytes_scanned += file_sizes[idx];
+            progress.elapsed = start.elapsed();
+
+            match status {
+                HintsStatus::Loaded => {}
+                HintsStatus::Finished => {
+                    progress.hints_finished += 1;
+                    open_summary.finished_hint_files.push(files[idx]);
+                }
+                HintsStatus::Recreated => {
+                    progress.hints_rebuilt += 1;
+                    open_summary.recreated_hint_files.push(files[idx]);
+                }
+            }
+
+            open_summary.files_loaded = progress.files_processed;
+            open_summary.bytes_scanned = progress.bytes_scanned;
+
+            if let Some(open_progress) = open_progress {
+                (open_progress.0)(&progress);
+            }
+        };
+
+        let load = |file_id: u32| -> (Vec<Hint<'static>>, HintsStatus) {
+            let mut load_hints = log.load_hints(file_id);
+            let hints = load_hints.by_ref().collect();
+            (hints, load_hints.status())
+        };
+
+        if threads <= 1 || files.len() <= 1 {
+            for (idx, &file_id) in files.iter().enumerate() {
+                merge(load(file_id), idx);
+            }
+        } else {
+            let threads = cmp::min(threads, files.len());
+            let queue = LoadQueue::new(files.len(), 2 * threads);
+            let (tx, rx) = mpsc::channel();
+
+            thread::scope(|scope| {
+                for _ in 0..threads {
+                    let tx = tx.clone();
+                    let (files, queue, load) = (&files, &queue, &load);
+
+                    scope.spawn(move || while let Some(idx) = queue.next() {
+                        let loaded = panic::catch_unwind(AssertUnwindSafe(|| load(files[idx])));
+
+                        match loaded {
+                            Ok(hints) => tx.send((idx, hints)).unwrap(),
+                            Err(err) => {
+                                // the file is never merged, the other threads would wait for it
+                                queue.abort();
+                                panic::resume_unwind(err);
+                            }
+                        }
+                    });
+                }
+
+                drop(tx);
+
+                // files are loaded in order but can complete out of order, later files wait
+                // until every previous file was merged
+                let mut loaded = HashMap::new();
+                let mut next_merge = 0;
+
+                for (idx, hints) in rx {
+                    loaded.insert(idx, hints);
+
+                    while let Some(hints) = loaded.remove(&next_merge) {
+                        merge(hints, next_merge);
+                        next_merge += 1;
+                    }
+
+                    queue.merged(next_merge);
+                }
+            });
+        }
+    }
+
+    sequence
+}
+
+/// Hands out the indexes of the files to load in order, at most `window` files past the last
+/// merged file, which bounds the number of loaded files waiting to be merged.
+struct LoadQueue {
+    files: usize,
+    window: usize,
+    state: Mutex<LoadQueueState>,
+    cond: Condvar,
+}
+
+struct LoadQueueState {
+    next: usize,
+    merged: usize,
+    aborted: bool,
+}
+
+impl LoadQueue {
+    fn new(files: usize, window: usize) -> LoadQueue {
+        LoadQueue {
+            files,
+            window,
+            state: Mutex::new(LoadQueueState {
+                next: 0,
+                merged: 0,
+                aborted: false,
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Returns the next file to load, waiting until it is within the window, or `None` once all
+    /// files were handed out.
+    fn next(&self) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+
+        while !state.aborted && state.next < self.files &&
+              state.next >= state.merged + self.window {
+            state = self.cond.wait(state).unwrap();
+        }
+
+        if state.aborted || state.next >= self.files {
+            return None;
+        }
 
-#[derive(Debug)]
-pub struct IndexEntry {
-    pub file_id: u32,
-    entry_pos: u64,
-    pub entry_size: u64,
-    sequence: SequenceNumber,
+        state.next += 1;
+        Some(state.next - 1)
+    }
+
+    /// Records that the files before `merged` were merged.
+    fn merged(&self, merged: usize) {
+        self.state.lock().unwrap().merged = merged;
+        self.cond.notify_all();
+    }
+
+    /// Stops handing out files.
+    fn abort(&self) {
+        self.state.lock().unwrap().aborted = true;
+        self.cond.notify_all();
+    }
 }
 
-struct Index {
-    map: HashMap<Vec<u8>, IndexEntry>,
-    stats: Stats,
+/// Checks that `entry` is one of `key`. Data files of read-only databases might be removed by the
+/// writer and their inodes recycled, so an index entry might point to an entry of another key:
+/// it's reported as a missing file.
+fn check_key(entry: &Entry, key: &[u8]) -> Result<()> {
+    if &*entry.key == key {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::NotFound, "Data file was recycled").into())
+    }
 }
 
-impl Index {
-    fn new() -> Index {
-        Index {
-            map: HashMap::new(),
-            stats: Stats::new(),
-        }
+fn is_not_found(err: &Error) -> bool {
+    match *err {
+        Error::Io(ref err) => err.kind() == io::ErrorKind::NotFound,
+        _ => false,
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use byteorder::{LittleEndian, WriteBytesExt};
+
+    use blob::{find_blob_files, get_blob_file_path, BlobLog};
+    use cask::{write_checkpoint, Cask, CaskOptions, IndexEntry, LoadQueue};
+    use data::{Entry, FileHeader, FileKind, FORMAT_VERSION};
+    use encryption::tests::Xor;
+    use index::IndexMode;
+    use errors::Error;
+    use file_cache::ValueRef;
+    use log::{find_data_files, get_data_file_path, get_hint_file_path, get_temp_file_path,
+              DEFAULT_SIZE_THRESHOLD};
+    use util::{xxhash32, ChecksumType, TestDir};
 
-    fn get(&self, key: &[u8]) -> Option<&IndexEntry> {
-        self.map.get(key)
+    /// Encodes an entry as written by cask 0.4, which had no file headers.
+    fn legacy_entry(sequence: u64, key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut v = vec![0u8; 4];
+        v.write_u64::<LittleEndian>(sequence).unwrap();
+        v.write_u16::<LittleEndian>(key.len() as u16).unwrap();
+        v.write_u32::<LittleEndian>(value.len() as u32).unwrap();
+        v.extend_from_slice(key);
+        v.extend_from_slice(value);
+
+        let checksum = xxhash32(&v[4..]);
+        (&mut v[..4]).write_u32::<LittleEndian>(checksum).unwrap();
+        v
     }
 
-    fn insert(&mut self, key: Vec<u8>, index_entry: IndexEntry) -> Option<IndexEntry> {
-        self.stats.add_entry(&index_entry);
-        self.map.insert(key, index_entry).map(|entry| {
-            self.stats.remove_entry(&entry);
-            entry
-        })
+    #[test]
+    fn test_upgrade() {
+        let test_dir = TestDir::new("cask-upgrade");
+        let path = test_dir.path();
+
+        let mut data = Vec::new();
+        data.extend(legacy_entry(1, b"key1", b"value1"));
+        data.extend(legacy_entry(2, b"key2", b"value2"));
+        data.extend(legacy_entry(3, b"key1", b"value3"));
+        let data_file_path = get_data_file_path(path, 0);
+        fs::write(&data_file_path, &data).unwrap();
+
+        // an upgrade interrupted while writing the new data file
+        let temp_file_path = get_temp_file_path(&data_file_path);
+        fs::write(&temp_file_path, &data[..10]).unwrap();
+
+        Cask::upgrade(test_dir.path_str()).unwrap();
+
+        assert!(!temp_file_path.exists());
+        let hint_file_path = get_hint_file_path(path, 0);
+        assert!(hint_file_path.exists());
+
+        // an upgrade interrupted after renaming the new data file, but not its hint file
+        let temp_hint_file_path = get_temp_file_path(&hint_file_path);
+        fs::rename(&hint_file_path, &temp_hint_file_path).unwrap();
+        {
+            let cask = Cask::open(test_dir.path_str(), false);
+            assert_eq!(cask.get(b"key1"), Some(b"value3".to_vec()));
+            assert!(cask.inner.log.hints(0).is_some());
+        }
+        assert!(hint_file_path.exists() && !temp_hint_file_path.exists());
+
+        let header = FileHeader::from_read(FileKind::Data,
+                                           &mut fs::File::open(&data_file_path).unwrap())
+            .unwrap();
+        assert_eq!(header.version, FORMAT_VERSION);
+
+        {
+            let cask = Cask::open(test_dir.path_str(), false);
+            assert_eq!(cask.get(b"key1"), Some(b"value3".to_vec()));
+            assert_eq!(cask.get(b"key2"), Some(b"value2".to_vec()));
+            assert_eq!(cask.len(), 2);
+        }
+
+        // a process which crashed right after creating its active file left only its header
+        let header_only_path = get_data_file_path(path, 100);
+        {
+            let mut file = fs::File::create(&header_only_path).unwrap();
+            header.write_bytes(&mut file).unwrap();
+        }
+
+        let cask = Cask::open(test_dir.path_str(), false);
+        assert_eq!(cask.get(b"key1"), Some(b"value3".to_vec()));
+        assert_eq!(cask.len(), 2);
     }
 
-    fn remove(&mut self, key: &[u8]) -> Option<IndexEntry> {
-        self.map.remove(key).map(|entry| {
-            self.stats.remove_entry(&entry);
-            entry
-        })
+    #[test]
+    fn test_blob_read_errors() {
+        let test_dir = TestDir::new("cask-blob-read-errors");
+        let cask = CaskOptions::new().blob_threshold(100).open(test_dir.path_str());
+
+        cask.put(&b"key1"[..], vec![1u8; 1000]).unwrap();
+        cask.put(&b"key2"[..], vec![2u8; 1000]).unwrap();
+
+        let blob_file_path = get_blob_file_path(test_dir.path(), 0);
+        {
+            let mut blob_file = fs::OpenOptions::new().write(true).open(&blob_file_path).unwrap();
+            blob_file.seek(SeekFrom::End(-10)).unwrap();
+            blob_file.write_all(&[0xff; 10]).unwrap();
+        }
+
+        // the corrupt chunk fails its checksum
+        assert_eq!(cask.get(b"key1"), Some(vec![1u8; 1000]));
+        assert_eq!(cask.get(b"key2"), None);
+        assert_eq!(cask.get_many(&[b"key1", b"key2"]), vec![Some(vec![1u8; 1000]), None]);
+        let mut buf = Vec::new();
+        assert!(cask.get_reader(b"key2").unwrap().read_to_end(&mut buf).is_err());
+
+        fs::remove_file(&blob_file_path).unwrap();
+
+        assert_eq!(cask.get(b"key1"), None);
+        assert!(cask.get_reader(b"key1").is_none());
+        assert!(cask.contains_key(b"key1"));
     }
 
-    fn update(&mut self, hint: Hint, file_id: u32) {
-        let index_entry = IndexEntry {
-            file_id: file_id,
-            entry_pos: hint.entry_pos,
-            entry_size: hint.entry_size(),
-            sequence: hint.sequence,
+    #[test]
+    fn test_blob_compaction() {
+        let test_dir = TestDir::new("cask-blob-compaction");
+        let mut options = CaskOptions::new();
+        options.blob_threshold(100);
+
+        let value = |i: u8| vec![i; 1000];
+        let compact_data_files = |cask: &Cask| for file_id in find_data_files(test_dir.path()) {
+            cask.compact_file(file_id);
         };
 
-        match self.map.entry(hint.key.to_vec()) {
-            HashMapEntry::Occupied(mut o) => {
-                if o.get().sequence <= hint.sequence {
-                    self.stats.remove_entry(o.get());
-                    if hint.deleted {
-                        o.remove();
-                    } else {
-                        self.stats.add_entry(&index_entry);
-                        o.insert(index_entry);
-                    }
-                } else {
-                    self.stats.add_entry(&index_entry);
-                    self.stats.remove_entry(&index_entry);
-                }
-            }
-            HashMapEntry::Vacant(e) => {
-                if !hint.deleted {
-                    self.stats.add_entry(&index_entry);
-                    e.insert(index_entry);
-                }
+        {
+            let cask = options.open(test_dir.path_str());
+            for i in 0..10u8 {
+                cask.put(vec![i], value(i)).unwrap();
             }
         }
-    }
-}
 
-struct CaskInner {
-    current_sequence: SequenceNumber,
-    index: Index,
-    log: Log,
-}
+        // a process crashed after writing a blob but before writing its entry
+        {
+            let mut blob_log = BlobLog::open(test_dir.path(),
+                                             false,
+                                             DEFAULT_SIZE_THRESHOLD,
+                                             ChecksumType::XxHash32);
+            blob_log.write(b"orphan", &mut &value(0)[..]).unwrap();
+        }
+        assert_eq!(find_blob_files(test_dir.path()), vec![0, 1]);
 
-impl CaskInner {
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.index.get(key).and_then(|index_entry| {
-            let entry = self.log.read_entry(index_entry.file_id, index_entry.entry_pos);
-            if entry.deleted {
-                warn!("Index pointed to dead entry: Entry {{ key: {:?}, sequence: {} }} at file: \
-                       {}",
-                      entry.key,
-                      entry.sequence,
-                      index_entry.file_id);
-                None
-            } else {
-                Some(entry.value.into_owned())
+        {
+            let cask = options.open(test_dir.path_str());
+            for i in 0..8u8 {
+                cask.put(vec![i], value(i + 100)).unwrap();
             }
-        })
+
+            compact_data_files(&cask);
+            cask.compact();
+
+            // the live values of the first blob file were copied to the active one
+            assert_eq!(find_blob_files(test_dir.path()), vec![2]);
+            assert_eq!(cask.stats().live_blob_bytes, 10 * 1000);
+        }
+
+        // data file compaction keeps the blobs of the entries it copies live
+        let cask = options.open(test_dir.path_str());
+        compact_data_files(&cask);
+        compact_data_files(&cask);
+        cask.compact();
+
+        assert_eq!(find_blob_files(test_dir.path()), vec![2]);
+        assert_eq!(cask.stats().live_blob_bytes, 10 * 1000);
+
+        for i in 0..10u8 {
+            let expected = if i < 8 { value(i + 100) } else { value(i) };
+            assert_eq!(cask.get([i]), Some(expected));
+        }
     }
 
-    fn put(&mut self, key: Vec<u8>, value: &[u8]) {
-        let index_entry = {
-            let entry = Entry::new(self.current_sequence, &*key, value);
+    #[test]
+    fn test_encryption() {
+        let test_dir = TestDir::new("cask-encryption");
+        let mut options = CaskOptions::new();
+        options.encryption(1, Xor(1)).blob_threshold(1000);
 
-            let (file_id, file_pos) = self.log.append_entry(&entry);
+        {
+            let cask = Cask::open(test_dir.path_str(), false);
+            cask.put(&b"key1"[..], b"plaintext").unwrap();
+        }
 
-            self.current_sequence += 1;
+        {
+            let cask = options.open(test_dir.path_str());
 
-            IndexEntry {
-                file_id: file_id,
-                entry_pos: file_pos,
-                entry_size: entry.size(),
-                sequence: entry.sequence,
+            // values which aren't encrypted can't be read once encryption is enabled
+            assert!(cask.contains_key(b"key1"));
+            assert_eq!(cask.get(b"key1"), None);
+            assert!(cask.get_ref(b"key1").is_none());
+
+            cask.put(&b"key2"[..], b"encrypted").unwrap();
+            assert_eq!(cask.get(b"key2"), Some(b"encrypted".to_vec()));
+
+            match cask.put(&b"key3"[..], vec![0u8; 2000]) {
+                Err(Error::UnencryptedBlob) => {}
+                _ => panic!("values can't be stored in blob files"),
             }
-        };
+            match cask.put_stream(&b"key3"[..], &[0u8; 10][..]) {
+                Err(Error::UnencryptedBlob) => {}
+                _ => panic!("values can't be stored in blob files"),
+            }
+            assert!(!cask.contains_key(b"key3"));
+        }
 
-        self.index.insert(key, index_entry);
-    }
+        options.upgrade(test_dir.path_str()).unwrap();
 
-    fn delete(&mut self, key: &[u8]) {
-        if self.index.remove(key).is_some() {
-            let entry = Entry::deleted(self.current_sequence, key);
-            let _ = self.log.append_entry(&entry);
-            self.current_sequence += 1;
+        for file_id in find_data_files(test_dir.path()) {
+            let data = fs::read(get_data_file_path(test_dir.path(), file_id)).unwrap();
+            assert!(!data.windows(9).any(|w| w == b"plaintext" || w == b"encrypted"));
         }
+
+        let cask = options.open(test_dir.path_str());
+        assert_eq!(cask.get(b"key1"), Some(b"plaintext".to_vec()));
+        assert_eq!(cask.get(b"key2"), Some(b"encrypted".to_vec()));
     }
-}
 
-#[derive(Clone)]
-pub struct Cask {
-    path: PathBuf,
-    dropped: Arc<AtomicBool>,
-    inner: Arc<RwLock<CaskInner>>,
-}
+    #[test]
+    fn test_concurrency() {
+        let test_dir = TestDir::new("cask-concurrency");
+        let cask = CaskOptions::new().value_cache_size(1 << 16).open(test_dir.path_str());
+        let value = |thread: usize, i: usize, round: usize| format!("{}-{}-{}", thread, i, round);
 
-impl Cask {
-    pub fn open(path: &str, sync: bool) -> Cask {
-        info!("Opening database: {:?}", &path);
-        let mut log = Log::open(path, sync);
-        let mut index = Index::new();
+        thread::scope(|scope| {
+            for t in 0..8 {
+                let cask = &cask;
+                scope.spawn(move || for round in 0..5 {
+                    for i in 0..200 {
+                        let key = format!("key{}-{}", t, i);
+                        cask.put(key.clone(), value(t, i, round)).unwrap();
+                        assert_eq!(cask.get(&key), Some(value(t, i, round).into_bytes()));
 
-        let mut sequence = 0;
+                        if i % 3 == 0 {
+                            cask.delete(&key).unwrap();
+                            assert_eq!(cask.get(&key), None);
+                        }
+                    }
+                });
+            }
 
-        for file_id in log.files() {
-            let mut f = |hint: Hint| {
-                if hint.sequence > sequence {
-                    sequence = hint.sequence;
+            let cask = &cask;
+            let path = test_dir.path();
+            scope.spawn(move || for _ in 0..5 {
+                cask.checkpoint().unwrap();
+                for file_id in find_data_files(path) {
+                    cask.compact_file(file_id);
                 }
+            });
+        });
 
-                index.update(hint, file_id);
-            };
-
-            match log.hints(file_id) {
-                Some(hints) => {
-                    for hint in hints {
-                        f(hint);
-                    }
+        let check = |cask: &Cask| {
+            for t in 0..8 {
+                for i in 0..200 {
+                    let expected = if i % 3 == 0 {
+                        None
+                    } else {
+                        Some(value(t, i, 4).into_bytes())
+                    };
+                    assert_eq!(cask.get(format!("key{}-{}", t, i)), expected);
                 }
-                None => {
-                    for hint in log.recreate_hints(file_id) {
-                        f(hint);
+            }
+
+            assert_eq!(cask.len(), 8 * 133);
+            assert_eq!(cask.stats().live_entries, 8 * 133);
+        };
+
+        check(&cask);
+        drop(cask);
+
+        let cask = Cask::open(test_dir.path_str(), false);
+        check(&cask);
+
+        // a key deleted while its data file is compacted isn't indexed again from its copy
+        cask.put("compacted", "value").unwrap();
+        let file_id = cask.inner.log.active_file_id();
+        cask.inner.log.rotate();
+        let new_file_id = cask.compact_file_aux(file_id).unwrap();
+        cask.delete("compacted").unwrap();
+        cask.finish_compaction(file_id, new_file_id);
+
+        assert_eq!(cask.get("compacted"), None);
+        check(&cask);
+    }
+
+    #[test]
+    fn test_parallel_open() {
+        let test_dir = TestDir::new("cask-parallel-open");
+
+        {
+            let cask = Cask::open(test_dir.path_str(), false);
+            for file in 0..20 {
+                for i in 0..100 {
+                    cask.put(format!("key{}", (file * 37 + i) % 500), format!("{}-{}", file, i))
+                        .unwrap();
+                    if i % 7 == 0 {
+                        cask.delete(format!("key{}", (file * 11 + i) % 500)).unwrap();
                     }
                 }
-            };
+                cask.inner.log.rotate();
+            }
         }
 
-        info!("Opened database: {:?}", &path);
-        info!("Current sequence number: {:?}", sequence);
-
-        let cask = Cask {
-            path: log.path.clone(),
-            dropped: Arc::new(AtomicBool::new(false)),
-            inner: Arc::new(RwLock::new(CaskInner {
-                current_sequence: sequence + 1,
-                log: log,
-                index: index,
-            })),
+        let open = |threads| {
+            let cask = CaskOptions::new().open_threads(threads).open(test_dir.path_str());
+            let mut entries = cask.inner.index.entries();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            (entries, cask.stats().live_entries)
         };
 
-        let caskt = cask.clone();
-        thread::spawn(move || {
-            loop {
-                thread::sleep(Duration::new(COMPACTION_CHECK_FREQUENCY, 0));
+        let serial = open(1);
+        assert!(!serial.0.is_empty());
+        assert_eq!(open(4), serial);
+        assert_eq!(open(32), serial);
 
-                info!("Compaction thread wake up");
+        // files are only handed out within the window following the last merged file
+        let queue = LoadQueue::new(3, 2);
+        assert_eq!(queue.next(), Some(0));
+        assert_eq!(queue.next(), Some(1));
+        queue.merged(1);
+        assert_eq!(queue.next(), Some(2));
+        assert_eq!(queue.next(), None);
+    }
 
-                if caskt.dropped.load(Ordering::SeqCst) {
-                    info!("Cask has been dropped, background compaction thread is exiting");
-                    break;
+    #[test]
+    fn test_checkpoint() {
+        for &mode in &[IndexMode::Full, IndexMode::HashOnly] {
+            let test_dir = TestDir::new("cask-checkpoint");
+            let open = || {
+                CaskOptions::new()
+                    .index_mode(mode)
+                    .blob_threshold(1024)
+                    .open(test_dir.path_str())
+            };
+
+            {
+                let cask = open();
+                for i in 0..300 {
+                    cask.put(format!("key{}", i), format!("value{}", i)).unwrap();
+                    if i % 50 == 0 {
+                        cask.put(format!("blob{}", i), vec![i as u8; 2048]).unwrap();
+                        cask.inner.log.rotate();
+                    }
                 }
 
-                caskt.compact();
+                let update = |i: usize| {
+                    cask.put(format!("key{}", i * 3), format!("new{}", i)).unwrap();
+                    cask.delete(format!("key{}", i * 3 + 1)).unwrap();
+                };
+
+                // the first writes race with the copy of the index, see `Cask::checkpoint`
+                let checkpoint = cask.inner.start_checkpoint();
+                (0..50).for_each(&update);
+                cask.put("blob0", vec![1u8; 4096]).unwrap();
+
+                cask.inner.log.sync_files(checkpoint.watermark).unwrap();
+                write_checkpoint(test_dir.path(),
+                                 ChecksumType::XxHash32,
+                                 &checkpoint,
+                                 &cask.inner.index)
+                    .unwrap();
+
+                (50..100).for_each(&update);
             }
-        });
 
-        cask
+            let snapshot = |cask: &Cask| {
+                let mut entries = cask.inner.index.entries();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                let stats = cask.inner.index.stats();
+                (entries,
+                 cask.stats().live_entries,
+                 format!("{:?}", stats.fragmentation()),
+                 format!("{:?}", stats.blob_fragmentation()))
+            };
+
+            let check = |cask: &Cask| {
+                for i in 0..300 {
+                    let expected = match i % 3 {
+                        0 => Some(format!("new{}", i / 3).into_bytes()),
+                        1 => None,
+                        _ => Some(format!("value{}", i).into_bytes()),
+                    };
+                    assert_eq!(cask.get(format!("key{}", i)), expected);
+                }
+                assert_eq!(cask.get("blob0"), Some(vec![1u8; 4096]));
+                assert_eq!(cask.get("blob50"), Some(vec![50u8; 2048]));
+            };
+
+            // only the data files written since the checkpoint are replayed
+            let from_checkpoint = {
+                let cask = open();
+                assert!(cask.open_summary().checkpoint);
+                assert_eq!(cask.open_summary().files_loaded, 1);
+                check(&cask);
+                snapshot(&cask)
+            };
+
+            fs::remove_file(test_dir.path().join("cask.index")).unwrap();
+
+            let cask = open();
+            assert!(!cask.open_summary().checkpoint);
+            check(&cask);
+            assert_eq!(snapshot(&cask), from_checkpoint);
+        }
+
+        // a corrupt key size fails to read rather than allocating the key
+        assert!(IndexEntry::from_read(&mut &[0xff, 0xff, 0xff, 0xff, 1, 2][..]).is_err());
     }
 
-    fn compact_file_aux(&self, file_id: u32) -> Option<u32> {
-        if file_id == self.inner.read().unwrap().log.active_file_id {
-            return None;
+    #[test]
+    fn test_torn_write() {
+        let test_dir = TestDir::new("cask-torn-write");
+        let path = test_dir.path();
+
+        {
+            let cask = Cask::open(test_dir.path_str(), false);
+            for i in 0..10 {
+                cask.put(format!("key{}", i), vec![i as u8; 100]).unwrap();
+            }
         }
 
-        let hints = {
-            self.inner.read().unwrap().log.hints(file_id)
-        };
+        // a crash while writing the last entry, before the header of the hint file was flushed
+        let data_file_path = get_data_file_path(path, 0);
+        let hint_file_path = get_hint_file_path(path, 0);
+        let data = fs::read(&data_file_path).unwrap();
+        fs::write(&data_file_path, &data[..data.len() - 50]).unwrap();
+        fs::remove_file(&hint_file_path).unwrap();
+        fs::write(get_temp_file_path(&hint_file_path), b"CASKH").unwrap();
 
-        hints.map(|hints| {
-            let new_file_id = {
-                self.inner.read().unwrap().log.new_file_id()
-            };
+        // a crash while writing the header of a new data file
+        fs::write(get_data_file_path(path, 1), b"CASKD").unwrap();
+        fs::write(get_temp_file_path(&get_hint_file_path(path, 1)), b"").unwrap();
 
-            info!("Compacting data file: {} into: {}", file_id, new_file_id);
+        {
+            let cask = Cask::open(test_dir.path_str(), false);
+            assert!(cask.open_summary().recreated_hint_files.is_empty());
+            assert_eq!(cask.open_summary().finished_hint_files, vec![0, 1]);
+            for i in 0..9 {
+                assert_eq!(cask.get(format!("key{}", i)), Some(vec![i as u8; 100]));
+            }
+            assert_eq!(cask.get("key9"), None);
+            cask.put("key9", vec![9u8; 100]).unwrap();
+        }
 
-            let mut log_writer = LogWriter::new(&self.path, new_file_id, false);
-            let mut deletes = HashMap::new();
+        // the data files were truncated to their last valid entry
+        let entry_size = Entry::new(9, &b"key9"[..], &[9u8; 100][..]).size(ChecksumType::XxHash32);
+        assert_eq!(fs::metadata(&data_file_path).unwrap().len(),
+                   data.len() as u64 - entry_size);
+        assert_eq!(fs::metadata(get_data_file_path(path, 1)).unwrap().len(), 0);
 
-            {
-                let inserts = hints.filter(|hint| {
-                    let inner = self.inner.read().unwrap();
-                    let index_entry = inner.index.get(&*hint.key);
+        let cask = Cask::open(test_dir.path_str(), false);
+        assert!(cask.open_summary().recreated_hint_files.is_empty());
+        for i in 0..10 {
+            assert_eq!(cask.get(format!("key{}", i)), Some(vec![i as u8; 100]));
+        }
+    }
 
-                    if hint.deleted {
-                        if index_entry.is_none() {
-                            match deletes.entry(hint.key.to_vec()) {
-                                HashMapEntry::Occupied(mut o) => {
-                                    if *o.get() < hint.sequence {
-                                        o.insert(hint.sequence);
-                                    }
-                                }
-                                HashMapEntry::Vacant(e) => {
-                                    e.insert(hint.sequence);
-                                }
-                            }
-                        }
+    #[test]
+    fn test_index_modes() {
+        for &mode in &[IndexMode::Compact, IndexMode::HashOnly] {
+            let test_dir = TestDir::new("cask-index-modes");
+            let open = || CaskOptions::new().index_mode(mode).open(test_dir.path_str());
 
-                        false
+            let check = |cask: &Cask| {
+                for i in 0..1000 {
+                    let expected = match i % 3 {
+                        0 => Some(format!("new{}", i).into_bytes()),
+                        1 => None,
+                        _ => Some(format!("value{}", i).into_bytes()),
+                    };
+                    assert_eq!(cask.get(format!("key{}", i)), expected);
+                    assert_eq!(cask.contains_key(format!("key{}", i)), i % 3 != 1);
+                }
+                assert_eq!(cask.len(), 667);
+            };
 
-                    } else {
-                        index_entry.is_some() && index_entry.unwrap().sequence == hint.sequence
+            {
+                let cask = open();
+                for i in 0..1000 {
+                    cask.put(format!("key{}", i), format!("value{}", i)).unwrap();
+                    if i % 100 == 0 {
+                        cask.inner.log.rotate();
                     }
-                });
+                }
+                for i in 0..1000 {
+                    match i % 3 {
+                        0 => cask.put(format!("key{}", i), format!("new{}", i)).unwrap(),
+                        1 => cask.delete(format!("key{}", i)).unwrap(),
+                        _ => {}
+                    }
+                }
+                check(&cask);
 
-                for hint in inserts {
-                    let log = &self.inner.read().unwrap().log;
-                    log_writer.write(&log.read_entry(file_id, hint.entry_pos));
+                cask.inner.log.rotate();
+                for file_id in find_data_files(test_dir.path()) {
+                    cask.compact_file(file_id);
                 }
+                check(&cask);
             }
 
-            for (key, sequence) in deletes {
-                log_writer.write(&Entry::deleted(sequence, key));
+            let cask = open();
+            check(&cask);
+
+            if mode != IndexMode::HashOnly {
+                continue;
             }
 
-            new_file_id
-        })
+            // keys which can't be read from their data file fail lookups and writes, rather than
+            // panicking
+            drop(cask);
+            for file_id in find_data_files(test_dir.path()) {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open(get_data_file_path(test_dir.path(), file_id))
+                    .unwrap()
+                    .set_len(20)
+                    .unwrap();
+            }
+
+            let cask = open();
+            assert_eq!(cask.get("key2"), None);
+            assert!(!cask.contains_key("key2"));
+            assert!(cask.put("key2", "value").is_err());
+        }
     }
 
-    pub fn compact_file(&self, file_id: u32) {
-        let new_file_id = self.compact_file_aux(file_id);
+    #[test]
+    fn test_index_spill() {
+        let spill_files = |path: &Path| {
+            fs::read_dir(path)
+                .unwrap()
+                .filter(|f| f.as_ref().unwrap().path().to_str().unwrap().ends_with(".cask.spill"))
+                .count()
+        };
 
-        if let Some(new_file_id) = new_file_id {
-            let hints = {
-                self.inner.read().unwrap().log.hints(new_file_id)
+        for &mode in &[IndexMode::Full, IndexMode::Compact, IndexMode::HashOnly] {
+            let test_dir = TestDir::new("cask-index-spill");
+            let open = || {
+                CaskOptions::new()
+                    .index_mode(mode)
+                    .index_memory_budget(64 * 1024)
+                    .open(test_dir.path_str())
             };
 
-            if let Some(hints) = hints {
-                for hint in hints {
-                    self.inner.write().unwrap().index.update(hint, new_file_id);
+            let check = |cask: &Cask| {
+                for i in 0..10000 {
+                    let expected = match i % 3 {
+                        0 => Some(format!("new{}", i).into_bytes()),
+                        1 => None,
+                        _ => Some(format!("value{}", i).into_bytes()),
+                    };
+                    assert_eq!(cask.get(format!("key{}", i)), expected);
                 }
+                assert_eq!(cask.len(), 6667);
+            };
 
-                self.inner.write().unwrap().log.swap_file(file_id, new_file_id);
+            {
+                let cask = open();
+                for i in 0..10000 {
+                    cask.put(format!("key{}", i), format!("value{}", i)).unwrap();
+                }
+                for i in 0..10000 {
+                    match i % 3 {
+                        0 => cask.put(format!("key{}", i), format!("new{}", i)).unwrap(),
+                        1 => cask.delete(format!("key{}", i)).unwrap(),
+                        _ => {}
+                    }
+                }
+                check(&cask);
+                assert!(cask.stats().index_spilled_entries > 0);
 
-                info!("Finished compacting data file: {} into: {}",
-                      file_id,
-                      new_file_id);
+                // runs of similar sizes are merged, a shard only keeps a few of them
+                assert!(spill_files(test_dir.path()) <= 16 * 6);
+            }
+
+            assert_eq!(spill_files(test_dir.path()), 0);
+
+            // the spill files of a crashed process are removed
+            fs::write(test_dir.path().join("0000000001-0000000000.cask.spill"), b"").unwrap();
+            let cask = open();
+            check(&cask);
+            drop(cask);
+            assert_eq!(spill_files(test_dir.path()), 0);
+        }
+    }
+
+    #[test]
+    fn test_value_cache() {
+        let test_dir = TestDir::new("cask-value-cache");
+        let cask = CaskOptions::new()
+            .mmap(true)
+            .blob_threshold(100)
+            .value_cache_size(1 << 16)
+            .open(test_dir.path_str());
+
+        cask.put("plain", "value").unwrap();
+        cask.put("blob", vec![1u8; 1000]).unwrap();
+        // the active data file isn't memory mapped
+        cask.inner.log.rotate();
+
+        // values borrowed from the memory mapping are neither copied nor cached
+        match cask.get_ref("plain").unwrap() {
+            ValueRef::Mapped { .. } => {}
+            value => panic!("Expected a mapped value, got {:?}", value),
+        }
+
+        let mut buf = Vec::with_capacity(4096);
+        let ptr = buf.as_ptr();
+        assert!(cask.get_into("plain", &mut buf));
+        assert_eq!(buf, b"value");
+        assert_eq!(cask.stats().value_cache_bytes, 0);
+
+        // values read from blob files are cached and shared with the cache
+        assert_eq!(&*cask.get_ref("blob").unwrap(), &[1u8; 1000][..]);
+        match cask.get_ref("blob").unwrap() {
+            ValueRef::Cached(ref value) => assert_eq!(&value[..], &[1u8; 1000][..]),
+            value => panic!("Expected a cached value, got {:?}", value),
+        }
+
+        // cached values are copied into the buffer rather than replacing it
+        assert!(cask.get_into("blob", &mut buf));
+        assert_eq!(buf, vec![1u8; 1000]);
+        assert_eq!(buf.as_ptr(), ptr);
+
+        assert_eq!(cask.get("plain"), Some(b"value".to_vec()));
+        assert_eq!(cask.get("plain"), Some(b"value".to_vec()));
+
+        let stats = cask.stats();
+        assert_eq!(stats.value_cache_hits, 3);
+        assert!(stats.value_cache_bytes > 0);
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        for &(mode, memory_budget) in &[(IndexMode::HashOnly, None),
+                                         (IndexMode::Full, Some(64 * 1024)),
+                                         (IndexMode::HashOnly, Some(64 * 1024))] {
+            let test_dir = TestDir::new("cask-bloom-filter");
+            let open = || {
+                let mut options = CaskOptions::new();
+                options.index_mode(mode).bloom_filter(true);
+                if let Some(memory_budget) = memory_budget {
+                    options.index_memory_budget(memory_budget);
+                }
+                options.open(test_dir.path_str())
             };
+
+            {
+                let cask = open();
+                for i in 0..1000 {
+                    cask.put(format!("key{}", i), format!("value{}", i)).unwrap();
+                }
+            }
+
+            // the filters are built once the index is loaded, then grow with the keys added
+            let cask = open();
+            for i in 1000..20000 {
+                cask.put(format!("key{}", i), format!("value{}", i)).unwrap();
+            }
+            for i in (0..20000).filter(|i| i % 3 != 2) {
+                cask.delete(format!("key{}", i)).unwrap();
+            }
+
+            for i in 0..20000 {
+                let expected = if i % 3 != 2 {
+                    None
+                } else {
+                    Some(format!("value{}", i).into_bytes())
+                };
+                assert_eq!(cask.get(format!("key{}", i)), expected);
+                assert!(!cask.contains_key(format!("absent{}", i)));
+            }
+
+            // the filters were rebuilt to hold the added keys and shed the deleted ones
+            let mut filter_len = 0;
+            for shard in &cask.inner.index.shards {
+                let entries = shard.entries.read().unwrap();
+                let filter = entries.filter.as_ref().unwrap();
+                assert!(filter.len() <= filter.capacity());
+                assert!(entries.filter_removed <= filter.len() / 2);
+                filter_len += filter.len();
+            }
+            assert!(filter_len < 15000);
         }
     }
 
-    pub fn compact(&self) {
-        let iter = {
-            self.inner
-                .read()
+    #[test]
+    fn test_read_only() {
+        let test_dir = TestDir::new("cask-read-only");
+        let list_dir = || {
+            let mut names: Vec<_> = fs::read_dir(test_dir.path())
                 .unwrap()
-                .index
-                .stats
-                .fragmentation()
+                .map(|f| f.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+        let sorted = |mut entries: Vec<(Vec<u8>, Vec<u8>)>| {
+            entries.sort();
+            entries
+        };
+        let expected = |keys: &mut dyn Iterator<Item = usize>, version: usize| {
+            keys.map(|i| {
+                    let value = format!("value{}-{}", i, version);
+                    (format!("key{}", i).into_bytes(), value.into_bytes())
+                })
+                .collect::<Vec<_>>()
         };
 
-        for &(file_id, fragmentation) in iter.iter().filter(|e| e.1 >= FRAGMENTATION_THRESHOLD) {
+        let writer = Cask::open(test_dir.path_str(), false);
+        for i in 0..100 {
+            writer.put(format!("key{}", i), format!("value{}-0", i)).unwrap();
+        }
+        writer.inner.log.rotate();
 
-            info!("File {} has fragmentation factor of {}%, adding for compaction",
-                  file_id,
-                  fragmentation * 100.0);
+        // the reader creates no file, nor writes
+        let files = list_dir();
+        let reader = Cask::open_read_only(test_dir.path_str()).unwrap();
+        assert!(reader.is_read_only());
+        assert_eq!(reader.get("key0"), Some(b"value0-0".to_vec()));
+        assert_eq!(sorted(reader.iter().unwrap().collect()), sorted(expected(&mut (0..100), 0)));
+        assert!(reader.put("key0", "value").is_err());
+        assert!(reader.delete("key0").is_err());
+        reader.compact();
+        reader.refresh();
+        drop(reader);
+        assert_eq!(list_dir(), files);
 
-            self.compact_file(file_id);
+        let reader = Cask::open_read_only(test_dir.path_str()).unwrap();
+
+        // puts and deletes of the writer are seen once the reader is refreshed
+        for i in 0..50 {
+            writer.put(format!("key{}", i), format!("value{}-1", i)).unwrap();
         }
-    }
+        for i in 50..60 {
+            writer.delete(format!("key{}", i)).unwrap();
+        }
+        writer.inner.log.rotate();
 
-    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<Vec<u8>> {
-        self.inner.read().unwrap().get(key.as_ref())
-    }
+        assert_eq!(reader.get("key0"), Some(b"value0-0".to_vec()));
+        assert_eq!(reader.get("key50"), Some(b"value50-0".to_vec()));
 
-    pub fn put<K: Into<Vec<u8>>, V: AsRef<[u8]>>(&self, key: K, value: V) {
-        self.inner.write().unwrap().put(key.into(), value.as_ref())
-    }
+        reader.refresh();
+        assert_eq!(reader.get("key0"), Some(b"value0-1".to_vec()));
+        assert_eq!(reader.get("key50"), None);
+        assert_eq!(reader.len(), 90);
+
+        // reads, and iterators started before, survive the compaction of the files they read
+        let iter = reader.iter().unwrap();
+        let active_file_id = writer.inner.log.rotate();
+        for file_id in find_data_files(test_dir.path()) {
+            if file_id < active_file_id {
+                writer.compact_file(file_id);
+            }
+        }
+        assert!(!find_data_files(test_dir.path()).contains(&0));
+
+        let mut all_entries = expected(&mut (0..50), 1);
+        all_entries.extend(expected(&mut (60..100), 0));
+        assert_eq!(sorted(iter.collect()), sorted(all_entries.clone()));
 
-    pub fn delete<K: AsRef<[u8]>>(&self, key: K) {
-        self.inner.write().unwrap().delete(key.as_ref())
+        for (key, value) in all_entries {
+            assert_eq!(reader.get(&key), Some(value));
+        }
+        assert_eq!(reader.get("key50"), None);
     }
-}
 
-impl Drop for Cask {
-    fn drop(&mut self) {
-        self.dropped.store(true, Ordering::SeqCst);
+    #[test]
+    fn test_clone() {
+        let test_dir = TestDir::new("cask-clone");
+        let size_threshold = 1024 * 1024;
+        let cask = CaskOptions::new()
+            .preallocate(true)
+            .size_threshold(size_threshold)
+            .open(test_dir.path_str());
+        let data_file_path = get_data_file_path(test_dir.path(), 0);
+        let data_file_size = || fs::metadata(&data_file_path).unwrap().len();
+
+        // dropping a clone leaves the database open for the other handles
+        let clone = cask.clone();
+        thread::spawn(move || clone.put("key", "value").unwrap()).join().unwrap();
+
+        assert!(!cask.dropped.load(Ordering::SeqCst));
+        let shutdown = cask.shutdown.as_ref().unwrap();
+        assert!(shutdown.compaction_thread.lock().unwrap().is_some());
+        assert_eq!(data_file_size(), size_threshold as u64);
+
+        cask.put("key2", "value2").unwrap();
+        assert_eq!(cask.get("key"), Some(b"value".to_vec()));
+
+        // the active data file is truncated once the last handle is dropped
+        drop(cask);
+        assert!(data_file_size() < size_threshold as u64);
     }
 }
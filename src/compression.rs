@@ -0,0 +1,240 @@
+use std::fmt;
+use std::io;
+use std::io::Cursor;
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use errors::{Error, Result};
+
+// codec_id(1) + uncompressed_size(4)
+pub const COMPRESSED_VALUE_HEADER_SIZE: usize = 5;
+
+/// A compression codec used to compress values before they are written to disk.
+///
+/// The id of the codec is stored with every value it compresses so that the value can be
+/// decompressed later on, even if the database is opened with a different codec. Ids up to 127 are
+/// reserved for the codecs provided by cask.
+pub trait Codec: fmt::Debug + Send + Sync {
+    fn id(&self) -> u8;
+
+    fn compress(&self, value: &[u8]) -> io::Result<Vec<u8>>;
+
+    fn decompress(&self, compressed: &[u8], size: usize) -> io::Result<Vec<u8>>;
+}
+
+/// LZ4 block compression.
+#[cfg(feature = "lz4")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Codec for Lz4 {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn compress(&self, value: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(::lz4_flex::block::compress(value))
+    }
+
+    fn decompress(&self, compressed: &[u8], size: usize) -> io::Result<Vec<u8>> {
+        ::lz4_flex::block::decompress(compressed, size)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// Snappy raw compression.
+#[cfg(feature = "snappy")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Snappy;
+
+#[cfg(feature = "snappy")]
+impl Codec for Snappy {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn compress(&self, value: &[u8]) -> io::Result<Vec<u8>> {
+        ::snap::raw::Encoder::new().compress_vec(value).map_err(io::Error::from)
+    }
+
+    fn decompress(&self, compressed: &[u8], _size: usize) -> io::Result<Vec<u8>> {
+        ::snap::raw::Decoder::new().decompress_vec(compressed).map_err(io::Error::from)
+    }
+}
+
+/// Zstandard compression with a configurable compression level.
+#[cfg(feature = "zstd")]
+#[derive(Clone, Copy, Debug)]
+pub struct Zstd {
+    level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Zstd {
+    pub fn new(level: i32) -> Zstd {
+        Zstd { level }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Default for Zstd {
+    fn default() -> Zstd {
+        Zstd::new(::zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Codec for Zstd {
+    fn id(&self) -> u8 {
+        3
+    }
+
+    fn compress(&self, value: &[u8]) -> io::Result<Vec<u8>> {
+        ::zstd::bulk::compress(value, self.level)
+    }
+
+    fn decompress(&self, compressed: &[u8], size: usize) -> io::Result<Vec<u8>> {
+        ::zstd::bulk::decompress(compressed, size)
+    }
+}
+
+/// The set of codecs that can be used to decompress values, indexed by codec id.
+#[derive(Clone)]
+pub struct Codecs {
+    codecs: Vec<Arc<dyn Codec>>,
+}
+
+impl Codecs {
+    /// Creates a new set with all the codecs enabled through cargo features.
+    pub fn new() -> Codecs {
+        #[allow(unused_mut)]
+        let mut codecs = Codecs { codecs: Vec::new() };
+
+        #[cfg(feature = "lz4")]
+        codecs.register(Arc::new(Lz4));
+
+        #[cfg(feature = "snappy")]
+        codecs.register(Arc::new(Snappy));
+
+        #[cfg(feature = "zstd")]
+        codecs.register(Arc::new(Zstd::default()));
+
+        codecs
+    }
+
+    pub fn register(&mut self, codec: Arc<dyn Codec>) {
+        self.codecs.retain(|c| c.id() != codec.id());
+        self.codecs.push(codec);
+    }
+
+    pub fn get(&self, id: u8) -> Option<&Arc<dyn Codec>> {
+        self.codecs.iter().find(|c| c.id() == id)
+    }
+}
+
+impl fmt::Debug for Codecs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.codecs.iter()).finish()
+    }
+}
+
+/// Compresses `value` with `codec`, returning `None` if the compressed value isn't any smaller.
+/// The compressed value is prefixed with the codec id and the uncompressed size.
+pub fn compress(codec: &dyn Codec, value: &[u8]) -> Result<Option<Vec<u8>>> {
+    let compressed = codec.compress(value)?;
+
+    if compressed.len() + COMPRESSED_VALUE_HEADER_SIZE >= value.len() {
+        return Ok(None);
+    }
+
+    let mut buf = Vec::with_capacity(COMPRESSED_VALUE_HEADER_SIZE + compressed.len());
+    buf.write_u8(codec.id())?;
+    buf.write_u32::<LittleEndian>(value.len() as u32)?;
+    buf.extend_from_slice(&compressed);
+
+    Ok(Some(buf))
+}
+
+pub fn decompress(codecs: &Codecs, value: &[u8]) -> Result<Vec<u8>> {
+    let mut cursor = Cursor::new(value);
+    let codec_id = cursor.read_u8()?;
+    let size = cursor.read_u32::<LittleEndian>()?;
+
+    let codec = codecs.get(codec_id).ok_or(Error::UnknownCodec(codec_id))?;
+    let decompressed = codec.decompress(&value[COMPRESSED_VALUE_HEADER_SIZE..], size as usize)?;
+
+    Ok(decompressed)
+}
+
+/// Returns the uncompressed size of a value compressed with `compress`.
+pub fn uncompressed_size(value: &[u8]) -> Result<u32> {
+    let mut cursor = Cursor::new(value);
+    cursor.read_u8()?;
+    Ok(cursor.read_u32::<LittleEndian>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use compression::{compress, decompress, uncompressed_size, Codec, Codecs};
+
+    #[derive(Debug)]
+    struct Rle;
+
+    impl Codec for Rle {
+        fn id(&self) -> u8 {
+            200
+        }
+
+        fn compress(&self, value: &[u8]) -> io::Result<Vec<u8>> {
+            let mut v = Vec::new();
+            for &b in value {
+                if v.len() >= 2 && v[v.len() - 1] == b && v[v.len() - 2] < 255 {
+                    let n = v.len() - 2;
+                    v[n] += 1;
+                } else {
+                    v.push(1);
+                    v.push(b);
+                }
+            }
+            Ok(v)
+        }
+
+        fn decompress(&self, compressed: &[u8], size: usize) -> io::Result<Vec<u8>> {
+            let mut v = Vec::with_capacity(size);
+            for c in compressed.chunks(2) {
+                for _ in 0..c[0] {
+                    v.push(c[1]);
+                }
+            }
+            Ok(v)
+        }
+    }
+
+    #[test]
+    fn test_compression() {
+        let mut codecs = Codecs::new();
+        codecs.register(::std::sync::Arc::new(Rle));
+
+        let value = vec![42u8; 1000];
+        let compressed = compress(&Rle, &value).unwrap().unwrap();
+
+        assert!(compressed.len() < value.len());
+        assert_eq!(uncompressed_size(&compressed).unwrap(), 1000);
+        assert_eq!(decompress(&codecs, &compressed).unwrap(), value);
+
+        // incompressible values are stored as is
+        let value: Vec<u8> = (0..255).collect();
+        assert!(compress(&Rle, &value).unwrap().is_none());
+
+        assert!(decompress(&Codecs::new(), &compress(&Rle, &[0; 100]).unwrap().unwrap()).is_err());
+
+        // truncated values are errors
+        assert!(uncompressed_size(&[]).is_err());
+        assert!(uncompressed_size(&compressed[..3]).is_err());
+        assert!(decompress(&codecs, &[]).is_err());
+    }
+}
@@ -1,22 +1,183 @@
 use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher as StdHasher};
 use std::io::prelude::*;
-use std::io::Cursor;
+use std::io;
+use std::io::{Cursor, SeekFrom};
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use util::{xxhash32, XxHash32};
+use compression;
+use encryption::ENCRYPTION_OVERHEAD;
+use errors::{Error, Result};
+use util::{read_full, ChecksumType, Hasher};
 
-const ENTRY_STATIC_SIZE: usize = 18; // checksum(4) + sequence(8) + key_size(2) + value_size(4)
+// sequence(8) + flags(1) + key_size(4) + value_size(4), preceded by the checksum
+const ENTRY_STATIC_SIZE: usize = 17;
 const ENTRY_TOMBSTONE: u32 = !0;
 
+const ENTRY_FLAG_BLOB: u8 = 1;
+const ENTRY_FLAG_COMPRESSED: u8 = 2;
+const ENTRY_FLAG_ENCRYPTED: u8 = 4;
+
+pub const BLOB_REF_SIZE: usize = 20; // file_id(4) + value_pos(8) + value_size(8)
+
+pub const MAX_KEY_SIZE: usize = !0u32 as usize;
+pub const MAX_VALUE_SIZE: usize = ENTRY_TOMBSTONE as usize - 1;
+
+const FILE_HEADER_SIZE: usize = 11; // magic(8) + version(2) + checksum_type(1)
+const DATA_FILE_HEADER_SIZE: usize = 15; // file header + salt(4)
+const DATA_FILE_MAGIC: &[u8] = b"CASKDATA";
+const HINT_FILE_MAGIC: &[u8] = b"CASKHINT";
+const BLOB_FILE_MAGIC: &[u8] = b"CASKBLOB";
+const INDEX_FILE_MAGIC: &[u8] = b"CASKINDX";
+
+/// Files written by cask 0.4 and earlier carry no header. Their entries have no flags and store the
+/// key size as an u16, they are always checksummed with xxHash32 and their hint files have a single
+/// checksum instead of checksummed blocks.
+pub const LEGACY_FORMAT_VERSION: u16 = 0;
+pub const FORMAT_VERSION: u16 = 1;
+
 pub type SequenceNumber = u64;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileKind {
+    Data,
+    Hint,
+    Blob,
+    Index,
+}
+
+impl FileKind {
+    fn magic(&self) -> &'static [u8] {
+        match *self {
+            FileKind::Data => DATA_FILE_MAGIC,
+            FileKind::Hint => HINT_FILE_MAGIC,
+            FileKind::Blob => BLOB_FILE_MAGIC,
+            FileKind::Index => INDEX_FILE_MAGIC,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FileHeader {
+    pub kind: FileKind,
+    pub version: u16,
+    pub checksum: ChecksumType,
+    /// Mixed into the checksums of the entries of a data file, see `entry_hasher`.
+    pub salt: u32,
+}
+
+impl FileHeader {
+    pub fn new(kind: FileKind, checksum: ChecksumType) -> FileHeader {
+        FileHeader {
+            kind,
+            version: FORMAT_VERSION,
+            checksum,
+            salt: 0,
+        }
+    }
+
+    /// Returns the header of a new file with a random salt. Compacted data files can be recycled
+    /// without being truncated, see `CaskOptions::max_free_files`, and the salt keeps the entries
+    /// of the previous file from being read as entries of the new one.
+    pub fn salted(kind: FileKind, checksum: ChecksumType) -> FileHeader {
+        let salt = RandomState::new().build_hasher().finish() as u32;
+        FileHeader { salt, ..FileHeader::new(kind, checksum) }
+    }
+
+    /// Returns a hasher for the checksum of an entry of the file.
+    pub fn entry_hasher(&self) -> Hasher {
+        let mut hasher = self.checksum.hasher();
+        if self.has_salt() {
+            let mut salt = [0u8; 4];
+            LittleEndian::write_u32(&mut salt, self.salt);
+            hasher.update(&salt);
+        }
+        hasher
+    }
+
+    fn has_salt(&self) -> bool {
+        self.kind == FileKind::Data && self.version != LEGACY_FORMAT_VERSION
+    }
+
+    /// Whether the file is in the current format.
+    pub fn is_current(&self) -> bool {
+        self.version == FORMAT_VERSION
+    }
+
+    pub fn size(&self) -> u64 {
+        if self.version == LEGACY_FORMAT_VERSION {
+            0
+        } else if self.has_salt() {
+            DATA_FILE_HEADER_SIZE as u64
+        } else {
+            FILE_HEADER_SIZE as u64
+        }
+    }
+
+    pub fn write_bytes<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(self.kind.magic())?;
+        writer.write_u16::<LittleEndian>(self.version)?;
+        writer.write_u8(self.checksum.id())?;
+        if self.has_salt() {
+            writer.write_u32::<LittleEndian>(self.salt)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the header at the start of `reader`. Files without a header are assumed to be legacy
+    /// files and the reader is rewound so that the first entry can be read.
+    pub fn from_read<R: Read + Seek>(kind: FileKind, reader: &mut R) -> Result<FileHeader> {
+        let mut magic = [0u8; 8];
+        let read = read_full(reader, &mut magic)?;
+
+        if read < magic.len() || magic != kind.magic() {
+            reader.seek(SeekFrom::Start(0))?;
+
+            return Ok(FileHeader {
+                kind,
+                version: LEGACY_FORMAT_VERSION,
+                checksum: ChecksumType::XxHash32,
+                salt: 0,
+            });
+        }
+
+        let version = reader.read_u16::<LittleEndian>()?;
+        if version == LEGACY_FORMAT_VERSION || version > FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let id = reader.read_u8()?;
+        let checksum = ChecksumType::from_id(id).ok_or(Error::UnknownChecksum(id))?;
+
+        let salt = if kind == FileKind::Data {
+            reader.read_u32::<LittleEndian>()?
+        } else {
+            0
+        };
+
+        Ok(FileHeader {
+            kind,
+            version,
+            checksum,
+            salt,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Entry<'a> {
     pub key: Cow<'a, [u8]>,
     pub value: Cow<'a, [u8]>,
     pub sequence: SequenceNumber,
     pub deleted: bool,
+    /// Whether the value holds a reference to a blob stored outside of the data file.
+    pub blob: bool,
+    /// Whether the value was compressed, see `compression::compress`.
+    pub compressed: bool,
+    /// Whether the value was encrypted, see `encryption::Keys::encrypt`.
+    pub encrypted: bool,
 }
 
 impl<'a> Entry<'a> {
@@ -24,14 +185,40 @@ impl<'a> Entry<'a> {
         where Cow<'a, [u8]>: From<K>,
               Cow<'a, [u8]>: From<V>
     {
+        let k = Cow::from(key);
         let v = Cow::from(value);
-        assert!(v.len() < ENTRY_TOMBSTONE as usize);
+        assert!(k.len() <= MAX_KEY_SIZE);
+        assert!(v.len() <= MAX_VALUE_SIZE);
 
         Entry {
-            key: Cow::from(key),
+            key: k,
             value: v,
-            sequence: sequence,
+            sequence,
+            deleted: false,
+            blob: false,
+            compressed: false,
+            encrypted: false,
+        }
+    }
+
+    pub fn compressed<K, V>(sequence: SequenceNumber, key: K, value: V) -> Entry<'a>
+        where Cow<'a, [u8]>: From<K>,
+              Cow<'a, [u8]>: From<V>
+    {
+        Entry { compressed: true, ..Entry::new(sequence, key, value) }
+    }
+
+    pub fn blob<K>(sequence: SequenceNumber, key: K, blob_ref: &BlobRef) -> Entry<'a>
+        where Cow<'a, [u8]>: From<K>
+    {
+        Entry {
+            key: Cow::from(key),
+            value: Cow::Owned(blob_ref.to_bytes()),
+            sequence,
             deleted: false,
+            blob: true,
+            compressed: false,
+            encrypted: false,
         }
     }
 
@@ -41,20 +228,93 @@ impl<'a> Entry<'a> {
         Entry {
             key: Cow::from(key),
             value: Cow::Borrowed(&[]),
-            sequence: sequence,
+            sequence,
             deleted: true,
+            blob: false,
+            compressed: false,
+            encrypted: false,
         }
     }
 
-    pub fn size(&self) -> u64 {
-        ENTRY_STATIC_SIZE as u64 + self.key.len() as u64 + self.value.len() as u64
+    /// The size of the entry when written with the current format version and `checksum`.
+    pub fn size(&self, checksum: ChecksumType) -> u64 {
+        entry_static_size(FORMAT_VERSION, checksum) as u64 + self.key.len() as u64 +
+        self.value.len() as u64
+    }
+
+    /// The offset of the value from the start of the entry when stored in a file with `header`.
+    pub fn value_offset(&self, header: &FileHeader) -> usize {
+        entry_static_size(header.version, header.checksum) + self.key.len()
+    }
+
+    /// Returns an entry that owns its key and value.
+    pub fn into_owned<'b>(self) -> Entry<'b> {
+        Entry {
+            key: Cow::Owned(self.key.into_owned()),
+            value: Cow::Owned(self.value.into_owned()),
+            sequence: self.sequence,
+            deleted: self.deleted,
+            blob: self.blob,
+            compressed: self.compressed,
+            encrypted: self.encrypted,
+        }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut cursor = Cursor::new(Vec::with_capacity(self.size() as usize));
-        cursor.set_position(4);
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+
+        if self.blob {
+            flags |= ENTRY_FLAG_BLOB;
+        }
+
+        if self.compressed {
+            flags |= ENTRY_FLAG_COMPRESSED;
+        }
+
+        if self.encrypted {
+            flags |= ENTRY_FLAG_ENCRYPTED;
+        }
+
+        flags
+    }
+
+    pub fn blob_ref(&self) -> Option<BlobRef> {
+        if self.blob {
+            BlobRef::from_bytes(&self.value).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Returns the size of the value as seen by users, i.e. before compression and encryption or
+    /// the size of the blob it refers to.
+    pub fn value_len(&self) -> u64 {
+        match (self.blob_ref(), self.uncompressed_size()) {
+            (Some(blob_ref), _) => blob_ref.value_size,
+            (None, Some(size)) => size as u64,
+            (None, None) if self.encrypted => (self.value.len() - ENCRYPTION_OVERHEAD) as u64,
+            (None, None) => self.value.len() as u64,
+        }
+    }
+
+    pub fn uncompressed_size(&self) -> Option<u32> {
+        if self.compressed {
+            compression::uncompressed_size(&self.value).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Returns the entry as written to a file with the given `header`.
+    pub fn to_bytes(&self, header: &FileHeader) -> Vec<u8> {
+        let checksum = header.checksum;
+        let checksum_size = checksum.size();
+
+        let mut cursor = Cursor::new(Vec::with_capacity(self.size(checksum) as usize));
+        cursor.set_position(checksum_size as u64);
         cursor.write_u64::<LittleEndian>(self.sequence).unwrap();
-        cursor.write_u16::<LittleEndian>(self.key.len() as u16).unwrap();
+        cursor.write_u8(self.flags()).unwrap();
+        cursor.write_u32::<LittleEndian>(self.key.len() as u32).unwrap();
 
         if self.deleted {
             cursor.write_u32::<LittleEndian>(ENTRY_TOMBSTONE).unwrap();
@@ -65,18 +325,28 @@ impl<'a> Entry<'a> {
             cursor.write_all(&self.value).unwrap();
         }
 
-        let checksum = xxhash32(&cursor.get_ref()[4..]);
+        let hash = {
+            let mut hasher = header.entry_hasher();
+            hasher.update(&cursor.get_ref()[checksum_size..]);
+            hasher.get()
+        };
         cursor.set_position(0);
-        cursor.write_u32::<LittleEndian>(checksum).unwrap();
+        checksum.write(&mut cursor, hash).unwrap();
 
         cursor.into_inner()
     }
 
-    pub fn write_bytes<W: Write>(&self, writer: &mut W) {
-        let mut cursor = Cursor::new(Vec::with_capacity(ENTRY_STATIC_SIZE));
-        cursor.set_position(4);
+    /// Writes the entry to `writer`, a file with the given `header`.
+    pub fn write_bytes<W: Write>(&self, writer: &mut W, header: &FileHeader) {
+        let checksum = header.checksum;
+        let checksum_size = checksum.size();
+
+        let mut cursor = Cursor::new(Vec::with_capacity(entry_static_size(FORMAT_VERSION,
+                                                                          checksum)));
+        cursor.set_position(checksum_size as u64);
         cursor.write_u64::<LittleEndian>(self.sequence).unwrap();
-        cursor.write_u16::<LittleEndian>(self.key.len() as u16).unwrap();
+        cursor.write_u8(self.flags()).unwrap();
+        cursor.write_u32::<LittleEndian>(self.key.len() as u32).unwrap();
 
         if self.deleted {
             cursor.write_u32::<LittleEndian>(ENTRY_TOMBSTONE).unwrap();
@@ -84,16 +354,16 @@ impl<'a> Entry<'a> {
             cursor.write_u32::<LittleEndian>(self.value.len() as u32).unwrap();
         }
 
-        let checksum = {
-            let mut hasher = XxHash32::new();
-            hasher.update(&cursor.get_ref()[4..]);
+        let hash = {
+            let mut hasher = header.entry_hasher();
+            hasher.update(&cursor.get_ref()[checksum_size..]);
             hasher.update(&self.key);
             hasher.update(&self.value);
             hasher.get()
         };
 
         cursor.set_position(0);
-        cursor.write_u32::<LittleEndian>(checksum).unwrap();
+        checksum.write(&mut cursor, hash).unwrap();
 
         writer.write_all(&cursor.into_inner()).unwrap();
         writer.write_all(&self.key).unwrap();
@@ -103,112 +373,149 @@ impl<'a> Entry<'a> {
         }
     }
 
-    pub fn from_bytes(bytes: &'a [u8]) -> Entry<'a> {
-        let mut cursor = Cursor::new(bytes);
-
-        let checksum = cursor.read_u32::<LittleEndian>().unwrap();
-        assert_eq!(xxhash32(&bytes[4..]), checksum);
+    /// Reads the entry at the start of `bytes`, stored in a file with the given `header`. The key
+    /// and the value are borrowed from `bytes`, which may extend past the end of the entry.
+    pub fn from_bytes(bytes: &'a [u8], header: &FileHeader) -> Result<Entry<'a>> {
+        let version = header.version;
+        let checksum_size = header.checksum.size();
+        let static_size = entry_static_size(version, header.checksum);
 
-        let sequence = cursor.read_u64::<LittleEndian>().unwrap();
-        let key_size = cursor.read_u16::<LittleEndian>().unwrap();
-        let value_size = cursor.read_u32::<LittleEndian>().unwrap();
+        let mut cursor = Cursor::new(bytes);
+        let checksum = header.checksum.read(&mut cursor)?;
+        let sequence = cursor.read_u64::<LittleEndian>()?;
+        let flags = read_flags(&mut cursor, version)?;
+        let key_size = read_key_size(&mut cursor, version)?;
+        let value_size = cursor.read_u32::<LittleEndian>()?;
 
         let deleted = value_size == ENTRY_TOMBSTONE;
 
-        let value = if deleted {
-            let empty: &[u8] = &[];
-            Cow::from(empty)
+        let key_end = static_size + key_size;
+        let value_end = if deleted {
+            key_end
         } else {
-            Cow::from(&bytes[ENTRY_STATIC_SIZE + key_size as usize..])
+            key_end + value_size as usize
         };
 
-        Entry {
-            key: Cow::from(&bytes[ENTRY_STATIC_SIZE..ENTRY_STATIC_SIZE + key_size as usize]),
-            value: value,
-            sequence: sequence,
-            deleted: value_size == ENTRY_TOMBSTONE,
+        if bytes.len() < value_end {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated entry").into());
         }
-    }
-
-    pub fn from_read<R: Read>(reader: &mut R) -> Entry<'a> {
-        let mut header = vec![0u8; ENTRY_STATIC_SIZE as usize];
-        reader.read(&mut header).unwrap();
 
-        let mut cursor = Cursor::new(header);
-        let checksum = cursor.read_u32::<LittleEndian>().unwrap();
-        let sequence = cursor.read_u64::<LittleEndian>().unwrap();
-        let key_size = cursor.read_u16::<LittleEndian>().unwrap();
-        let value_size = cursor.read_u32::<LittleEndian>().unwrap();
+        let hash = {
+            let mut hasher = header.entry_hasher();
+            hasher.update(&bytes[checksum_size..value_end]);
+            hasher.get()
+        };
 
-        let mut key = vec![0u8; key_size as usize];
-        reader.read_exact(&mut key).unwrap();
+        if hash != checksum {
+            return Err(Error::InvalidChecksum {
+                expected: checksum,
+                found: hash,
+            });
+        }
 
-        let deleted = value_size == ENTRY_TOMBSTONE;
+        Ok(Entry {
+            key: Cow::from(&bytes[static_size..key_end]),
+            value: Cow::from(&bytes[key_end..value_end]),
+            sequence,
+            deleted,
+            blob: flags & ENTRY_FLAG_BLOB != 0,
+            compressed: flags & ENTRY_FLAG_COMPRESSED != 0,
+            encrypted: flags & ENTRY_FLAG_ENCRYPTED != 0,
+        })
+    }
 
-        let value = if deleted {
-            let empty: &[u8] = &[];
-            Cow::from(empty)
-        } else {
-            let mut value = vec![0u8; value_size as usize];
-            reader.read_exact(&mut value).unwrap();
-            Cow::from(value)
-        };
+    /// Reads an entry stored in a file with the given `header`.
+    pub fn from_read<R: Read>(reader: &mut R, header: &FileHeader) -> Result<Entry<'a>> {
+        let mut value = Vec::new();
+        let entry = read_entry(reader, header, &mut value)?;
 
-        let hash = {
-            let mut hasher = XxHash32::new();
-            hasher.update(&cursor.get_ref()[4..]);
-            hasher.update(&key);
-            hasher.update(&value);
-            hasher.get()
-        };
+        Ok(Entry { value: Cow::from(value), ..entry })
+    }
 
-        assert_eq!(hash, checksum);
+    /// Reads an entry as `from_read` but reads its value into `buf`, reusing its allocation. The
+    /// value of the returned entry is borrowed from `buf`.
+    pub fn from_read_into<R: Read>(reader: &mut R,
+                                   header: &FileHeader,
+                                   buf: &'a mut Vec<u8>)
+                                   -> Result<Entry<'a>> {
+        let entry = read_entry(reader, header, buf)?;
 
-        Entry {
-            key: Cow::from(key),
-            value: value,
-            sequence: sequence,
-            deleted: deleted,
-        }
+        Ok(Entry { value: Cow::from(&buf[..]), ..entry })
     }
 }
 
 pub struct Hint<'a> {
     pub key: Cow<'a, [u8]>,
     pub entry_pos: u64,
+    /// The size of the entry in the data file.
+    pub entry_size: u64,
     pub value_size: u32,
     pub sequence: SequenceNumber,
     pub deleted: bool,
+    pub blob_ref: Option<BlobRef>,
+    pub uncompressed_size: Option<u32>,
+    pub encrypted: bool,
 }
 
 impl<'a> Hint<'a> {
-    pub fn new(e: &'a Entry, entry_pos: u64) -> Hint<'a> {
+    pub fn new(e: &'a Entry, entry_pos: u64, entry_size: u64) -> Hint<'a> {
         Hint {
             key: Cow::from(&*e.key),
-            entry_pos: entry_pos,
+            entry_pos,
+            entry_size,
             value_size: e.value.len() as u32,
             sequence: e.sequence,
             deleted: e.deleted,
+            blob_ref: e.blob_ref(),
+            uncompressed_size: e.uncompressed_size(),
+            encrypted: e.encrypted,
         }
     }
 
-    pub fn from(e: Entry<'a>, entry_pos: u64) -> Hint<'a> {
+    pub fn from(e: Entry<'a>, entry_pos: u64, entry_size: u64) -> Hint<'a> {
         Hint {
+            entry_size,
+            blob_ref: e.blob_ref(),
+            uncompressed_size: e.uncompressed_size(),
+            encrypted: e.encrypted,
             key: e.key,
-            entry_pos: entry_pos,
+            entry_pos,
             value_size: e.value.len() as u32,
             sequence: e.sequence,
             deleted: e.deleted,
         }
     }
 
-    pub fn entry_size(&self) -> u64 {
-        ENTRY_STATIC_SIZE as u64 + self.key.len() as u64 + self.value_size as u64
+    /// See `Entry::value_len`.
+    pub fn value_len(&self) -> u64 {
+        match (self.blob_ref, self.uncompressed_size) {
+            (Some(blob_ref), _) => blob_ref.value_size,
+            (None, Some(size)) => size as u64,
+            (None, None) if self.encrypted => {
+                (self.value_size as usize - ENCRYPTION_OVERHEAD) as u64
+            }
+            (None, None) => self.value_size as u64,
+        }
     }
 
     pub fn write_bytes<W: Write>(&self, writer: &mut W) {
         writer.write_u64::<LittleEndian>(self.sequence).unwrap();
-        writer.write_u16::<LittleEndian>(self.key.len() as u16).unwrap();
+        let mut flags = 0;
+
+        if self.blob_ref.is_some() {
+            flags |= ENTRY_FLAG_BLOB;
+        }
+
+        if self.uncompressed_size.is_some() {
+            flags |= ENTRY_FLAG_COMPRESSED;
+        }
+
+        if self.encrypted {
+            flags |= ENTRY_FLAG_ENCRYPTED;
+        }
+
+        writer.write_u8(flags).unwrap();
+        writer.write_u32::<LittleEndian>(self.key.len() as u32).unwrap();
 
         if self.deleted {
             writer.write_u32::<LittleEndian>(ENTRY_TOMBSTONE).unwrap();
@@ -217,62 +524,289 @@ impl<'a> Hint<'a> {
         }
 
         writer.write_u64::<LittleEndian>(self.entry_pos).unwrap();
-        writer.write(&self.key).unwrap();
+        writer.write_all(&self.key).unwrap();
+
+        if let Some(ref blob_ref) = self.blob_ref {
+            writer.write_all(&blob_ref.to_bytes()).unwrap();
+        }
+
+        if let Some(uncompressed_size) = self.uncompressed_size {
+            writer.write_u32::<LittleEndian>(uncompressed_size).unwrap();
+        }
     }
 
-    pub fn from_read<R: Read>(reader: &mut R) -> Hint<'a> {
-        let sequence = reader.read_u64::<LittleEndian>().unwrap();
-        let key_size = reader.read_u16::<LittleEndian>().unwrap();
-        let value_size = reader.read_u32::<LittleEndian>().unwrap();
-        let entry_pos = reader.read_u64::<LittleEndian>().unwrap();
+    /// Reads a hint stored in a hint file with the given `header`.
+    pub fn from_read<R: Read>(reader: &mut R, header: &FileHeader) -> Result<Hint<'a>> {
+        let version = header.version;
+        let sequence = reader.read_u64::<LittleEndian>()?;
+        let flags = read_flags(reader, version)?;
+        let key_size = read_key_size(reader, version)?;
+        let value_size = reader.read_u32::<LittleEndian>()?;
+        let entry_pos = reader.read_u64::<LittleEndian>()?;
 
         let mut key = vec![0u8; key_size as usize];
-        reader.read_exact(&mut key).unwrap();
+        reader.read_exact(&mut key)?;
 
         let deleted = value_size == ENTRY_TOMBSTONE;
 
-        Hint {
+        let blob_ref = if flags & ENTRY_FLAG_BLOB != 0 {
+            let mut bytes = [0u8; BLOB_REF_SIZE];
+            reader.read_exact(&mut bytes)?;
+            Some(BlobRef::from_bytes(&bytes)?)
+        } else {
+            None
+        };
+
+        let uncompressed_size = if flags & ENTRY_FLAG_COMPRESSED != 0 {
+            Some(reader.read_u32::<LittleEndian>()?)
+        } else {
+            None
+        };
+
+        let value_size = if deleted {
+            0
+        } else {
+            value_size
+        };
+
+        Ok(Hint {
+            entry_size: entry_static_size(version, header.checksum) as u64 + key.len() as u64 +
+                        value_size as u64,
             key: Cow::from(key),
-            entry_pos: entry_pos,
-            value_size: if deleted {
-                0
-            } else {
-                value_size
-            },
-            sequence: sequence,
-            deleted: value_size == ENTRY_TOMBSTONE,
-        }
+            entry_pos,
+            value_size,
+            sequence,
+            deleted,
+            blob_ref,
+            uncompressed_size,
+            encrypted: flags & ENTRY_FLAG_ENCRYPTED != 0,
+        })
     }
 }
 
+/// A reference to a value stored in a blob file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BlobRef {
+    pub file_id: u32,
+    pub value_pos: u64,
+    pub value_size: u64,
+}
+
+impl BlobRef {
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(BLOB_REF_SIZE);
+        bytes.write_u32::<LittleEndian>(self.file_id).unwrap();
+        bytes.write_u64::<LittleEndian>(self.value_pos).unwrap();
+        bytes.write_u64::<LittleEndian>(self.value_size).unwrap();
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<BlobRef> {
+        let mut cursor = Cursor::new(bytes);
+
+        Ok(BlobRef {
+            file_id: cursor.read_u32::<LittleEndian>()?,
+            value_pos: cursor.read_u64::<LittleEndian>()?,
+            value_size: cursor.read_u64::<LittleEndian>()?,
+        })
+    }
+}
+
+/// Reads an entry into `value`, returning the entry without its value.
+fn read_entry<R: Read>(reader: &mut R,
+                       header: &FileHeader,
+                       value: &mut Vec<u8>)
+                       -> Result<Entry<'static>> {
+    let version = header.version;
+    let checksum_size = header.checksum.size();
+
+    let mut buf = vec![0u8; entry_static_size(version, header.checksum)];
+    reader.read_exact(&mut buf)?;
+
+    let mut cursor = Cursor::new(buf);
+    let checksum = header.checksum.read(&mut cursor)?;
+    let sequence = cursor.read_u64::<LittleEndian>()?;
+    let flags = read_flags(&mut cursor, version)?;
+    let key_size = read_key_size(&mut cursor, version)?;
+    let value_size = cursor.read_u32::<LittleEndian>()?;
+
+    let mut key = vec![0u8; key_size as usize];
+    reader.read_exact(&mut key)?;
+
+    let deleted = value_size == ENTRY_TOMBSTONE;
+
+    value.clear();
+    if !deleted {
+        value.resize(value_size as usize, 0);
+        reader.read_exact(value)?;
+    }
+
+    let hash = {
+        let mut hasher = header.entry_hasher();
+        hasher.update(&cursor.get_ref()[checksum_size..]);
+        hasher.update(&key);
+        hasher.update(value);
+        hasher.get()
+    };
+
+    if hash != checksum {
+        return Err(Error::InvalidChecksum {
+            expected: checksum,
+            found: hash,
+        });
+    }
+
+    Ok(Entry {
+        key: Cow::from(key),
+        value: Cow::Borrowed(&[]),
+        sequence,
+        deleted,
+        blob: flags & ENTRY_FLAG_BLOB != 0,
+        compressed: flags & ENTRY_FLAG_COMPRESSED != 0,
+        encrypted: flags & ENTRY_FLAG_ENCRYPTED != 0,
+    })
+}
+
+pub fn entry_static_size(version: u16, checksum: ChecksumType) -> usize {
+    let size = checksum.size() + ENTRY_STATIC_SIZE;
+
+    if version == LEGACY_FORMAT_VERSION {
+        // no flags and an u16 key size
+        size - 3
+    } else {
+        size
+    }
+}
+
+/// Reads the key of an entry stored in a file with the given `header`, without reading its value.
+/// The key isn't verified since the checksum of the entry covers its value.
+pub fn read_key<R: Read>(reader: &mut R, header: &FileHeader) -> Result<Vec<u8>> {
+    let version = header.version;
+
+    header.checksum.read(reader)?;
+    reader.read_u64::<LittleEndian>()?;
+    read_flags(reader, version)?;
+    let key_size = read_key_size(reader, version)?;
+    reader.read_u32::<LittleEndian>()?;
+
+    let mut key = vec![0u8; key_size];
+    reader.read_exact(&mut key)?;
+
+    Ok(key)
+}
+
+fn read_flags<R: Read>(reader: &mut R, version: u16) -> Result<u8> {
+    if version == LEGACY_FORMAT_VERSION {
+        Ok(0)
+    } else {
+        Ok(reader.read_u8()?)
+    }
+}
+
+fn read_key_size<R: Read>(reader: &mut R, version: u16) -> Result<usize> {
+    let key_size = if version == LEGACY_FORMAT_VERSION {
+        reader.read_u16::<LittleEndian>()? as usize
+    } else {
+        reader.read_u32::<LittleEndian>()? as usize
+    };
+
+    Ok(key_size)
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
-    use data::Entry;
+    use byteorder::{LittleEndian, WriteBytesExt};
+
+    use data::{BlobRef, Entry, FileHeader, FileKind, Hint, FORMAT_VERSION,
+               LEGACY_FORMAT_VERSION};
+    use errors::Error;
+    use util::{xxhash32, ChecksumType};
+
+    const CHECKSUM: ChecksumType = ChecksumType::XxHash32;
+
+    fn data_header() -> FileHeader {
+        FileHeader::new(FileKind::Data, CHECKSUM)
+    }
+
+    fn hint_header() -> FileHeader {
+        FileHeader::new(FileKind::Hint, CHECKSUM)
+    }
 
     #[test]
     fn test_serialization() {
+        let header = data_header();
         let sequence = 0;
         let key: &[u8] = &[0, 0, 0];
         let value: &[u8] = &[0, 0, 0];
         let entry = Entry::new(sequence, key, value);
         let deleted_entry = Entry::deleted(sequence, key);
 
-        assert_eq!(entry.to_bytes().len(), 24);
+        assert_eq!(entry.to_bytes(&header).len(), 27);
 
-        assert_eq!(entry, Entry::from_bytes(&entry.to_bytes()));
-        assert_eq!(entry, Entry::from_read(&mut Cursor::new(entry.to_bytes())));
+        assert_eq!(entry, Entry::from_bytes(&entry.to_bytes(&header), &header).unwrap());
+        assert_eq!(entry,
+                   Entry::from_read(&mut Cursor::new(entry.to_bytes(&header)), &header)
+                       .unwrap());
         let mut v = Vec::new();
-        entry.write_bytes(&mut v);
-        assert_eq!(entry, Entry::from_bytes(&v));
+        entry.write_bytes(&mut v, &header);
+        assert_eq!(entry, Entry::from_bytes(&v, &header).unwrap());
 
-        assert_eq!(deleted_entry, Entry::from_bytes(&deleted_entry.to_bytes()));
         assert_eq!(deleted_entry,
-                   Entry::from_read(&mut Cursor::new(deleted_entry.to_bytes())));
+                   Entry::from_bytes(&deleted_entry.to_bytes(&header), &header).unwrap());
+        assert_eq!(deleted_entry,
+                   Entry::from_read(&mut Cursor::new(deleted_entry.to_bytes(&header)),
+                                    &header)
+                       .unwrap());
         v.clear();
-        deleted_entry.write_bytes(&mut v);
-        assert_eq!(deleted_entry, Entry::from_bytes(&v));
+        deleted_entry.write_bytes(&mut v, &header);
+        assert_eq!(deleted_entry, Entry::from_bytes(&v, &header).unwrap());
+
+        // entries are read from the start of a larger slice, e.g. a memory-mapped file
+        entry.write_bytes(&mut v, &header);
+        assert_eq!(deleted_entry, Entry::from_bytes(&v, &header).unwrap());
+        let offset = deleted_entry.size(CHECKSUM) as usize;
+        let read = Entry::from_bytes(&v[offset..], &header).unwrap();
+        assert_eq!(read, entry);
+        assert_eq!(&v[offset + read.value_offset(&header)..], &*entry.value);
+
+        let mut buf = vec![1u8; 64];
+        let read = Entry::from_read_into(&mut Cursor::new(&v[offset..]), &header, &mut buf)
+            .unwrap();
+        assert_eq!(read, entry);
+
+        match Entry::from_bytes(&v[offset..v.len() - 1], &header) {
+            Err(Error::Io(_)) => {}
+            _ => panic!("truncated entry should fail"),
+        }
+    }
+
+    #[test]
+    fn test_checksum_types() {
+        let entry = Entry::new(0, &b"key"[..], &b"value"[..]);
+
+        for &checksum in &[ChecksumType::XxHash32, ChecksumType::Crc32c, ChecksumType::XxHash64] {
+            let header = FileHeader::new(FileKind::Data, checksum);
+
+            let mut v = Vec::new();
+            header.write_bytes(&mut v).unwrap();
+            assert_eq!(FileHeader::from_read(FileKind::Data, &mut Cursor::new(&v)).unwrap(),
+                       header);
+
+            let mut bytes = entry.to_bytes(&header);
+            assert_eq!(bytes.len() as u64, entry.size(checksum));
+            assert_eq!(entry, Entry::from_read(&mut Cursor::new(&bytes), &header).unwrap());
+
+            let last = bytes.len() - 1;
+            bytes[last] ^= 1;
+            match Entry::from_read(&mut Cursor::new(&bytes), &header) {
+                Err(Error::InvalidChecksum { .. }) => {}
+                _ => panic!("corruption should be detected"),
+            }
+        }
+
+        assert_eq!(entry.size(ChecksumType::XxHash64), entry.size(ChecksumType::XxHash32) + 4);
     }
 
     #[test]
@@ -283,4 +817,100 @@ mod tests {
         assert!(Entry::deleted(sequence, key).deleted);
         assert_eq!(Entry::deleted(sequence, key).value.len(), 0);
     }
+
+    #[test]
+    fn test_file_header() {
+        let mut v = Vec::new();
+        data_header().write_bytes(&mut v).unwrap();
+        assert_eq!(v.len() as u64, data_header().size());
+
+        let header = FileHeader::from_read(FileKind::Data, &mut Cursor::new(&v)).unwrap();
+        assert_eq!(header.version, FORMAT_VERSION);
+
+        // a hint header is not a valid data header
+        let header = FileHeader::from_read(FileKind::Hint, &mut Cursor::new(&v)).unwrap();
+        assert_eq!(header.version, LEGACY_FORMAT_VERSION);
+
+        // files without a header are legacy files and the reader is rewound to the first entry
+        let entry = Entry::new(0, &b"key"[..], &b"value"[..]);
+        let mut cursor = Cursor::new(entry.to_bytes(&data_header()));
+        let header = FileHeader::from_read(FileKind::Data, &mut cursor).unwrap();
+        assert_eq!(header.version, LEGACY_FORMAT_VERSION);
+        assert_eq!(header.size(), 0);
+        assert_eq!(entry, Entry::from_read(&mut cursor, &data_header()).unwrap());
+
+        // entries are checksummed along with the salt of their data file
+        let header = FileHeader::salted(FileKind::Data, CHECKSUM);
+        v.clear();
+        header.write_bytes(&mut v).unwrap();
+        assert_eq!(v.len() as u64, header.size());
+        assert_eq!(FileHeader::from_read(FileKind::Data, &mut Cursor::new(&v)).unwrap(), header);
+
+        let bytes = entry.to_bytes(&header);
+        assert_eq!(entry, Entry::from_bytes(&bytes, &header).unwrap());
+        let other = FileHeader { salt: header.salt ^ 1, ..header };
+        assert!(Entry::from_bytes(&bytes, &other).is_err());
+        assert!(Entry::from_read(&mut Cursor::new(&bytes), &other).is_err());
+    }
+
+    #[test]
+    fn test_large_key() {
+        let header = data_header();
+        let key = vec![1u8; 70 * 1024];
+        let entry = Entry::new(0, &*key, &b"value"[..]);
+
+        assert_eq!(entry, Entry::from_bytes(&entry.to_bytes(&header), &header).unwrap());
+        assert_eq!(entry,
+                   Entry::from_read(&mut Cursor::new(entry.to_bytes(&header)), &header)
+                       .unwrap());
+
+        let mut v = Vec::new();
+        Hint::new(&entry, 0, entry.size(CHECKSUM)).write_bytes(&mut v);
+        let hint = Hint::from_read(&mut Cursor::new(v), &hint_header()).unwrap();
+        assert_eq!(hint.key.len(), key.len());
+        assert_eq!(hint.entry_size, entry.size(CHECKSUM));
+    }
+
+    #[test]
+    fn test_legacy_entry() {
+        let mut v = vec![0u8; 4];
+        v.write_u64::<LittleEndian>(42).unwrap();
+        v.write_u16::<LittleEndian>(3).unwrap();
+        v.write_u32::<LittleEndian>(5).unwrap();
+        v.extend_from_slice(b"keyvalue");
+
+        let checksum = xxhash32(&v[4..]);
+        (&mut v[..4]).write_u32::<LittleEndian>(checksum).unwrap();
+
+        let header = FileHeader {
+            kind: FileKind::Data,
+            version: LEGACY_FORMAT_VERSION,
+            checksum: ChecksumType::XxHash32,
+            salt: 0,
+        };
+        let entry = Entry::from_read(&mut Cursor::new(v), &header).unwrap();
+        assert_eq!(entry, Entry::new(42, &b"key"[..], &b"value"[..]));
+    }
+
+    #[test]
+    fn test_blob_entry() {
+        let header = data_header();
+        let blob_ref = BlobRef {
+            file_id: 1,
+            value_pos: 10,
+            value_size: 1 << 40,
+        };
+        let entry = Entry::blob(0, &b"key"[..], &blob_ref);
+
+        let decoded = Entry::from_read(&mut Cursor::new(entry.to_bytes(&header)), &header)
+            .unwrap();
+        assert!(decoded.blob);
+        assert_eq!(decoded.blob_ref(), Some(blob_ref));
+        assert_eq!(Entry::new(0, &b"key"[..], &b"value"[..]).blob_ref(), None);
+
+        let mut v = Vec::new();
+        Hint::new(&entry, 0, entry.size(CHECKSUM)).write_bytes(&mut v);
+        let hint = Hint::from_read(&mut Cursor::new(v), &hint_header()).unwrap();
+        assert_eq!(hint.blob_ref, Some(blob_ref));
+    }
 }
@@ -0,0 +1,341 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::io::Cursor;
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use compression::COMPRESSED_VALUE_HEADER_SIZE;
+use data::Entry;
+use errors::{Error, Result};
+
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+
+// key_id(4) + tag(16)
+pub const ENCRYPTION_OVERHEAD: usize = 4 + TAG_SIZE;
+
+/// An AEAD cipher used to encrypt values before they are written to data files.
+///
+/// `encrypt` must return the ciphertext followed by a `TAG_SIZE` bytes authentication tag and
+/// `decrypt` must return `None` if the ciphertext fails to authenticate.
+pub trait Cipher: fmt::Debug + Send + Sync {
+    fn encrypt(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Vec<u8>;
+
+    fn decrypt(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// AES-256 in Galois/Counter Mode.
+#[cfg(feature = "aes-gcm")]
+pub struct Aes256Gcm(::aes_gcm::Aes256Gcm);
+
+#[cfg(feature = "aes-gcm")]
+impl Aes256Gcm {
+    pub fn new(key: &[u8; 32]) -> Aes256Gcm {
+        use aes_gcm::KeyInit;
+        Aes256Gcm(::aes_gcm::Aes256Gcm::new(key.into()))
+    }
+}
+
+#[cfg(feature = "aes-gcm")]
+impl fmt::Debug for Aes256Gcm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Aes256Gcm")
+    }
+}
+
+#[cfg(feature = "aes-gcm")]
+impl Cipher for Aes256Gcm {
+    fn encrypt(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        use aes_gcm::aead::{Aead, Payload};
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        self.0.encrypt(nonce.into(), payload).unwrap()
+    }
+
+    fn decrypt(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        use aes_gcm::aead::{Aead, Payload};
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        self.0.decrypt(nonce.into(), payload).ok()
+    }
+}
+
+/// ChaCha20-Poly1305 as specified in RFC 8439.
+#[cfg(feature = "chacha20poly1305")]
+pub struct ChaCha20Poly1305(::chacha20poly1305::ChaCha20Poly1305);
+
+#[cfg(feature = "chacha20poly1305")]
+impl ChaCha20Poly1305 {
+    pub fn new(key: &[u8; 32]) -> ChaCha20Poly1305 {
+        use chacha20poly1305::KeyInit;
+        ChaCha20Poly1305(::chacha20poly1305::ChaCha20Poly1305::new(key.into()))
+    }
+}
+
+#[cfg(feature = "chacha20poly1305")]
+impl fmt::Debug for ChaCha20Poly1305 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ChaCha20Poly1305")
+    }
+}
+
+#[cfg(feature = "chacha20poly1305")]
+impl Cipher for ChaCha20Poly1305 {
+    fn encrypt(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        use chacha20poly1305::aead::{Aead, Payload};
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        self.0.encrypt(nonce.into(), payload).unwrap()
+    }
+
+    fn decrypt(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+        use chacha20poly1305::aead::{Aead, Payload};
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        self.0.decrypt(nonce.into(), payload).ok()
+    }
+}
+
+/// The set of keys that can be used to decrypt values, indexed by key id, and the key used to
+/// encrypt new values.
+#[derive(Clone)]
+pub struct Keys {
+    current: Option<(u32, Arc<dyn Cipher>)>,
+    keys: Vec<(u32, Arc<dyn Cipher>)>,
+}
+
+impl Keys {
+    pub fn new() -> Keys {
+        Keys {
+            current: None,
+            keys: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, key_id: u32, cipher: Arc<dyn Cipher>) {
+        if self.current.as_ref().is_some_and(|k| k.0 == key_id) {
+            self.current = Some((key_id, cipher.clone()));
+        }
+
+        self.keys.retain(|k| k.0 != key_id);
+        self.keys.push((key_id, cipher));
+    }
+
+    /// Registers `cipher` under `key_id` and uses it to encrypt new values.
+    pub fn set_current(&mut self, key_id: u32, cipher: Arc<dyn Cipher>) {
+        self.register(key_id, cipher.clone());
+        self.current = Some((key_id, cipher));
+    }
+
+    /// Whether new values are encrypted.
+    pub fn encrypts(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn get(&self, key_id: u32) -> Option<&Arc<dyn Cipher>> {
+        self.keys.iter().find(|k| k.0 == key_id).map(|k| &k.1)
+    }
+
+    /// Encrypts the value of `entry` with the current key, if any, returning the entry that should
+    /// be written at `entry_pos` of data file `file_id`. Tombstones and blob references are
+    /// written as is.
+    ///
+    /// The compression header of compressed values is left in the clear so that the size of the
+    /// value can be recovered without decrypting it. The encrypted value is prefixed with the id of
+    /// the key and the entry key is used as associated data.
+    pub fn encrypt<'a>(&self,
+                       entry: &'a Entry,
+                       file_id: u32,
+                       entry_pos: u64)
+                       -> Cow<'a, Entry<'a>> {
+        let (key_id, cipher) = match self.current {
+            Some((key_id, ref cipher)) if has_value(entry) && !entry.encrypted => (key_id, cipher),
+            _ => return Cow::Borrowed(entry),
+        };
+
+        let header_size = header_size(entry);
+
+        let mut value = Vec::with_capacity(entry.value.len() + ENCRYPTION_OVERHEAD);
+        value.extend_from_slice(&entry.value[..header_size]);
+        value.write_u32::<LittleEndian>(key_id).unwrap();
+        value.extend_from_slice(&cipher.encrypt(&nonce(file_id, entry_pos),
+                                                &entry.key,
+                                                &entry.value[header_size..]));
+
+        Cow::Owned(Entry {
+            key: Cow::Owned(entry.key.to_vec()),
+            value: Cow::Owned(value),
+            encrypted: true,
+            ..*entry
+        })
+    }
+
+    /// Decrypts an entry read from `entry_pos` of data file `file_id`. Once a current key is set
+    /// values must be encrypted, so that an encrypted value can't be replaced by a plaintext one.
+    pub fn decrypt<'a>(&self, entry: Entry<'a>, file_id: u32, entry_pos: u64) -> Result<Entry<'a>> {
+        if !entry.encrypted && self.encrypts() && has_value(&entry) {
+            return Err(Error::UnencryptedValue);
+        }
+
+        self.decrypt_upgrade(entry, file_id, entry_pos)
+    }
+
+    /// Decrypts an entry as `decrypt` but accepts plaintext values, only used to encrypt the
+    /// values of a database written without encryption, see `log::upgrade`.
+    pub fn decrypt_upgrade<'a>(&self,
+                               entry: Entry<'a>,
+                               file_id: u32,
+                               entry_pos: u64)
+                               -> Result<Entry<'a>> {
+        if !entry.encrypted {
+            return Ok(entry);
+        }
+
+        let header_size = header_size(&entry);
+
+        let key_id = Cursor::new(&entry.value[header_size..]).read_u32::<LittleEndian>()?;
+        let cipher = self.get(key_id).ok_or(Error::UnknownEncryptionKey(key_id))?;
+
+        let plaintext = cipher.decrypt(&nonce(file_id, entry_pos),
+                     &entry.key,
+                     &entry.value[header_size + 4..])
+            .ok_or(Error::DecryptionFailed)?;
+
+        let mut value = Vec::with_capacity(header_size + plaintext.len());
+        value.extend_from_slice(&entry.value[..header_size]);
+        value.extend_from_slice(&plaintext);
+
+        Ok(Entry {
+            value: Cow::Owned(value),
+            encrypted: false,
+            ..entry
+        })
+    }
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Keys")
+            .field("current", &self.current.as_ref().map(|k| k.0))
+            .field("keys", &self.keys.iter().map(|k| k.0).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Whether the value of `entry` is encrypted with a current key. Tombstones have no value and blob
+/// references are not encrypted.
+pub fn has_value(entry: &Entry) -> bool {
+    !entry.deleted && !entry.blob
+}
+
+fn header_size(entry: &Entry) -> usize {
+    if entry.compressed {
+        COMPRESSED_VALUE_HEADER_SIZE
+    } else {
+        0
+    }
+}
+
+/// File ids are never reused and entries are never rewritten in place, so a nonce is never used
+/// twice with the same key as long as the key is only used by a single database.
+fn nonce(file_id: u32, entry_pos: u64) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    {
+        let mut cursor = Cursor::new(&mut nonce[..]);
+        cursor.write_u32::<LittleEndian>(file_id).unwrap();
+        cursor.write_u64::<LittleEndian>(entry_pos).unwrap();
+    }
+    nonce
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Arc;
+
+    use data::Entry;
+    use encryption::{Cipher, Keys, TAG_SIZE};
+    use errors::Error;
+
+    // xor "cipher" with a tag derived from the nonce, the key and the associated data
+    #[derive(Debug)]
+    pub struct Xor(pub u8);
+
+    impl Xor {
+        fn tag(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+            let mut tag = vec![self.0; TAG_SIZE];
+            for (i, b) in nonce.iter().chain(aad).chain(plaintext).enumerate() {
+                tag[i % TAG_SIZE] ^= *b;
+            }
+            tag
+        }
+    }
+
+    impl Cipher for Xor {
+        fn encrypt(&self, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+            let mut v: Vec<u8> = plaintext.iter().map(|b| b ^ self.0).collect();
+            v.extend(self.tag(nonce, aad, plaintext));
+            v
+        }
+
+        fn decrypt(&self, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+            let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - TAG_SIZE);
+            let plaintext: Vec<u8> = ciphertext.iter().map(|b| b ^ self.0).collect();
+            if self.tag(nonce, aad, &plaintext) == tag {
+                Some(plaintext)
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_encryption() {
+        let mut keys = Keys::new();
+        keys.set_current(1, Arc::new(Xor(1)));
+
+        let entry = Entry::new(0, &b"key"[..], &b"value"[..]);
+        let encrypted = keys.encrypt(&entry, 0, 10).into_owned();
+
+        assert!(encrypted.encrypted);
+        assert!(&*encrypted.value != b"value");
+        assert_eq!(encrypted.value_len(), 5);
+        assert_eq!(keys.decrypt(encrypted.clone(), 0, 10).unwrap(), entry);
+
+        // the entry is bound to its position
+        match keys.decrypt(encrypted.clone(), 0, 11) {
+            Err(Error::DecryptionFailed) => {}
+            _ => panic!("decryption should fail"),
+        }
+
+        // old keys can still be used to decrypt after rotating
+        keys.set_current(2, Arc::new(Xor(2)));
+        assert_eq!(keys.decrypt(encrypted.clone(), 0, 10).unwrap(), entry);
+
+        match Keys::new().decrypt(encrypted, 0, 10) {
+            Err(Error::UnknownEncryptionKey(1)) => {}
+            _ => panic!("decryption should fail"),
+        }
+
+        // tombstones are never encrypted
+        let deleted = Entry::deleted(0, &b"key"[..]);
+        assert!(!keys.encrypt(&deleted, 0, 10).encrypted);
+        assert_eq!(keys.decrypt(deleted.clone(), 0, 10).unwrap(), deleted);
+
+        // plaintext values are only accepted without a current key or when upgrading
+        match keys.decrypt(entry.clone(), 0, 10) {
+            Err(Error::UnencryptedValue) => {}
+            _ => panic!("plaintext values should be rejected"),
+        }
+        assert_eq!(keys.decrypt_upgrade(entry.clone(), 0, 10).unwrap(), entry);
+        assert_eq!(Keys::new().decrypt(entry.clone(), 0, 10).unwrap(), entry);
+    }
+}
@@ -0,0 +1,69 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    InvalidChecksum { expected: u64, found: u64 },
+    InvalidFileHeader,
+    UnsupportedVersion(u16),
+    UpgradeVerificationFailed(u32),
+    KeyTooLarge(usize),
+    ValueTooLarge(usize),
+    UnknownCodec(u8),
+    UnknownChecksum(u8),
+    UnknownEncryptionKey(u32),
+    DecryptionFailed,
+    UnencryptedValue,
+    UnencryptedBlob,
+    ReadOnly,
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "IO error: {}", err),
+            Error::InvalidChecksum { expected, found } => {
+                write!(f,
+                       "Invalid checksum (expected: {}, found: {})",
+                       expected,
+                       found)
+            }
+            Error::InvalidFileHeader => write!(f, "Invalid file header"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "Unsupported file format version: {}", version)
+            }
+            Error::UpgradeVerificationFailed(file_id) => {
+                write!(f, "Verification of upgraded data file {} failed", file_id)
+            }
+            Error::KeyTooLarge(size) => write!(f, "Key too large: {} bytes", size),
+            Error::ValueTooLarge(size) => write!(f, "Value too large: {} bytes", size),
+            Error::UnknownCodec(id) => write!(f, "Unknown compression codec: {}", id),
+            Error::UnknownChecksum(id) => write!(f, "Unknown checksum type: {}", id),
+            Error::UnknownEncryptionKey(id) => write!(f, "Unknown encryption key: {}", id),
+            Error::DecryptionFailed => write!(f, "Decryption failed"),
+            Error::UnencryptedValue => write!(f, "Value is not encrypted"),
+            Error::UnencryptedBlob => write!(f, "Values stored in blob files can't be encrypted"),
+            Error::ReadOnly => write!(f, "Database is opened read-only"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
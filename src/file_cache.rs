@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+use memmap2::Mmap;
+
+use data::{read_key, Entry, FileHeader, FileKind};
+use errors::Result;
+use log::get_data_file_path;
+use util::PositionalReader;
+
+pub const DEFAULT_MAX_OPEN_FILES: usize = 64;
+
+/// A data file opened for reading. Entries are read with positional reads or from a memory mapping
+/// of the file, so a single handle can be shared by concurrent readers.
+pub struct DataFile {
+    file: File,
+    mmap: Option<Mmap>,
+    pub header: FileHeader,
+}
+
+impl DataFile {
+    fn open(path: &Path, mmap: bool) -> Result<DataFile> {
+        let mut file = File::open(path)?;
+        let header = FileHeader::from_read(FileKind::Data, &mut file)?;
+
+        // only immutable data files are mapped, they are never modified in place and are only
+        // removed once compacted, which leaves existing mappings valid.
+        let mmap = if mmap {
+            Some(unsafe { Mmap::map(&file)? })
+        } else {
+            None
+        };
+
+        Ok(DataFile {
+            file,
+            mmap,
+            header,
+        })
+    }
+
+    pub fn read_entry<'a>(&self, entry_pos: u64) -> Result<Entry<'a>> {
+        match self.mmap {
+            Some(ref mmap) => {
+                let entry = Entry::from_bytes(mapped_from(mmap, entry_pos)?, &self.header)?;
+                Ok(entry.into_owned())
+            }
+            None => {
+                Entry::from_read(&mut PositionalReader::new(&self.file, entry_pos), &self.header)
+            }
+        }
+    }
+
+    /// Reads the key of the entry at `entry_pos`, see `data::read_key`.
+    pub fn read_key(&self, entry_pos: u64) -> Result<Vec<u8>> {
+        match self.mmap {
+            Some(ref mmap) => read_key(&mut mapped_from(mmap, entry_pos)?, &self.header),
+            None => read_key(&mut PositionalReader::new(&self.file, entry_pos), &self.header),
+        }
+    }
+
+    /// Reads the entry at `entry_pos` as `read_entry`, reading its value into `buf` unless the file
+    /// is mapped.
+    pub fn read_entry_into<'a>(&self,
+                               entry_pos: u64,
+                               buf: &'a mut Vec<u8>)
+                               -> Result<Entry<'a>> {
+        match self.mmap {
+            Some(_) => self.read_entry(entry_pos),
+            None => {
+                Entry::from_read_into(&mut PositionalReader::new(&self.file, entry_pos),
+                                      &self.header,
+                                      buf)
+            }
+        }
+    }
+
+    /// Returns the value of the entry at `entry_pos` borrowed from the memory mapping of the file,
+    /// if the file is mapped, the entry is one of `key` and the value is stored as is, i.e. it
+    /// isn't a blob reference and it isn't compressed or encrypted.
+    pub fn read_value_ref(data_file: &Arc<DataFile>,
+                          key: &[u8],
+                          entry_pos: u64)
+                          -> Result<Option<ValueRef>> {
+        let mmap = match data_file.mmap {
+            Some(ref mmap) => mmap,
+            None => return Ok(None),
+        };
+
+        let entry = Entry::from_bytes(mapped_from(mmap, entry_pos)?, &data_file.header)?;
+
+        if entry.deleted || entry.blob || entry.compressed || entry.encrypted ||
+           &*entry.key != key {
+            return Ok(None);
+        }
+
+        let value_pos = entry_pos as usize + entry.value_offset(&data_file.header);
+
+        Ok(Some(ValueRef::Mapped {
+            data_file: data_file.clone(),
+            value_pos,
+            value_size: entry.value.len(),
+        }))
+    }
+}
+
+/// Returns the mapped bytes starting at `entry_pos`, which comes from the index or a hint file and
+/// might be past the end of the file if they are corrupt.
+fn mapped_from(mmap: &Mmap, entry_pos: u64) -> Result<&[u8]> {
+    if entry_pos > mmap.len() as u64 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                  "Entry position past the end of the data file")
+            .into());
+    }
+
+    Ok(&mmap[entry_pos as usize..])
+}
+
+/// A value returned by `Cask::get_ref`. Values of memory-mapped data files are borrowed from the
+/// mapping, which is kept alive for as long as the guard, even if the file is compacted meanwhile.
+pub enum ValueRef {
+    Mapped {
+        data_file: Arc<DataFile>,
+        value_pos: usize,
+        value_size: usize,
+    },
+    Owned(Vec<u8>),
+    /// A value shared with the value cache, see `CaskOptions::value_cache_size`.
+    Cached(Arc<[u8]>),
+}
+
+impl Deref for ValueRef {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            ValueRef::Mapped { ref data_file, value_pos, value_size } => {
+                &data_file.mmap.as_ref().unwrap()[value_pos..value_pos + value_size]
+            }
+            ValueRef::Owned(ref value) => value,
+            ValueRef::Cached(ref value) => value,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueRef {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Vec<u8>> for ValueRef {
+    fn from(value: Vec<u8>) -> ValueRef {
+        ValueRef::Owned(value)
+    }
+}
+
+impl fmt::Debug for ValueRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+struct FileCacheInner {
+    files: HashMap<u32, (Arc<DataFile>, u64)>,
+    /// The handles opened for each file, including those evicted but still held by readers.
+    handles: HashMap<u32, Vec<Weak<DataFile>>>,
+    tick: u64,
+}
+
+/// A bounded cache of data files opened for reading, the least recently used file is closed when
+/// the cache is full.
+pub struct FileCache {
+    path: PathBuf,
+    capacity: usize,
+    inner: Mutex<FileCacheInner>,
+}
+
+impl FileCache {
+    pub fn new(path: &Path, capacity: usize) -> FileCache {
+        FileCache {
+            path: path.to_path_buf(),
+            capacity,
+            inner: Mutex::new(FileCacheInner {
+                files: HashMap::new(),
+                handles: HashMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// Returns the data file `file_id`, which is memory mapped if `mmap` is set. Only immutable
+    /// files should be mapped.
+    pub fn get(&self, file_id: u32, mmap: bool) -> Result<Arc<DataFile>> {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if let Some(&mut (ref data_file, ref mut last_used)) = inner.files.get_mut(&file_id) {
+            *last_used = tick;
+            return Ok(data_file.clone());
+        }
+
+        if inner.files.len() >= self.capacity {
+            let lru = inner.files
+                .iter()
+                .min_by_key(|&(_, &(_, last_used))| last_used)
+                .map(|(&file_id, _)| file_id);
+
+            if let Some(lru) = lru {
+                inner.files.remove(&lru);
+            }
+        }
+
+        let data_file = Arc::new(DataFile::open(&get_data_file_path(&self.path, file_id), mmap)?);
+
+        {
+            let handles = inner.handles.entry(file_id).or_default();
+            handles.retain(|handle| handle.strong_count() > 0);
+            handles.push(Arc::downgrade(&data_file));
+        }
+
+        if self.capacity > 0 {
+            inner.files.insert(file_id, (data_file.clone(), tick));
+        }
+
+        Ok(data_file)
+    }
+
+    /// Closes the cached handle of `file_id`, must be called before the file is removed or once it
+    /// becomes immutable so that it can be mapped. Readers still holding the handle can keep using
+    /// it, returns whether any of them still does.
+    pub fn remove(&self, file_id: u32) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.files.remove(&file_id);
+        inner.handles
+            .remove(&file_id)
+            .is_some_and(|handles| handles.iter().any(|handle| handle.strong_count() > 0))
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.inner.lock().unwrap().files.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use data::Entry;
+    use encryption::Keys;
+    use file_cache::{DataFile, FileCache};
+    use log::LogWriter;
+    use util::{ChecksumType, TestDir};
+
+    #[test]
+    fn test_file_cache() {
+        let test_dir = TestDir::new("file-cache");
+        let path = test_dir.path();
+
+        let mut positions = Vec::new();
+        for file_id in 0..3 {
+            let mut log_writer =
+                LogWriter::new(path, file_id, false, &Keys::new(), ChecksumType::XxHash32, 0);
+            let entry = Entry::new(file_id as u64, &b"key"[..], &[0u8; 100][..]);
+            let (entry_pos, _) = log_writer.write(&entry);
+            positions.push(entry_pos);
+        }
+
+        let cache = FileCache::new(path, 2);
+
+        for file_id in 0..3 {
+            let data_file = cache.get(file_id, false).unwrap();
+            let entry = data_file.read_entry(positions[file_id as usize]).unwrap();
+            assert_eq!(entry.sequence, file_id as u64);
+        }
+        assert_eq!(cache.len(), 2);
+
+        let data_file = cache.get(2, false).unwrap();
+        assert!(Arc::ptr_eq(&data_file, &cache.get(2, false).unwrap()));
+
+        assert!(cache.remove(2));
+        assert_eq!(cache.len(), 1);
+        assert!(!Arc::ptr_eq(&data_file, &cache.get(2, false).unwrap()));
+
+        drop(data_file);
+        assert!(!cache.remove(2));
+    }
+
+    #[test]
+    fn test_mmap() {
+        let test_dir = TestDir::new("file-cache-mmap");
+        let path = test_dir.path();
+
+        let entries = vec![Entry::new(0, &b"key"[..], &b"value"[..]),
+                           Entry::compressed(1, &b"key"[..], &b"value"[..]),
+                           Entry::deleted(2, &b"key"[..])];
+        let mut positions = Vec::new();
+        {
+            let mut log_writer =
+                LogWriter::new(path, 0, false, &Keys::new(), ChecksumType::XxHash32, 0);
+            for entry in &entries {
+                positions.push(log_writer.write(entry).0);
+            }
+        }
+
+        let cache = FileCache::new(path, 1);
+        let data_file = cache.get(0, true).unwrap();
+
+        for (entry, &entry_pos) in entries.iter().zip(&positions) {
+            assert_eq!(&data_file.read_entry(entry_pos).unwrap(), entry);
+        }
+
+        let value = DataFile::read_value_ref(&data_file, b"key", positions[0]).unwrap().unwrap();
+        assert_eq!(&*value, b"value");
+
+        // positions past the end of the file are errors
+        let past_end = 1 << 20;
+        assert!(data_file.read_entry(past_end).is_err());
+        assert!(data_file.read_key(past_end).is_err());
+        assert!(DataFile::read_value_ref(&data_file, b"key", past_end).is_err());
+
+        // compressed values and tombstones can't be borrowed
+        assert!(DataFile::read_value_ref(&data_file, b"key", positions[1]).unwrap().is_none());
+        assert!(DataFile::read_value_ref(&data_file, b"key", positions[2]).unwrap().is_none());
+
+        // entries of other keys aren't borrowed
+        assert!(DataFile::read_value_ref(&data_file, b"other", positions[0]).unwrap().is_none());
+
+        // the mapping outlives the removal of the file
+        drop(data_file);
+        cache.remove(0);
+        drop(test_dir);
+        assert_eq!(&*value, b"value");
+    }
+}
@@ -0,0 +1,675 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::iter;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use byteorder::{ByteOrder, LittleEndian};
+use memmap2::Mmap;
+use regex::Regex;
+
+use cask::IndexEntry;
+use data::BLOB_REF_SIZE;
+use errors::Result;
+
+/// Slots of the table hold the offset of a record plus one, so that 0 marks an empty slot, and a
+/// tag from the high bits of the hash of its key.
+const EMPTY_SLOT: u64 = 0;
+const TAG_SHIFT: u32 = 48;
+const OFFSET_MASK: u64 = (1 << TAG_SHIFT) - 1;
+const MIN_SLOTS: usize = 16;
+
+// file_id(4) + entry_pos(4) + entry_size(4) + value_size(4) + sequence(8)
+const PACKED_ENTRY_SIZE: usize = 24;
+/// Marks packed entries whose index entry is stored in `CompactMap.overflow`.
+const OVERFLOW: u32 = !0;
+/// The arena is only rewritten once it is larger than this and mostly made of removed records.
+const MIN_GARBAGE: usize = 64 * 1024;
+
+// file_id(4) + entry_pos(8) + entry_size(8) + sequence(8) + value_size(8)
+const SPILLED_ENTRY_SIZE: usize = 36;
+const SPILL_FILE_EXTENSION: &str = "cask.spill";
+/// Every this many records of a spilled run, the key of a record is kept in memory.
+const SPARSE_INTERVAL: usize = 64;
+
+/// Numbers the spill files written by this process, see `new_spill_file_path`.
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+/// How the index stores keys and their entries in memory.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum IndexMode {
+    /// Every key is stored in its own allocation along with its index entry.
+    #[default]
+    Full,
+    /// Keys are packed in an arena along with a packed index entry of 24 bytes.
+    Compact,
+    /// Only a 64-bit fingerprint of every key is kept in memory along with a packed index entry,
+    /// keys are read from the data files to verify lookups and writes.
+    HashOnly,
+}
+
+/// A hash table of index entries whose keys are packed in an arena. Every record of the arena
+/// holds the length of its key, the key and a packed entry, and the table only holds the offsets of
+/// the records. Entries of blobs and entries too large for a packed entry are stored separately.
+///
+/// The table doesn't check for duplicate keys, callers look records up with `find` and decide
+/// which one matches, so that hash-only indexes can store fingerprints which aren't unique.
+pub struct CompactMap {
+    slots: Vec<u64>,
+    arena: Vec<u8>,
+    overflow: HashMap<u64, IndexEntry>,
+    hash: fn(&[u8]) -> u64,
+    len: usize,
+    /// The size of the records of removed entries still in the arena.
+    garbage: usize,
+}
+
+impl CompactMap {
+    /// Creates a map whose keys are hashed with `hash`.
+    pub fn new(hash: fn(&[u8]) -> u64) -> CompactMap {
+        CompactMap {
+            slots: vec![EMPTY_SLOT; MIN_SLOTS],
+            arena: Vec::new(),
+            overflow: HashMap::new(),
+            hash,
+            len: 0,
+            garbage: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The memory used by the map in bytes.
+    pub fn memory_size(&self) -> usize {
+        self.slots.capacity() * mem::size_of::<u64>() + self.arena.capacity() +
+        self.overflow.capacity() * (mem::size_of::<(u64, IndexEntry)>() + 1)
+    }
+
+    /// Returns the records holding `key`.
+    pub fn find<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = u64> + 'a {
+        let hash = (self.hash)(key);
+        let mut idx = self.home(hash);
+
+        iter::from_fn(move || {
+            while self.slots[idx] != EMPTY_SLOT {
+                let slot = self.slots[idx];
+                idx = (idx + 1) & (self.slots.len() - 1);
+
+                if slot >> TAG_SHIFT == hash >> TAG_SHIFT {
+                    let record = (slot & OFFSET_MASK) - 1;
+                    if self.key(record) == key {
+                        return Some(record);
+                    }
+                }
+            }
+
+            None
+        })
+    }
+
+    /// Returns the key of `record`.
+    pub fn key(&self, record: u64) -> &[u8] {
+        let (key_pos, key_len) = self.key_range(record);
+        &self.arena[key_pos..key_pos + key_len]
+    }
+
+    /// Returns the index entry of `record`.
+    pub fn get(&self, record: u64) -> IndexEntry {
+        let entry_pos = self.entry_pos(record);
+        let bytes = &self.arena[entry_pos..entry_pos + PACKED_ENTRY_SIZE];
+
+        if LittleEndian::read_u32(&bytes[8..]) == OVERFLOW {
+            return self.overflow[&record].clone();
+        }
+
+        IndexEntry {
+            file_id: LittleEndian::read_u32(&bytes[0..]),
+            entry_pos: LittleEndian::read_u32(&bytes[4..]) as u64,
+            entry_size: LittleEndian::read_u32(&bytes[8..]) as u64,
+            value_size: LittleEndian::read_u32(&bytes[12..]) as u64,
+            sequence: LittleEndian::read_u64(&bytes[16..]),
+            blob_ref: None,
+        }
+    }
+
+    /// Replaces the index entry of `record`.
+    pub fn set(&mut self, record: u64, index_entry: &IndexEntry) {
+        let entry_pos = self.entry_pos(record);
+        let overflow = {
+            let bytes = &mut self.arena[entry_pos..entry_pos + PACKED_ENTRY_SIZE];
+            pack(index_entry, bytes)
+        };
+
+        if overflow {
+            self.overflow.insert(record, index_entry.clone());
+        } else {
+            self.overflow.remove(&record);
+        }
+    }
+
+    /// Adds a record for `key`, even if the map already holds records for it.
+    pub fn insert(&mut self, key: &[u8], index_entry: &IndexEntry) {
+        if (self.len + 1) * 8 > self.slots.len() * 7 {
+            let slots = self.slots.len() * 2;
+            self.resize(slots);
+        }
+
+        let record = self.arena.len() as u64;
+        write_varint(&mut self.arena, key.len() as u64);
+        self.arena.extend_from_slice(key);
+        self.arena.extend_from_slice(&[0u8; PACKED_ENTRY_SIZE]);
+        self.set(record, index_entry);
+
+        let hash = (self.hash)(key);
+        self.insert_slot(record, hash);
+        self.len += 1;
+    }
+
+    /// Removes `record`, returning its index entry.
+    pub fn remove(&mut self, record: u64) -> IndexEntry {
+        let index_entry = self.get(record);
+        let mask = self.slots.len() - 1;
+
+        let mut idx = self.home((self.hash)(self.key(record)));
+        while (self.slots[idx] & OFFSET_MASK) - 1 != record {
+            idx = (idx + 1) & mask;
+        }
+
+        // the following records of the cluster are shifted back so that lookups never stop at the
+        // removed slot before reaching them
+        let mut next = (idx + 1) & mask;
+        while self.slots[next] != EMPTY_SLOT {
+            let home = self.home((self.hash)(self.key((self.slots[next] & OFFSET_MASK) - 1)));
+            if (next.wrapping_sub(home) & mask) >= (next.wrapping_sub(idx) & mask) {
+                self.slots[idx] = self.slots[next];
+                idx = next;
+            }
+            next = (next + 1) & mask;
+        }
+
+        self.slots[idx] = EMPTY_SLOT;
+        self.overflow.remove(&record);
+        self.len -= 1;
+        self.garbage += self.entry_pos(record) + PACKED_ENTRY_SIZE - record as usize;
+
+        if self.garbage > MIN_GARBAGE && self.garbage > self.arena.len() / 2 {
+            self.compact();
+        }
+
+        index_entry
+    }
+
+    /// Returns the keys and index entries of all records.
+    pub fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a [u8], IndexEntry)> + 'a> {
+        Box::new(self.slots
+            .iter()
+            .filter(|&&slot| slot != EMPTY_SLOT)
+            .map(move |&slot| {
+                let record = (slot & OFFSET_MASK) - 1;
+                (self.key(record), self.get(record))
+            }))
+    }
+
+    fn home(&self, hash: u64) -> usize {
+        hash as usize & (self.slots.len() - 1)
+    }
+
+    fn insert_slot(&mut self, record: u64, hash: u64) {
+        let mut idx = self.home(hash);
+        while self.slots[idx] != EMPTY_SLOT {
+            idx = (idx + 1) & (self.slots.len() - 1);
+        }
+
+        self.slots[idx] = (hash >> TAG_SHIFT << TAG_SHIFT) | (record + 1);
+    }
+
+    fn resize(&mut self, slots: usize) {
+        let old_slots = mem::replace(&mut self.slots, vec![EMPTY_SLOT; slots]);
+
+        for slot in old_slots.into_iter().filter(|&slot| slot != EMPTY_SLOT) {
+            let record = (slot & OFFSET_MASK) - 1;
+            let hash = (self.hash)(self.key(record));
+            self.insert_slot(record, hash);
+        }
+    }
+
+    /// Rewrites the arena without the records of removed entries.
+    fn compact(&mut self) {
+        let mut arena = Vec::with_capacity(self.arena.len() - self.garbage);
+        let mut overflow = HashMap::with_capacity(self.overflow.len());
+
+        for idx in 0..self.slots.len() {
+            let slot = self.slots[idx];
+            if slot == EMPTY_SLOT {
+                continue;
+            }
+
+            let record = (slot & OFFSET_MASK) - 1;
+            let end = self.entry_pos(record) + PACKED_ENTRY_SIZE;
+            let new_record = arena.len() as u64;
+
+            arena.extend_from_slice(&self.arena[record as usize..end]);
+
+            if let Some(index_entry) = self.overflow.remove(&record) {
+                overflow.insert(new_record, index_entry);
+            }
+
+            self.slots[idx] = (slot & !OFFSET_MASK) | (new_record + 1);
+        }
+
+        self.arena = arena;
+        self.overflow = overflow;
+        self.garbage = 0;
+    }
+
+    fn key_range(&self, record: u64) -> (usize, usize) {
+        let (key_len, len_size) = read_varint(&self.arena[record as usize..]);
+        (record as usize + len_size, key_len as usize)
+    }
+
+    fn entry_pos(&self, record: u64) -> usize {
+        let (key_pos, key_len) = self.key_range(record);
+        key_pos + key_len
+    }
+}
+
+/// The sparse keys of a spilled run and the positions of their records.
+type SparseKeys = Vec<(Vec<u8>, u64)>;
+
+/// A run of index entries spilled to disk, sorted by key. Only the key of every
+/// `SPARSE_INTERVAL`th record is kept in memory, lookups scan the records that follow the last
+/// sparse key before theirs. The file is removed once the run is dropped.
+pub struct SpillRun {
+    path: PathBuf,
+    mmap: Mmap,
+    sparse: SparseKeys,
+    len: usize,
+}
+
+impl SpillRun {
+    /// Writes `entries`, which must be sorted by key, to a run at `path`. Returns `None` if there
+    /// are no entries, and the first error of `entries` if any.
+    pub fn create<I>(path: &Path, entries: I) -> Result<Option<SpillRun>>
+        where I: Iterator<Item = Result<(Vec<u8>, IndexEntry)>>
+    {
+        let result = SpillRun::write(path, entries);
+        if result.is_err() {
+            let _ = fs::remove_file(path);
+        }
+
+        let (sparse, len) = result?;
+        if len == 0 {
+            fs::remove_file(path)?;
+            return Ok(None);
+        }
+
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        Ok(Some(SpillRun {
+            path: path.to_path_buf(),
+            mmap,
+            sparse,
+            len,
+        }))
+    }
+
+    fn write<I>(path: &Path, entries: I) -> Result<(SparseKeys, usize)>
+        where I: Iterator<Item = Result<(Vec<u8>, IndexEntry)>>
+    {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let mut sparse = Vec::new();
+        let mut record = Vec::new();
+        let mut pos = 0;
+        let mut len = 0;
+
+        for entry in entries {
+            let (key, index_entry) = entry?;
+            record.clear();
+            index_entry.write_bytes(&key, &mut record)?;
+            writer.write_all(&record)?;
+
+            if len % SPARSE_INTERVAL == 0 {
+                sparse.push((key, pos));
+            }
+
+            pos += record.len() as u64;
+            len += 1;
+        }
+
+        writer.flush()?;
+
+        Ok((sparse, len))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The memory used by the sparse keys of the run in bytes.
+    pub fn memory_size(&self) -> usize {
+        self.sparse.capacity() * mem::size_of::<(Vec<u8>, u64)>() +
+        self.sparse.iter().map(|(key, _)| key.len()).sum::<usize>()
+    }
+
+    /// Returns the records holding `key`.
+    pub fn find<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = Result<u64>> + 'a {
+        // the first records holding `key` may follow the last sparse key before it
+        let idx = self.sparse.partition_point(|(sparse_key, _)| &sparse_key[..] < key);
+        let mut pos = self.sparse[idx.saturating_sub(1)].1;
+        let end = self.mmap.len() as u64;
+
+        iter::from_fn(move || {
+            while pos < end {
+                let record = pos;
+                let (record_key, record_size) = match self.key(record) {
+                    Ok(key) => key,
+                    Err(err) => {
+                        pos = end;
+                        return Some(Err(err));
+                    }
+                };
+
+                if record_key > key {
+                    pos = end;
+                    return None;
+                }
+
+                pos += record_size;
+                if record_key == key {
+                    return Some(Ok(record));
+                }
+            }
+
+            None
+        })
+    }
+
+    pub fn get(&self, record: u64) -> Result<IndexEntry> {
+        let mut bytes = &self.mmap[record as usize..];
+        Ok(IndexEntry::from_read(&mut bytes)?.1)
+    }
+
+    /// Returns the key of `record` without copying it, along with the size of the record.
+    fn key(&self, record: u64) -> Result<(&[u8], u64)> {
+        let bytes = &self.mmap[record as usize..];
+        let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "truncated spill record");
+
+        if bytes.len() < 4 {
+            return Err(truncated().into());
+        }
+
+        let key_end = 4 + LittleEndian::read_u32(bytes) as usize;
+        // the key is followed by the entry and whether a blob reference follows
+        let blob_flag = key_end + SPILLED_ENTRY_SIZE;
+        if bytes.len() <= blob_flag {
+            return Err(truncated().into());
+        }
+
+        let record_size = blob_flag + 1 + if bytes[blob_flag] != 0 { BLOB_REF_SIZE } else { 0 };
+        if bytes.len() < record_size {
+            return Err(truncated().into());
+        }
+
+        Ok((&bytes[4..key_end], record_size as u64))
+    }
+
+    /// Returns all records along with their keys and entries, in key order. Stops after the first
+    /// record which can't be read.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = Result<(u64, Vec<u8>, IndexEntry)>> + 'a {
+        let mut pos = 0;
+        let end = self.mmap.len() as u64;
+
+        iter::from_fn(move || {
+            if pos >= end {
+                return None;
+            }
+
+            let record = pos;
+            match self.read(record) {
+                Ok((key, index_entry, record_size)) => {
+                    pos += record_size;
+                    Some(Ok((record, key, index_entry)))
+                }
+                Err(err) => {
+                    pos = end;
+                    Some(Err(err))
+                }
+            }
+        })
+    }
+
+    /// Reads the key and entry of `record`, and the size of the record.
+    fn read(&self, record: u64) -> Result<(Vec<u8>, IndexEntry, u64)> {
+        let mut bytes = &self.mmap[record as usize..];
+        let available = bytes.len();
+        let (key, index_entry) = IndexEntry::from_read(&mut bytes)?;
+
+        Ok((key, index_entry, (available - bytes.len()) as u64))
+    }
+}
+
+impl Drop for SpillRun {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            // removed by a writer opening the database, see `remove_spill_files`
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!("Failed to remove spill file {:?}: {}", self.path, err),
+            Ok(()) => {}
+        }
+    }
+}
+
+/// Returns the path of a new spill file in the database directory at `path`. Spill files are named
+/// after the process which wrote them, so that processes opening the same database never write to
+/// each other's spill files.
+pub fn new_spill_file_path(path: &Path) -> PathBuf {
+    let spill = SPILLS.fetch_add(1, Ordering::SeqCst);
+    path.join(format!("{:010}-{:010}", process::id(), spill)).with_extension(SPILL_FILE_EXTENSION)
+}
+
+/// Removes the spill files left behind by crashed processes. Files which can't be removed are
+/// logged and skipped.
+pub fn remove_spill_files(path: &Path) -> Result<()> {
+    let re = Regex::new(&format!("^\\d+-\\d+\\.{}$", SPILL_FILE_EXTENSION)).unwrap();
+
+    for dir_entry in fs::read_dir(path)? {
+        let file_path = dir_entry?.path();
+        let is_spill_file =
+            file_path.file_name().and_then(|name| name.to_str()).is_some_and(|name| {
+                re.is_match(name)
+            });
+
+        if is_spill_file {
+            if let Err(err) = fs::remove_file(&file_path) {
+                warn!("Failed to remove spill file {:?}: {}", file_path, err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Packs `index_entry` into `bytes`, returning whether it doesn't fit and must be stored
+/// separately.
+fn pack(index_entry: &IndexEntry, bytes: &mut [u8]) -> bool {
+    let max = OVERFLOW as u64;
+    let overflow = index_entry.blob_ref.is_some() || index_entry.entry_pos > max ||
+                   index_entry.entry_size >= max || index_entry.value_size > max;
+
+    LittleEndian::write_u32(&mut bytes[0..], index_entry.file_id);
+    LittleEndian::write_u32(&mut bytes[4..], index_entry.entry_pos as u32);
+    LittleEndian::write_u32(&mut bytes[8..],
+                            if overflow {
+                                OVERFLOW
+                            } else {
+                                index_entry.entry_size as u32
+                            });
+    LittleEndian::write_u32(&mut bytes[12..], index_entry.value_size as u32);
+    LittleEndian::write_u64(&mut bytes[16..], index_entry.sequence);
+
+    overflow
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Reads a varint from the start of `bytes`, returning its value and its size.
+fn read_varint(bytes: &[u8]) -> (u64, usize) {
+    let mut value = 0;
+    let mut size = 0;
+
+    loop {
+        let byte = bytes[size];
+        value |= ((byte & 0x7f) as u64) << (7 * size);
+        size += 1;
+
+        if byte < 0x80 {
+            return (value, size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io;
+    use std::iter;
+
+    use cask::IndexEntry;
+    use data::BlobRef;
+    use index::{new_spill_file_path, remove_spill_files, CompactMap, SpillRun};
+    use util::{xxhash64, TestDir};
+
+    fn index_entry(i: u64) -> IndexEntry {
+        IndexEntry {
+            file_id: i as u32 % 7,
+            entry_pos: i * 100,
+            entry_size: 100,
+            sequence: i,
+            blob_ref: None,
+            value_size: i % 50,
+        }
+    }
+
+    #[test]
+    fn test_compact_map() {
+        let mut map = CompactMap::new(xxhash64);
+        let mut expected = HashMap::new();
+
+        for i in 0..10000u64 {
+            let key = format!("key{}", i).into_bytes();
+            let mut entry = index_entry(i);
+
+            if i % 10 == 0 {
+                entry.blob_ref = Some(BlobRef {
+                    file_id: 1,
+                    value_pos: i,
+                    value_size: 1 << 40,
+                });
+                entry.value_size = 1 << 40;
+            }
+
+            map.insert(&key, &entry);
+            expected.insert(key, entry);
+        }
+
+        // remove most keys so that the arena is compacted
+        for i in 0..10000u64 {
+            let key = format!("key{}", i).into_bytes();
+
+            if i % 4 != 0 {
+                let records: Vec<u64> = map.find(&key).collect();
+                assert_eq!(records.len(), 1);
+                assert_eq!(map.remove(records[0]), expected.remove(&key).unwrap());
+            } else if i % 8 == 0 {
+                let entry = index_entry(i + 1);
+                let record = map.find(&key).next().unwrap();
+                map.set(record, &entry);
+                expected.insert(key, entry);
+            }
+        }
+
+        assert_eq!(map.len(), expected.len());
+        assert_eq!(map.iter().count(), expected.len());
+
+        for (key, entry) in &expected {
+            let records: Vec<u64> = map.find(key).collect();
+            assert_eq!(records.len(), 1);
+            assert_eq!(map.key(records[0]), &key[..]);
+            assert_eq!(&map.get(records[0]), entry);
+        }
+
+        assert!(map.find(b"key1").next().is_none());
+    }
+
+    #[test]
+    fn test_spill_run() {
+        let test_dir = TestDir::new("spill-run");
+        let path = test_dir.path();
+
+        let spill_file_path = new_spill_file_path(path);
+        assert!(SpillRun::create(&spill_file_path, Vec::new().into_iter()).unwrap().is_none());
+        assert!(!spill_file_path.exists());
+        assert!(new_spill_file_path(path) != spill_file_path);
+
+        let mut entries: Vec<(Vec<u8>, IndexEntry)> = (0..1000u64)
+            .map(|i| (format!("key{}", i * 2).into_bytes(), index_entry(i)))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        {
+            let run = SpillRun::create(&spill_file_path, entries.clone().into_iter().map(Ok))
+                .unwrap()
+                .unwrap();
+            assert_eq!(run.len(), entries.len());
+
+            for (key, entry) in &entries {
+                let records: Vec<u64> = run.find(key).map(|record| record.unwrap()).collect();
+                assert_eq!(records.len(), 1);
+                assert_eq!(&run.get(records[0]).unwrap(), entry);
+            }
+
+            assert!(run.find(b"key1").next().is_none());
+            assert!(run.find(b"a").next().is_none());
+            assert!(run.find(b"z").next().is_none());
+
+            let iterated: Vec<(Vec<u8>, IndexEntry)> = run.iter()
+                .map(|record| {
+                    let (_, key, entry) = record.unwrap();
+                    (key, entry)
+                })
+                .collect();
+            assert_eq!(iterated, entries);
+        }
+
+        assert!(!spill_file_path.exists());
+
+        // a run whose entries fail to be read is discarded
+        let failing = entries.into_iter()
+            .map(Ok)
+            .chain(iter::once(Err(io::Error::other("failed").into())));
+        assert!(SpillRun::create(&spill_file_path, failing).is_err());
+        assert!(!spill_file_path.exists());
+
+        // only spill files are removed
+        fs::write(&spill_file_path, b"stale").unwrap();
+        fs::write(path.join("0000000001.cask.data"), b"data").unwrap();
+        remove_spill_files(path).unwrap();
+        assert!(!spill_file_path.exists());
+        assert!(path.join("0000000001.cask.data").exists());
+    }
+}
@@ -3,14 +3,54 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log as logrs;
 extern crate byteorder;
+extern crate crc32c;
 extern crate fs2;
+extern crate memmap2;
 extern crate regex;
 extern crate xxhash2;
 
+#[cfg(feature = "aes-gcm")]
+extern crate aes_gcm;
+#[cfg(feature = "chacha20poly1305")]
+extern crate chacha20poly1305;
+
+#[cfg(feature = "lz4")]
+extern crate lz4_flex;
+#[cfg(feature = "snappy")]
+extern crate snap;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+
+mod blob;
+mod bloom;
 mod cask;
+mod compression;
 mod data;
+mod encryption;
+mod errors;
+mod file_cache;
+mod index;
 mod log;
 mod stats;
 mod util;
+mod value_cache;
 
-pub use cask::Cask;
+pub use blob::ValueReader;
+pub use cask::{Cask, CaskOptions, Iter, OpenProgress, OpenSummary};
+pub use compression::Codec;
+#[cfg(feature = "lz4")]
+pub use compression::Lz4;
+#[cfg(feature = "snappy")]
+pub use compression::Snappy;
+#[cfg(feature = "zstd")]
+pub use compression::Zstd;
+pub use encryption::Cipher;
+#[cfg(feature = "aes-gcm")]
+pub use encryption::Aes256Gcm;
+#[cfg(feature = "chacha20poly1305")]
+pub use encryption::ChaCha20Poly1305;
+pub use errors::{Error, Result};
+pub use file_cache::ValueRef;
+pub use index::IndexMode;
+pub use stats::StatsSnapshot;
+pub use util::ChecksumType;
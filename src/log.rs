@@ -1,38 +1,84 @@
 use std::fs;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::prelude::*;
-use std::io::{Cursor, SeekFrom, Take};
+use std::io::{BufReader, BufWriter, Cursor, SeekFrom, Take};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::vec;
 use std::vec::Vec;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use fs2::FileExt;
 use regex::Regex;
 
-use data::{Entry, Hint};
-use util::{xxhash32, XxHash32, get_file_handle};
-
-const DATA_FILE_EXTENSION: &'static str = "cask.data";
-const HINT_FILE_EXTENSION: &'static str = "cask.hint";
-const LOCK_FILE_NAME: &'static str = "cask.lock";
-
-const DEFAULT_SIZE_THRESHOLD: usize = 2000 * 1024 * 1024;
+use data::{entry_static_size, Entry, FileHeader, FileKind, Hint, SequenceNumber,
+           LEGACY_FORMAT_VERSION};
+use encryption;
+use encryption::Keys;
+use errors::{Error, Result};
+use file_cache::{DataFile, FileCache, ValueRef};
+use util::{xxhash32, ChecksumReader, ChecksumType, get_file_handle};
+
+const DATA_FILE_EXTENSION: &str = "cask.data";
+const HINT_FILE_EXTENSION: &str = "cask.hint";
+const FREE_FILE_EXTENSION: &str = "cask.free";
+const TEMP_FILE_EXTENSION: &str = "tmp";
+const LOCK_FILE_NAME: &str = "cask.lock";
+
+pub const DEFAULT_SIZE_THRESHOLD: usize = 2000 * 1024 * 1024;
+pub const DEFAULT_WRITE_BUFFER_SIZE: usize = 64 * 1024;
+const HINT_BLOCK_SIZE: usize = 64 * 1024;
+
+/// How the data files of a database are written and read, see `Log::open`.
+pub struct LogOptions {
+    pub sync: bool,
+    pub keys: Keys,
+    pub checksum: ChecksumType,
+    pub max_open_files: usize,
+    pub mmap: bool,
+    pub write_buffer_size: usize,
+    pub size_threshold: usize,
+    pub preallocate: bool,
+    pub max_free_files: usize,
+}
 
+/// The data files of a database. Entries are read without locking, appends are serialized on the
+/// active data file.
 pub struct Log {
     pub path: PathBuf,
     sync: bool,
     size_threshold: usize,
-    lock_file: File,
-    files: Vec<u32>,
+    /// Not held by read-only logs, see `open_read_only`.
+    lock_file: Option<File>,
+    write_buffer_size: usize,
     current_file_id: AtomicUsize,
-    pub active_file_id: u32,
-    active_log_writer: LogWriter,
+    active_file_id: AtomicUsize,
+    /// The position up to which the active data file was flushed, entries past it are still
+    /// buffered.
+    flushed_pos: AtomicU64,
+    active: Mutex<ActiveLog>,
+    file_cache: FileCache,
+    mmap: bool,
+    keys: Keys,
+    checksum: ChecksumType,
+    preallocate: bool,
+    max_free_files: usize,
 }
 
 impl Log {
-    pub fn open(path: &str, sync: bool) -> Log {
+    pub fn open(path: &str, options: LogOptions) -> Log {
+        let LogOptions { sync,
+                         keys,
+                         checksum,
+                         max_open_files,
+                         mmap,
+                         write_buffer_size,
+                         size_threshold,
+                         preallocate,
+                         max_free_files } = options;
         let path = PathBuf::from(path);
 
         if path.exists() {
@@ -52,302 +98,1336 @@ impl Log {
             files[files.len() - 1] + 1
         };
 
-        let active_log_writer = LogWriter::new(&path, active_file_id, sync);
+        let mut free_files: Vec<PathBuf> = find_free_files(&path)
+            .into_iter()
+            .map(|file_id| get_free_file_path(&path, file_id))
+            .collect();
+
+        while free_files.len() > max_free_files {
+            fs::remove_file(free_files.pop().unwrap()).unwrap();
+        }
+
+        if let Some(free_file_path) = free_files.pop() {
+            recycle_file(&free_file_path, &get_data_file_path(&path, active_file_id));
+        }
+
+        let mut active_log_writer = LogWriter::new(&path,
+                                                   active_file_id,
+                                                   sync,
+                                                   &keys,
+                                                   checksum,
+                                                   write_buffer_size);
+
+        if preallocate {
+            active_log_writer.preallocate(size_threshold as u64);
+        }
 
         info!("Created new active data file {:?}",
               active_log_writer.data_file_path);
 
+        let file_cache = FileCache::new(&path, max_open_files);
+
         Log {
-            path: path,
-            sync: sync,
-            size_threshold: DEFAULT_SIZE_THRESHOLD,
-            lock_file: lock_file,
-            files: files,
+            path,
+            sync,
+            size_threshold,
+            lock_file: Some(lock_file),
+            write_buffer_size,
             current_file_id: AtomicUsize::new(active_file_id as usize),
-            active_file_id: active_file_id,
-            active_log_writer: active_log_writer,
+            active_file_id: AtomicUsize::new(active_file_id as usize),
+            flushed_pos: AtomicU64::new(active_log_writer.flushed_pos()),
+            active: Mutex::new(ActiveLog {
+                files,
+                free_files,
+                active_log_writer: Some(active_log_writer),
+            }),
+            file_cache,
+            mmap,
+            keys,
+            checksum,
+            preallocate,
+            max_free_files,
+        }
+    }
+
+    /// Opens the data files at `path` without locking them or creating an active data file, so
+    /// that they can be read while another process writes to them. Since the data files might still
+    /// be written to, they are never mapped.
+    pub fn open_read_only(path: &str,
+                          keys: Keys,
+                          max_open_files: usize,
+                          size_threshold: usize)
+                          -> Result<Log> {
+        let path = PathBuf::from(path);
+
+        if !path.is_dir() {
+            let err = io::Error::new(io::ErrorKind::NotFound, format!("No database at {:?}", path));
+            return Err(err.into());
         }
+
+        let files = find_data_files(&path);
+        let file_cache = FileCache::new(&path, max_open_files);
+
+        Ok(Log {
+            path,
+            sync: false,
+            size_threshold,
+            lock_file: None,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+            current_file_id: AtomicUsize::new(files.last().cloned().unwrap_or(0) as usize),
+            // no data file is active, so every data file is read as written
+            active_file_id: AtomicUsize::new(u32::MAX as usize),
+            flushed_pos: AtomicU64::new(0),
+            active: Mutex::new(ActiveLog {
+                files,
+                free_files: Vec::new(),
+                active_log_writer: None,
+            }),
+            file_cache,
+            mmap: false,
+            keys,
+            checksum: ChecksumType::default(),
+            preallocate: false,
+            max_free_files: 0,
+        })
     }
 
     pub fn files(&self) -> Vec<u32> {
-        self.files.clone()
+        self.active.lock().unwrap().files.clone()
     }
 
-    pub fn entries<'a>(&self, file_id: u32) -> Entries<'a> {
-        let data_file_path = get_data_file_path(&self.path, file_id);
-        info!("Loading data file: {:?}", data_file_path);
-        let data_file = get_file_handle(&data_file_path, false);
-        let data_file_size = data_file.metadata().unwrap().len();
+    /// Lists the data files again, for read-only logs whose data files are written by another
+    /// process, closing the cached handles of the data files which were removed.
+    pub fn refresh_files(&self) -> Vec<u32> {
+        let files = find_data_files(&self.path);
+        let mut active = self.active.lock().unwrap();
 
-        Entries {
-            data_file: data_file.take(data_file_size),
-            data_file_pos: 0,
-            phantom: PhantomData,
+        for &file_id in &active.files {
+            if files.binary_search(&file_id).is_err() {
+                self.file_cache.remove(file_id);
+            }
         }
+
+        active.files = files.clone();
+        files
     }
 
+    pub fn active_file_id(&self) -> u32 {
+        self.active_file_id.load(Ordering::SeqCst) as u32
+    }
+
+    /// Returns the hints of data file `file_id`, if its hint file is complete and valid. The hint
+    /// file is verified while its hints are read, so they are only returned once it is fully read.
     pub fn hints<'a>(&self, file_id: u32) -> Option<Hints<'a>> {
         let hint_file_path = get_hint_file_path(&self.path, file_id);
-        if is_valid_hint_file(&hint_file_path) {
-            info!("Loading hint file: {:?}", hint_file_path);
-            let hint_file = get_file_handle(&hint_file_path, false);
-            let hint_file_size = hint_file.metadata().unwrap().len();
-
-            Some(Hints {
-                hint_file: hint_file.take(hint_file_size - 4),
-                phantom: PhantomData,
-            })
+        let mut hint_reader = HintReader::open(&hint_file_path).ok()?;
+
+        info!("Loading hint file: {:?}", hint_file_path);
+
+        let mut hints = Vec::new();
+        while let Some(block) = hint_reader.next_block() {
+            hints.extend(block);
+        }
+
+        if hint_reader.complete {
+            Some(Hints { hints: hints.into_iter() })
         } else {
+            warn!("Found incomplete or corrupt hint file: {:?}", hint_file_path);
             None
         }
     }
 
-    pub fn recreate_hints<'a>(&mut self, file_id: u32) -> RecreateHints<'a> {
-        let hint_file_path = get_hint_file_path(&self.path, file_id);
-        warn!("Re-creating hint file: {:?}", hint_file_path);
+    /// Returns the hints of data file `file_id` as they are verified. If the hint file is missing,
+    /// incomplete or corrupt, the remaining hints are read from the data file, following the last
+    /// valid hint, and the hint file is re-created. The hint file of a data file which was active
+    /// when the database was last closed is unfinished, its valid blocks are reused.
+    pub fn load_hints<'a>(&self, file_id: u32) -> LoadHints<'a> {
+        let mut hint_file_path = get_hint_file_path(&self.path, file_id);
+        if !hint_file_path.exists() {
+            hint_file_path = get_temp_file_path(&hint_file_path);
+        }
 
-        let hint_writer = HintWriter::new(&self.path, file_id);
-        let entries = self.entries(file_id);
+        let hint_reader = HintReader::open(&hint_file_path)
+            .inspect(|_| {
+                info!("Loading hint file: {:?}", hint_file_path);
+            })
+            .ok();
+
+        LoadHints {
+            path: self.path.clone(),
+            file_id,
+            hints: Vec::new().into_iter(),
+            hint_reader,
+            data_file_pos: None,
+            recreate_hints: None,
+        }
+    }
 
-        RecreateHints {
-            hint_writer: hint_writer,
-            entries: entries,
+    /// Returns the hints of the entries of data file `file_id` from `data_file_pos`, or its first
+    /// entry, read from the data file without re-creating its hint file.
+    pub fn scan_hints<'a>(&self,
+                          file_id: u32,
+                          data_file_pos: Option<u64>)
+                          -> Result<ScanHints<'a>> {
+        let mut entries = Entries::open(&get_data_file_path(&self.path, file_id))?;
+        // a data file read before its header was written has no entries, and is read as a legacy
+        // data file, without a header
+        if let Some(data_file_pos) = data_file_pos {
+            if data_file_pos > entries.pos() {
+                entries.seek(data_file_pos)?;
+            }
         }
+
+        Ok(ScanHints {
+            data_file_pos: entries.pos(),
+            entries: Some(entries),
+        })
     }
 
-    pub fn read_entry<'a>(&self, file_id: u32, entry_pos: u64) -> Entry<'a> {
-        let mut data_file = get_file_handle(&get_data_file_path(&self.path, file_id), false);
-        data_file.seek(SeekFrom::Start(entry_pos)).unwrap();
-        Entry::from_read(&mut data_file)
+    /// Reads the entry at `entry_pos` of data file `file_id`. Reads of files that were removed
+    /// after being compacted fail with a `NotFound` IO error.
+    pub fn read_entry<'a>(&self, file_id: u32, entry_pos: u64) -> Result<Entry<'a>> {
+        let entry = self.data_file(file_id, entry_pos)?.read_entry(entry_pos)?;
+        self.keys.decrypt(entry, file_id, entry_pos)
     }
 
-    pub fn append_entry<'a>(&mut self, entry: &Entry<'a>) -> (u32, u64) {
-        if self.active_log_writer.data_file_pos + entry.size() > self.size_threshold as u64 {
+    /// Reads the key of the entry at `entry_pos` of data file `file_id`, keys are never encrypted.
+    pub fn read_key(&self, file_id: u32, entry_pos: u64) -> Result<Vec<u8>> {
+        self.data_file(file_id, entry_pos)?.read_key(entry_pos)
+    }
+
+    /// Reads an entry as `read_entry`, reusing the allocation of `buf` for its value if possible.
+    pub fn read_entry_into<'a>(&self,
+                               file_id: u32,
+                               entry_pos: u64,
+                               buf: &'a mut Vec<u8>)
+                               -> Result<Entry<'a>> {
+        let entry = self.data_file(file_id, entry_pos)?.read_entry_into(entry_pos, buf)?;
+        self.keys.decrypt(entry, file_id, entry_pos)
+    }
+
+    /// Returns the value of the entry of `key` borrowed from the memory mapping of its data file,
+    /// if data files are mapped and the value is stored as is.
+    pub fn read_value_ref(&self,
+                          file_id: u32,
+                          key: &[u8],
+                          entry_pos: u64)
+                          -> Result<Option<ValueRef>> {
+        // plaintext values are rejected by `read_entry`
+        if self.keys.encrypts() {
+            return Ok(None);
+        }
+
+        DataFile::read_value_ref(&self.data_file(file_id, entry_pos)?, key, entry_pos)
+    }
+
+    fn data_file(&self, file_id: u32, entry_pos: u64) -> Result<Arc<DataFile>> {
+        let active = file_id == self.active_file_id();
+
+        // entries of the active data file might still be buffered
+        if active && entry_pos >= self.flushed_pos.load(Ordering::SeqCst) {
+            self.flush();
+        }
+
+        // the active data file is still being written to so it is never mapped
+        self.file_cache.get(file_id, self.mmap && !active)
+    }
+
+    /// Appends `entry` to the active data file, returning the file id, the position and the size
+    /// of the written entry.
+    pub fn append_entry<'a>(&self, entry: &Entry<'a>) -> (u32, u64, u64) {
+        let mut active = self.active.lock().unwrap();
+
+        if active.writer().data_file_pos + entry.size(self.checksum) >
+           self.size_threshold as u64 {
             info!("Active data file {:?} reached file limit",
-                  self.active_log_writer.data_file_path);
+                  active.writer().data_file_path);
 
-            self.new_active_writer();
+            self.new_active_writer(&mut active);
         }
 
-        let entry_pos = self.active_log_writer.write(entry);
+        let (entry_pos, entry_size) = active.writer().write(entry);
+        self.flushed_pos.store(active.writer().flushed_pos(), Ordering::SeqCst);
+
+        (active.writer().file_id, entry_pos, entry_size)
+    }
 
-        (self.active_file_id, entry_pos)
+    /// Flushes the entries buffered for the active data file.
+    pub fn flush(&self) {
+        let mut active = self.active.lock().unwrap();
+        active.writer().flush();
+        self.flushed_pos.store(active.writer().flushed_pos(), Ordering::SeqCst);
+    }
+
+    /// Flushes the active data file and its hints and truncates it to its logical end, called when
+    /// the database is closed.
+    pub fn close(&self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(ref mut active_log_writer) = active.active_log_writer {
+            active_log_writer.flush_hints();
+            if let Err(err) = active_log_writer.truncate() {
+                warn!("Failed to truncate data file {:?}: {}",
+                      active_log_writer.data_file_path,
+                      err);
+            }
+            self.flushed_pos.store(active_log_writer.flushed_pos(), Ordering::SeqCst);
+        }
+    }
+
+    pub fn sync(&self) {
+        let mut active = self.active.lock().unwrap();
+        active.writer().sync();
+        self.flushed_pos.store(active.writer().flushed_pos(), Ordering::SeqCst);
     }
 
     pub fn new_file_id(&self) -> u32 {
         self.current_file_id.fetch_add(1, Ordering::SeqCst) as u32 + 1
     }
 
-    pub fn swap_file(&mut self, file_id: u32, new_file_id: u32) {
-        let idx = self.files.binary_search(&file_id).unwrap();
-        self.files.remove(idx);
+    /// Closes the active data file and creates a new one, returning its id.
+    pub fn rotate(&self) -> u32 {
+        let mut active = self.active.lock().unwrap();
+        self.new_active_writer(&mut active);
+        active.writer().file_id
+    }
+
+    /// Syncs the data files older than `watermark` and their hint files, so that they are durable
+    /// before a checkpoint indexing them replaces their replay.
+    pub fn sync_files(&self, watermark: u32) -> Result<()> {
+        for file_id in self.files().into_iter().filter(|&file_id| file_id < watermark) {
+            for file_path in &[get_data_file_path(&self.path, file_id),
+                               get_hint_file_path(&self.path, file_id)] {
+                match File::open(file_path) {
+                    Ok(file) => file.sync_all()?,
+                    // a missing hint file is re-created from its data file
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
 
-        self.add_file(new_file_id);
+        sync_dir(&self.path)
+    }
+
+    /// Creates a writer for the new data file `file_id`, reusing a recycled file if any.
+    pub fn new_writer(&self, file_id: u32) -> LogWriter {
+        let free_file_path = self.active.lock().unwrap().free_files.pop();
+        self.create_writer(free_file_path, file_id, false)
+    }
+
+    /// Removes the data file `file_id` written by a compaction which was aborted, before it was
+    /// swapped in.
+    pub fn discard_file(&self, file_id: u32) {
+        for file_path in &[get_data_file_path(&self.path, file_id),
+                           get_hint_file_path(&self.path, file_id)] {
+            if let Err(err) = fs::remove_file(file_path) {
+                warn!("Failed to remove {:?}: {}", file_path, err);
+            }
+        }
+    }
+
+    /// Adds the data file `file_id` written by a compaction whose entries couldn't all be indexed,
+    /// keeping the compacted file.
+    pub fn add_file(&self, file_id: u32) {
+        self.active.lock().unwrap().add_file(file_id);
+    }
+
+    pub fn swap_file(&self, file_id: u32, new_file_id: u32) {
+        let recycle = {
+            let mut active = self.active.lock().unwrap();
+            let idx = active.files.binary_search(&file_id).unwrap();
+            active.files.remove(idx);
+            active.add_file(new_file_id);
+            active.free_files.len() < self.max_free_files
+        };
 
         let data_file_path = get_data_file_path(&self.path, file_id);
         let hint_file_path = get_hint_file_path(&self.path, file_id);
 
-        fs::remove_file(data_file_path).unwrap();
+        if recycle {
+            // the file is renamed first so that no reader can open it anymore, it can only be
+            // reused once the readers that still have it open are done with it
+            let free_file_path = get_free_file_path(&self.path, file_id);
+            fs::rename(&data_file_path, &free_file_path).unwrap();
+
+            if self.file_cache.remove(file_id) {
+                fs::remove_file(free_file_path).unwrap();
+            } else {
+                self.active.lock().unwrap().free_files.push(free_file_path);
+            }
+        } else {
+            self.file_cache.remove(file_id);
+            fs::remove_file(data_file_path).unwrap();
+        }
+
         fs::remove_file(hint_file_path).unwrap();
     }
 
-    pub fn add_file(&mut self, file_id: u32) {
-        self.files.push(file_id);
-        self.files.sort();
+    fn create_writer(&self,
+                     free_file_path: Option<PathBuf>,
+                     file_id: u32,
+                     sync: bool)
+                     -> LogWriter {
+        if let Some(free_file_path) = free_file_path {
+            recycle_file(&free_file_path, &get_data_file_path(&self.path, file_id));
+        }
+
+        let mut log_writer = LogWriter::new(&self.path,
+                                            file_id,
+                                            sync,
+                                            &self.keys,
+                                            self.checksum,
+                                            self.write_buffer_size);
+
+        if self.preallocate {
+            log_writer.preallocate(self.size_threshold as u64);
+        }
+
+        log_writer
     }
 
-    fn new_active_writer(&mut self) {
-        let active_file_id = self.active_file_id;
-        self.add_file(active_file_id);
+    fn new_active_writer(&self, active: &mut ActiveLog) {
+        let active_file_id = active.writer().file_id;
+        active.add_file(active_file_id);
 
-        info!("Closed active data file {:?}",
-              self.active_log_writer.data_file_path);
+        info!("Closed active data file {:?}", active.writer().data_file_path);
 
-        self.active_file_id = self.new_file_id();
-        self.active_log_writer = LogWriter::new(&self.path, self.active_file_id, self.sync);
+        // the previous writer is flushed when dropped, before the new active file is published
+        let new_file_id = self.new_file_id();
+        let free_file_path = active.free_files.pop();
+        active.active_log_writer = Some(self.create_writer(free_file_path, new_file_id, self.sync));
+        self.flushed_pos.store(active.writer().flushed_pos(), Ordering::SeqCst);
+        self.active_file_id.store(new_file_id as usize, Ordering::SeqCst);
+
+        // the file is now immutable and can be mapped
+        self.file_cache.remove(active_file_id);
 
         info!("Created new active data file {:?}",
-              self.active_log_writer.data_file_path);
+              active.writer().data_file_path);
+    }
+}
+
+struct ActiveLog {
+    files: Vec<u32>,
+    /// Data files left by compaction that are reused for new data files.
+    free_files: Vec<PathBuf>,
+    /// Read-only logs have no active data file.
+    active_log_writer: Option<LogWriter>,
+}
+
+impl ActiveLog {
+    fn writer(&mut self) -> &mut LogWriter {
+        self.active_log_writer.as_mut().expect("Can't write to a read-only log")
+    }
+
+    fn add_file(&mut self, file_id: u32) {
+        self.files.push(file_id);
+        self.files.sort();
     }
 }
 
 impl Drop for Log {
     fn drop(&mut self) {
-        self.lock_file.unlock().unwrap();
+        if let Some(ref lock_file) = self.lock_file {
+            lock_file.unlock().unwrap();
+        }
     }
 }
 
 pub struct LogWriter {
     sync: bool,
+    file_id: u32,
+    keys: Keys,
+    header: FileHeader,
     data_file_path: PathBuf,
-    data_file: File,
+    data_file: BufWriter<File>,
     data_file_pos: u64,
+    /// Whether the data file may extend past its logical end, i.e. it was preallocated or it is a
+    /// recycled file.
+    preallocated: bool,
     hint_writer: HintWriter,
 }
 
 impl LogWriter {
-    pub fn new(path: &Path, file_id: u32, sync: bool) -> LogWriter {
-        let data_file_path = get_data_file_path(path, file_id);
-        let data_file = get_file_handle(&data_file_path, true);
+    /// Creates the data and hint files for `file_id`, values are encrypted with the current key of
+    /// `keys`, if any, and entries are checksummed with `checksum`. Writes are buffered in buffers
+    /// of `buffer_size` bytes, unless `sync` is set.
+    pub fn new(path: &Path,
+               file_id: u32,
+               sync: bool,
+               keys: &Keys,
+               checksum: ChecksumType,
+               buffer_size: usize)
+               -> LogWriter {
+        LogWriter::create(get_data_file_path(path, file_id),
+                          &get_hint_file_path(path, file_id),
+                          file_id,
+                          sync,
+                          keys.clone(),
+                          checksum,
+                          buffer_size)
+    }
 
-        let hint_writer = HintWriter::new(path, file_id);
+    fn create(data_file_path: PathBuf,
+              hint_file_path: &Path,
+              file_id: u32,
+              sync: bool,
+              keys: Keys,
+              checksum: ChecksumType,
+              buffer_size: usize)
+              -> LogWriter {
+        // recycled files are overwritten rather than truncated so that they keep their extents,
+        // the entries left from the previous file don't match the salt of the new header.
+        let data_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&data_file_path)
+            .unwrap();
+        let recycled = data_file.metadata().unwrap().len() > 0;
+        let mut data_file = BufWriter::with_capacity(buffer_size, data_file);
+
+        let header = FileHeader::salted(FileKind::Data, checksum);
+        header.write_bytes(&mut data_file).unwrap();
+
+        let hint_writer = HintWriter::create(hint_file_path, checksum, buffer_size);
 
         LogWriter {
-            sync: sync,
-            data_file_path: data_file_path,
-            data_file: data_file,
-            data_file_pos: 0,
-            hint_writer: hint_writer,
+            sync,
+            file_id,
+            keys,
+            header,
+            data_file_path,
+            data_file,
+            data_file_pos: header.size(),
+            preallocated: recycled,
+            hint_writer,
         }
     }
 
-    pub fn write<'a>(&mut self, entry: &Entry<'a>) -> u64 {
+    pub fn write<'a>(&mut self, entry: &Entry<'a>) -> (u64, u64) {
         let entry_pos = self.data_file_pos;
 
-        let hint = Hint::new(entry, entry_pos);
-        entry.write_bytes(&mut self.data_file);
+        let entry = self.keys.encrypt(entry, self.file_id, entry_pos);
+        let entry_size = entry.size(self.header.checksum);
+        let hint = Hint::new(&entry, entry_pos, entry_size);
+
+        // an entry is never split between the buffer and the file, so that it is either readable
+        // from the file or still buffered, see `flushed_pos`.
+        let capacity = self.data_file.capacity() as u64;
+        if self.data_file.buffer().len() as u64 + entry_size > capacity {
+            self.data_file.flush().unwrap();
+        }
+
+        entry.write_bytes(&mut self.data_file, &self.header);
 
-        self.hint_writer.write(&hint);
+        // a block of hints is only written once its entries are, so that the valid blocks of an
+        // unfinished hint file can be trusted after a crash.
+        if self.hint_writer.add(&hint) {
+            self.data_file.flush().unwrap();
+            self.hint_writer.write_block().unwrap();
+        }
+
+        self.data_file_pos += entry_size;
 
         if self.sync {
-            self.data_file.sync_data().unwrap();
+            self.sync();
+        } else if entry_size > capacity {
+            self.flush();
+        }
+
+        (entry_pos, entry_size)
+    }
+
+    /// Allocates `size` bytes for the data file, which is truncated to its logical end when the
+    /// writer is dropped.
+    pub fn preallocate(&mut self, size: u64) {
+        match self.data_file.get_ref().allocate(size) {
+            Ok(()) => self.preallocated = true,
+            Err(err) => warn!("Failed to preallocate data file {:?}: {}", self.data_file_path, err),
+        }
+    }
+
+    /// Truncates a preallocated or recycled data file to its logical end, the file grows by
+    /// appending from then on.
+    fn truncate(&mut self) -> io::Result<()> {
+        if self.preallocated {
+            self.data_file.flush()?;
+            self.data_file.get_ref().set_len(self.data_file_pos)?;
+            self.preallocated = false;
         }
+        Ok(())
+    }
+
+    /// The position up to which entries were written to the data file, entries past it are still
+    /// buffered.
+    pub fn flushed_pos(&self) -> u64 {
+        self.data_file_pos - self.data_file.buffer().len() as u64
+    }
+
+    pub fn flush(&mut self) {
+        self.data_file.flush().unwrap();
+    }
 
-        self.data_file_pos += entry.size();
+    /// Writes the pending hints of the hint file, following the entries they point to.
+    fn flush_hints(&mut self) {
+        self.flush();
 
-        entry_pos
+        if let Err(err) = self.hint_writer.flush() {
+            warn!("Failed to flush hint file of data file {:?}: {}", self.data_file_path, err);
+        }
+    }
+
+    pub fn sync(&mut self) {
+        self.flush();
+        self.data_file.get_ref().sync_data().unwrap();
     }
 }
 
 impl Drop for LogWriter {
     fn drop(&mut self) {
-        if self.sync {
-            self.data_file.sync_data().unwrap();
+        // the data file is synced before its hint file is finished, so that a finished hint file
+        // never points to entries that were lost
+        let closed = self.truncate()
+            .and_then(|_| self.data_file.flush())
+            .and_then(|_| self.data_file.get_ref().sync_data());
+
+        if let Err(err) = closed {
+            warn!("Failed to close data file {:?}, its hint file is left unfinished: {}",
+                  self.data_file_path,
+                  err);
+            self.hint_writer.abandon();
         }
     }
 }
 
+/// Writes hints in blocks of about `HINT_BLOCK_SIZE` bytes, each followed by its checksum, so that
+/// the blocks preceding a crash or a corruption can still be trusted. Hints are written to a
+/// temporary file which is renamed once an empty block marking the end of the hint file is written.
 struct HintWriter {
-    hint_file: File,
-    hint_file_hasher: XxHash32,
+    hint_file: BufWriter<File>,
+    block: Vec<u8>,
+    checksum: ChecksumType,
+    temp_hint_file_path: PathBuf,
+    hint_file_path: PathBuf,
+    finished: bool,
 }
 
 impl HintWriter {
-    pub fn new(path: &Path, file_id: u32) -> HintWriter {
-        let hint_file = get_file_handle(&get_hint_file_path(path, file_id), true);
+    fn create(hint_file_path: &Path, checksum: ChecksumType, buffer_size: usize) -> HintWriter {
+        let temp_hint_file_path = get_temp_file_path(hint_file_path);
+        let mut hint_file = BufWriter::with_capacity(buffer_size,
+                                                     get_file_handle(&temp_hint_file_path, true));
+
+        let header = FileHeader::new(FileKind::Hint, checksum);
+        header.write_bytes(&mut hint_file).unwrap();
+
+        HintWriter::append(hint_file, hint_file_path, checksum)
+    }
+
+    /// Re-opens the hint file read by `hint_reader`, keeping its valid blocks and appending new
+    /// blocks after them.
+    fn reopen(hint_file_path: &Path, hint_reader: &HintReader) -> Result<HintWriter> {
+        let temp_hint_file_path = get_temp_file_path(hint_file_path);
+
+        let mut hint_file = if hint_reader.path == temp_hint_file_path {
+            // the hint file was left unfinished, e.g. after a crash, and can be appended to
+            let hint_file = OpenOptions::new().write(true).open(&temp_hint_file_path)?;
+            hint_file.set_len(hint_reader.valid_size)?;
+            hint_file
+        } else {
+            let mut hint_file = get_file_handle(&temp_hint_file_path, true);
+            io::copy(&mut File::open(&hint_reader.path)?.take(hint_reader.valid_size),
+                     &mut hint_file)?;
+            hint_file
+        };
+
+        hint_file.seek(SeekFrom::Start(hint_reader.valid_size))?;
+
+        Ok(HintWriter::append(BufWriter::with_capacity(DEFAULT_WRITE_BUFFER_SIZE, hint_file),
+                              hint_file_path,
+                              hint_reader.header.checksum))
+    }
 
+    fn append(hint_file: BufWriter<File>,
+              hint_file_path: &Path,
+              checksum: ChecksumType)
+              -> HintWriter {
         HintWriter {
-            hint_file: hint_file,
-            hint_file_hasher: XxHash32::new(),
+            hint_file,
+            block: Vec::with_capacity(HINT_BLOCK_SIZE),
+            checksum,
+            temp_hint_file_path: get_temp_file_path(hint_file_path),
+            hint_file_path: hint_file_path.to_path_buf(),
+            finished: false,
         }
     }
 
     pub fn write<'a>(&mut self, hint: &Hint<'a>) {
-        hint.write_bytes(&mut self.hint_file);
-        hint.write_bytes(&mut self.hint_file_hasher);
+        if self.add(hint) {
+            self.write_block().unwrap();
+        }
+    }
+
+    /// Adds `hint` to the pending block, returning whether the block is full and should be
+    /// written.
+    fn add<'a>(&mut self, hint: &Hint<'a>) -> bool {
+        hint.write_bytes(&mut self.block);
+        self.block.len() >= HINT_BLOCK_SIZE
+    }
+
+    fn write_block(&mut self) -> Result<()> {
+        let mut len = [0u8; 4];
+        (&mut len[..]).write_u32::<LittleEndian>(self.block.len() as u32)?;
+
+        let mut hasher = self.checksum.hasher();
+        hasher.update(&len);
+        hasher.update(&self.block);
+
+        self.hint_file.write_all(&len)?;
+        self.hint_file.write_all(&self.block)?;
+        self.checksum.write(&mut self.hint_file, hasher.get())?;
+
+        self.block.clear();
+
+        Ok(())
+    }
+
+    /// Writes the pending hints as a block, so that they can be trusted if the hint file is left
+    /// unfinished.
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+
+        self.hint_file.flush()?;
+
+        Ok(())
+    }
+
+    /// Leaves the hint file unfinished, it is finished from its data file when the database is
+    /// next opened.
+    fn abandon(&mut self) {
+        self.finished = true;
+    }
+
+    /// Completes the hint file and renames it from its temporary file once synced.
+    pub fn finish(&mut self) -> Result<()> {
+        self.finished = true;
+
+        self.flush()?;
+        self.write_block()?;
+        self.hint_file.flush()?;
+        self.hint_file.get_ref().sync_data()?;
+
+        fs::rename(&self.temp_hint_file_path, &self.hint_file_path)?;
+
+        Ok(())
     }
 }
 
 impl Drop for HintWriter {
     fn drop(&mut self) {
-        self.hint_file
-            .write_u32::<LittleEndian>(self.hint_file_hasher.get())
-            .unwrap();
+        if !self.finished {
+            if let Err(err) = self.finish() {
+                warn!("Failed to write hint file {:?}, it will be re-created from its data \
+                       file: {}",
+                      self.hint_file_path,
+                      err);
+            }
+        }
+    }
+}
+
+/// Reads the hints of a hint file block by block, only returning the hints of blocks whose
+/// checksum matches. Hint files written before blocks were introduced are read as a single block
+/// checksummed by the trailer of the file.
+struct HintReader {
+    path: PathBuf,
+    hint_file: Take<BufReader<File>>,
+    header: FileHeader,
+    /// The size of the prefix of the hint file made of valid blocks.
+    valid_size: u64,
+    /// Whether the end of the hint file was reached without finding an invalid block.
+    complete: bool,
+    done: bool,
+}
+
+impl HintReader {
+    fn open(hint_file_path: &Path) -> Result<HintReader> {
+        let mut hint_file = File::open(hint_file_path)?;
+        let hint_file_size = hint_file.metadata()?.len();
+
+        let header = match FileHeader::from_read(FileKind::Hint, &mut hint_file) {
+            Ok(header) => header,
+            Err(err) => {
+                warn!("Found hint file with invalid header: {:?}: {}", hint_file_path, err);
+                return Err(err);
+            }
+        };
+
+        Ok(HintReader {
+            path: hint_file_path.to_path_buf(),
+            hint_file: BufReader::new(hint_file).take(hint_file_size - header.size()),
+            header,
+            valid_size: header.size(),
+            complete: false,
+            done: false,
+        })
+    }
+
+    /// Reads the next block of hints, returns `None` once the end of the hint file or an invalid
+    /// block is reached.
+    fn next_block<'a>(&mut self) -> Option<Vec<Hint<'a>>> {
+        if self.done {
+            return None;
+        }
+
+        let block = if self.header.version == LEGACY_FORMAT_VERSION {
+            self.read_legacy_block()
+        } else {
+            self.read_block()
+        };
+
+        match block {
+            Ok(Some((block, block_size))) => {
+                self.valid_size += block_size;
+                Some(block)
+            }
+            Ok(None) => {
+                self.complete = true;
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                info!("Reached invalid hint file block at {}: {}", self.valid_size, err);
+                self.done = true;
+                None
+            }
+        }
+    }
+
+    fn read_block<'a>(&mut self) -> Result<Option<(Vec<Hint<'a>>, u64)>> {
+        let mut len = [0u8; 4];
+        self.hint_file.read_exact(&mut len)?;
+
+        let block_len = (&len[..]).read_u32::<LittleEndian>()? as u64;
+        let checksum_size = self.header.checksum.size() as u64;
+
+        if block_len + checksum_size > self.hint_file.limit() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block").into());
+        }
+
+        let mut block = vec![0u8; block_len as usize];
+        self.hint_file.read_exact(&mut block)?;
+
+        let mut hasher = self.header.checksum.hasher();
+        hasher.update(&len);
+        hasher.update(&block);
+
+        let hash = hasher.get();
+        let checksum = self.header.checksum.read(&mut self.hint_file)?;
+
+        if hash != checksum {
+            return Err(Error::InvalidChecksum {
+                expected: checksum,
+                found: hash,
+            });
+        }
+
+        if block.is_empty() {
+            return Ok(None);
+        }
+
+        let mut cursor = Cursor::new(&block[..]);
+        let mut hints = Vec::new();
+
+        while cursor.position() < block_len {
+            hints.push(Hint::from_read(&mut cursor, &self.header)?);
+        }
+
+        Ok(Some((hints, len.len() as u64 + block_len + checksum_size)))
+    }
+
+    fn read_legacy_block<'a>(&mut self) -> Result<Option<(Vec<Hint<'a>>, u64)>> {
+        let checksum_size = self.header.checksum.size() as u64;
+
+        if self.hint_file.limit() < checksum_size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "missing checksum").into());
+        }
+
+        let block_size = self.hint_file.limit() - checksum_size;
+
+        // the checksum covers the header as well
+        let mut hasher = self.header.checksum.hasher();
+        if self.header.size() > 0 {
+            self.header.write_bytes(&mut hasher)?;
+        }
+
+        let mut hints = Vec::new();
+
+        {
+            let mut reader = ChecksumReader::new((&mut self.hint_file).take(block_size), hasher);
+
+            while reader.get_ref().limit() > 0 {
+                hints.push(Hint::from_read(&mut reader, &self.header)?);
+            }
+
+            let hash = reader.checksum();
+            let checksum = self.header.checksum.read(&mut self.hint_file)?;
+
+            if hash != checksum {
+                return Err(Error::InvalidChecksum {
+                    expected: checksum,
+                    found: hash,
+                });
+            }
+        }
+
+        // the whole hint file is a single block which is always the last one
+        self.complete = true;
+        self.done = true;
+
+        Ok(Some((hints, block_size + checksum_size)))
     }
 }
 
+/// The entries of a data file. Iteration stops at the first entry which can't be read, which
+/// marks the logical end of the file, see `end`.
 pub struct Entries<'a> {
     data_file: Take<File>,
-    data_file_pos: u64,
+    data_file_size: u64,
+    header: FileHeader,
+    end: Option<u64>,
     phantom: PhantomData<&'a ()>,
 }
 
+impl<'a> Entries<'a> {
+    fn open(data_file_path: &Path) -> Result<Entries<'a>> {
+        let mut data_file = File::open(data_file_path)?;
+        let data_file_size = data_file.metadata()?.len();
+        let header = FileHeader::from_read(FileKind::Data, &mut data_file)?;
+
+        Ok(Entries {
+            data_file: data_file.take(data_file_size - header.size()),
+            data_file_size,
+            header,
+            end: None,
+            phantom: PhantomData,
+        })
+    }
+
+    /// The logical end of the data file, i.e. the position following its last valid entry, once
+    /// iteration reached it. Preallocated and recycled data files extend past their logical end
+    /// until they are truncated.
+    fn end(&self) -> Option<u64> {
+        self.end
+    }
+
+    /// Stops iterating at `pos`, the logical end of the data file.
+    fn stop(&mut self, pos: u64) {
+        self.end = Some(pos);
+        self.data_file.set_limit(0);
+    }
+
+    /// The position of the next entry in the data file.
+    fn pos(&self) -> u64 {
+        self.data_file_size - self.data_file.limit()
+    }
+
+    /// Skips to the entry at `pos`.
+    fn seek(&mut self, pos: u64) -> Result<()> {
+        self.data_file.get_mut().seek(SeekFrom::Start(pos))?;
+        self.data_file.set_limit(self.data_file_size.saturating_sub(pos));
+        Ok(())
+    }
+}
+
 impl<'a> Iterator for Entries<'a> {
-    type Item = (u64, Entry<'a>);
+    type Item = Result<(u64, Entry<'a>)>;
+
+    fn next(&mut self) -> Option<Result<(u64, Entry<'a>)>> {
+        if self.end.is_some() {
+            return None;
+        }
+
+        let entry_pos = self.pos();
 
-    fn next(&mut self) -> Option<(u64, Entry<'a>)> {
         if self.data_file.limit() == 0 {
-            None
-        } else {
-            let entry = Entry::from_read(&mut self.data_file);
-            let entry_pos = self.data_file_pos;
+            self.stop(entry_pos);
+            return None;
+        }
+
+        let static_size = entry_static_size(self.header.version, self.header.checksum) as u64;
+        let mut buf = Vec::new();
+        let read = (&mut self.data_file).take(static_size).read_to_end(&mut buf);
+
+        // preallocated data files are zero-filled past their logical end, which is not worth
+        // reporting as an invalid entry
+        if read.is_ok() && buf.iter().all(|&b| b == 0) {
+            info!("Reached the logical end of data file at {}", entry_pos);
+            self.stop(entry_pos);
+            return None;
+        }
 
-            self.data_file_pos += entry.size();
+        let entry = {
+            let header = &self.header;
+            let data_file = &mut self.data_file;
+
+            read.map_err(Error::from)
+                .and_then(|_| Entry::from_read(&mut (&buf[..]).chain(data_file), header))
+        };
 
-            Some((entry_pos, entry))
+        match entry {
+            Ok(entry) => Some(Ok((entry_pos, entry))),
+            Err(err) => {
+                self.stop(entry_pos);
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// The hints of the entries of a data file, read from the data file, see `Log::scan_hints`. The
+/// scan stops at the first entry which can't be read, which is expected at the end of a data file
+/// that is still being written to.
+pub struct ScanHints<'a> {
+    entries: Option<Entries<'a>>,
+    data_file_pos: u64,
+}
+
+impl<'a> ScanHints<'a> {
+    /// The position in the data file following the last entry read.
+    pub fn data_file_pos(&self) -> u64 {
+        self.data_file_pos
+    }
+}
+
+impl<'a> Iterator for ScanHints<'a> {
+    type Item = Hint<'a>;
+
+    fn next(&mut self) -> Option<Hint<'a>> {
+        let next = self.entries.as_mut().and_then(|entries| entries.next());
+
+        match next {
+            Some(Ok((entry_pos, entry))) => {
+                let entry_size = self.entries.as_ref().unwrap().pos() - entry_pos;
+                self.data_file_pos = entry_pos + entry_size;
+                Some(Hint::from(entry, entry_pos, entry_size))
+            }
+            Some(Err(err)) => {
+                info!("Stopped reading data file at {}: {}", self.data_file_pos, err);
+                self.entries = None;
+                None
+            }
+            None => None,
         }
     }
 }
 
 pub struct Hints<'a> {
-    hint_file: Take<File>,
-    phantom: PhantomData<&'a ()>,
+    hints: vec::IntoIter<Hint<'a>>,
 }
 
 impl<'a> Iterator for Hints<'a> {
     type Item = Hint<'a>;
 
     fn next(&mut self) -> Option<Hint<'a>> {
-        if self.hint_file.limit() == 0 {
-            None
-        } else {
-            Some(Hint::from_read(&mut self.hint_file))
+        self.hints.next()
+    }
+}
+
+pub struct LoadHints<'a> {
+    path: PathBuf,
+    file_id: u32,
+    hints: vec::IntoIter<Hint<'a>>,
+    hint_reader: Option<HintReader>,
+    /// The position in the data file following the last valid hint.
+    data_file_pos: Option<u64>,
+    recreate_hints: Option<RecreateHints<'a>>,
+}
+
+impl<'a> LoadHints<'a> {
+    /// Renames a complete hint file left as a temporary file, by a crash between the rename of a
+    /// data file and of its hint file, e.g. during an upgrade, so that compactions can read it.
+    fn finish_rename(&self, hint_reader: &HintReader) {
+        let hint_file_path = get_hint_file_path(&self.path, self.file_id);
+        if hint_reader.path == hint_file_path {
+            return;
+        }
+
+        info!("Renaming complete hint file {:?}", hint_reader.path);
+        if let Err(err) = fs::rename(&hint_reader.path, &hint_file_path) {
+            warn!("Failed to rename hint file {:?}: {}", hint_reader.path, err);
         }
     }
+
+    /// How the hints were loaded, which is only known once they were all read.
+    pub fn status(&self) -> HintsStatus {
+        match self.recreate_hints {
+            Some(ref recreate_hints) if recreate_hints.unfinished => HintsStatus::Finished,
+            Some(_) => HintsStatus::Recreated,
+            None => HintsStatus::Loaded,
+        }
+    }
+}
+
+/// How the hints of a data file were loaded, see `Log::load_hints`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HintsStatus {
+    /// The hints were read from the hint file.
+    Loaded,
+    /// The hint file was left unfinished, as the data file was still being written to when the
+    /// database was closed, and was finished from the data file.
+    Finished,
+    /// The hint file was missing or corrupt and was re-created from the data file.
+    Recreated,
 }
 
-pub struct RecreateHints<'a> {
+impl<'a> Iterator for LoadHints<'a> {
+    type Item = Hint<'a>;
+
+    fn next(&mut self) -> Option<Hint<'a>> {
+        loop {
+            if let Some(ref mut recreate_hints) = self.recreate_hints {
+                return recreate_hints.next();
+            }
+
+            if let Some(hint) = self.hints.next() {
+                self.data_file_pos = Some(hint.entry_pos + hint.entry_size);
+                return Some(hint);
+            }
+
+            let block = self.hint_reader.as_mut().and_then(|hint_reader| hint_reader.next_block());
+
+            match block {
+                Some(block) => self.hints = block.into_iter(),
+                None => {
+                    let hint_reader = self.hint_reader.take();
+
+                    if let Some(ref hint_reader) = hint_reader {
+                        if hint_reader.complete {
+                            self.finish_rename(hint_reader);
+                            return None;
+                        }
+                    }
+
+                    let recreate_hints = RecreateHints::open(&self.path,
+                                                             self.file_id,
+                                                             hint_reader,
+                                                             self.data_file_pos);
+
+                    match recreate_hints {
+                        Ok(recreate_hints) => self.recreate_hints = Some(recreate_hints),
+                        Err(err) => {
+                            warn!("Failed to re-create hint file of data file {}: {}",
+                                  self.file_id,
+                                  err);
+                            return None;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Drop for LoadHints<'a> {
+    fn drop(&mut self) {
+        if self.recreate_hints.is_some() {
+            while self.next().is_some() {}
+        }
+    }
+}
+
+/// Re-creates a hint file from its data file. The hint file replaces the previous one once all
+/// entries are read.
+struct RecreateHints<'a> {
     hint_writer: HintWriter,
     entries: Entries<'a>,
+    data_file_path: PathBuf,
+    /// Whether the hint file was unfinished, i.e. the data file was still being written to. Only
+    /// those data files are truncated to their logical end, the others are left untouched past an
+    /// entry which can't be read.
+    unfinished: bool,
+}
+
+impl<'a> RecreateHints<'a> {
+    /// Re-creates the hint file of data file `file_id`. If `hint_reader` read any valid block, the
+    /// valid prefix of the hint file is kept and the data file is only read from `data_file_pos`.
+    fn open(path: &Path,
+            file_id: u32,
+            hint_reader: Option<HintReader>,
+            data_file_pos: Option<u64>)
+            -> Result<RecreateHints<'a>> {
+        let data_file_path = get_data_file_path(path, file_id);
+        let hint_file_path = get_hint_file_path(path, file_id);
+
+        let mut entries = Entries::open(&data_file_path)?;
+
+        // the hint file of the data file which was active is still a temporary file, which might
+        // not even be readable if the process crashed before its header was written
+        let temp_hint_file_path = get_temp_file_path(&hint_file_path);
+        let unfinished = match hint_reader {
+            Some(ref hint_reader) => hint_reader.path == temp_hint_file_path,
+            None => !hint_file_path.exists() && temp_hint_file_path.exists(),
+        };
+
+        let hint_writer = match hint_reader {
+            Some(ref hint_reader) if hint_reader.header.is_current() => {
+                let data_file_pos = data_file_pos.unwrap_or(entries.pos());
+
+                // the hint file of the previously active data file is always unfinished
+                if unfinished {
+                    info!("Finishing hint file: {:?} from position {} of data file",
+                          hint_file_path,
+                          data_file_pos);
+                } else {
+                    warn!("Re-creating hint file: {:?} from position {} of data file",
+                          hint_file_path,
+                          data_file_pos);
+                }
+
+                entries.seek(data_file_pos)?;
+
+                HintWriter::reopen(&hint_file_path, hint_reader)?
+            }
+            _ => {
+                warn!("Re-creating hint file: {:?}", hint_file_path);
+                HintWriter::create(&hint_file_path,
+                                   entries.header.checksum,
+                                   DEFAULT_WRITE_BUFFER_SIZE)
+            }
+        };
+
+        Ok(RecreateHints {
+            hint_writer,
+            entries,
+            data_file_path,
+            unfinished,
+        })
+    }
+
+    /// Truncates the data file to its logical end, the data file of a crashed process may be
+    /// preallocated or recycled, or end with a torn entry.
+    fn truncate(&self, end: u64) -> Result<()> {
+        let data_file = OpenOptions::new().write(true).open(&self.data_file_path)?;
+        data_file.set_len(end)?;
+        data_file.sync_all()?;
+        Ok(())
+    }
 }
 
 impl<'a> Iterator for RecreateHints<'a> {
     type Item = Hint<'a>;
 
     fn next(&mut self) -> Option<Hint<'a>> {
-        self.entries.next().map(|e| {
-            let (entry_pos, entry) = e;
-            let hint = Hint::from(entry, entry_pos);
-            self.hint_writer.write(&hint);
-            hint
-        })
+        match self.entries.next() {
+            Some(Ok((entry_pos, entry))) => {
+                let hint = Hint::from(entry, entry_pos, self.entries.pos() - entry_pos);
+                self.hint_writer.write(&hint);
+                Some(hint)
+            }
+            Some(Err(err)) => {
+                warn!("Stopped reading data file {:?} at {}: {}",
+                      self.data_file_path,
+                      self.entries.end().unwrap(),
+                      err);
+                None
+            }
+            None => None,
+        }
     }
 }
 
 impl<'a> Drop for RecreateHints<'a> {
     fn drop(&mut self) {
         while self.next().is_some() {}
+
+        // the hint file is finished once the data file is truncated
+        match self.entries.end() {
+            Some(end) if self.unfinished && end < self.entries.data_file_size => {
+                info!("Truncating data file {:?} to its logical end at {}",
+                      self.data_file_path,
+                      end);
+
+                if let Err(err) = self.truncate(end) {
+                    warn!("Failed to truncate data file {:?}: {}", self.data_file_path, err);
+                }
+            }
+            Some(end) if end < self.entries.data_file_size => {
+                warn!("Leaving data file {:?} untouched past {}, its entries aren't indexed",
+                      self.data_file_path,
+                      end);
+            }
+            _ => {}
+        }
     }
 }
 
-fn get_data_file_path(path: &Path, file_id: u32) -> PathBuf {
+pub fn get_data_file_path(path: &Path, file_id: u32) -> PathBuf {
     let file_id = format!("{:010}", file_id);
     path.join(file_id).with_extension(DATA_FILE_EXTENSION)
 }
 
-fn get_hint_file_path(path: &Path, file_id: u32) -> PathBuf {
+pub fn get_hint_file_path(path: &Path, file_id: u32) -> PathBuf {
     let file_id = format!("{:010}", file_id);
     path.join(file_id).with_extension(HINT_FILE_EXTENSION)
 }
 
-fn find_data_files(path: &Path) -> Vec<u32> {
-    let files = fs::read_dir(path).unwrap();
+pub fn get_temp_file_path(file_path: &Path) -> PathBuf {
+    let mut path = file_path.as_os_str().to_owned();
+    path.push(".");
+    path.push(TEMP_FILE_EXTENSION);
+    PathBuf::from(path)
+}
+
+fn get_free_file_path(path: &Path, file_id: u32) -> PathBuf {
+    let file_id = format!("{:010}", file_id);
+    path.join(file_id).with_extension(FREE_FILE_EXTENSION)
+}
 
+/// Reuses the free file at `free_file_path` as the data file at `data_file_path`, its contents are
+/// overwritten when the data file is created and truncated once it is closed.
+fn recycle_file(free_file_path: &Path, data_file_path: &Path) {
+    info!("Recycling free file {:?} as {:?}", free_file_path, data_file_path);
+    fs::rename(free_file_path, data_file_path).unwrap();
+}
+
+fn find_free_files(path: &Path) -> Vec<u32> {
+    let re = Regex::new(&format!("(\\d+)\\.{}$", FREE_FILE_EXTENSION)).unwrap();
+    find_files(path, &re)
+}
+
+// `lazy_static` 0.2 initializes statics with the deprecated `ONCE_INIT`
+#[allow(deprecated)]
+pub fn find_data_files(path: &Path) -> Vec<u32> {
     lazy_static! {
         static ref RE: Regex =
-            Regex::new(&format!("(\\d+).{}$", DATA_FILE_EXTENSION)).unwrap();
+            Regex::new(&format!("(\\d+)\\.{}$", DATA_FILE_EXTENSION)).unwrap();
     }
 
-    let mut files: Vec<u32> = files.flat_map(|f| {
-            let file = f.unwrap();
-            let file_metadata = file.metadata().unwrap();
+    find_files(path, &RE)
+}
 
-            if file_metadata.is_file() {
-                let file_name = file.file_name();
-                let captures = RE.captures(file_name.to_str().unwrap());
-                captures.and_then(|c| c.at(1).and_then(|n| n.parse::<u32>().ok()))
-            } else {
-                None
+/// Returns the ids of the files in `path` whose name matches `re`. Files removed while the
+/// directory is listed, e.g. by the compaction of another process, are skipped.
+pub fn find_files(path: &Path, re: &Regex) -> Vec<u32> {
+    let files = fs::read_dir(path).unwrap();
+
+    let mut files: Vec<u32> = files.flat_map(|f| {
+            let file = match f {
+                Ok(file) => file,
+                Err(_) => return None,
+            };
+
+            match file.metadata() {
+                Ok(ref file_metadata) if file_metadata.is_file() => {
+                    let file_name = file.file_name();
+                    let captures = file_name.to_str().and_then(|name| re.captures(name));
+                    captures.and_then(|c| c.at(1).and_then(|n| n.parse::<u32>().ok()))
+                }
+                _ => None,
             }
         })
         .collect();
@@ -357,29 +1437,429 @@ fn find_data_files(path: &Path) -> Vec<u32> {
     files
 }
 
-fn is_valid_hint_file(path: &Path) -> bool {
-    path.is_file() &&
-    {
-        let mut hint_file = get_file_handle(path, false);
+fn is_current_hint_file(path: &Path) -> bool {
+    HintReader::open(path)
+        .map(|mut hint_reader| {
+            while hint_reader.next_block().is_some() {}
+            hint_reader.complete && hint_reader.header.is_current()
+        })
+        .unwrap_or(false)
+}
 
-        // FIXME: avoid reading the whole hint file into memory;
-        let mut buf = Vec::new();
-        hint_file.read_to_end(&mut buf).unwrap();
+/// Rewrites all data and hint files of the database at `path` to the current file format. Every
+/// file is first written to a temporary file which is synced and verified before it replaces the
+/// original, so the database is left in a consistent state if the upgrade is interrupted.
+///
+/// Rewritten data files are checksummed with `checksum` and their values are encrypted with the
+/// current key of `keys`, if any. Encrypted values must be decryptable with `keys` since they are
+/// bound to the position of their entry.
+pub fn upgrade(path: &Path, keys: &Keys, checksum: ChecksumType) -> Result<()> {
+    let lock_file = File::create(path.join(LOCK_FILE_NAME))?;
+    lock_file.try_lock_exclusive()?;
+
+    remove_temp_files(path)?;
+
+    for file_id in find_data_files(path) {
+        let data_file_path = get_data_file_path(path, file_id);
+        let hint_file_path = get_hint_file_path(path, file_id);
+
+        let header = {
+            let mut data_file = File::open(&data_file_path)?;
+            FileHeader::from_read(FileKind::Data, &mut data_file)?
+        };
+
+        if !header.is_current() {
+            info!("Upgrading data file {:?} from version {}",
+                  data_file_path,
+                  header.version);
+            upgrade_data_file(file_id, &data_file_path, &hint_file_path, keys, checksum)?;
+        } else if keys.encrypts() && has_plaintext_values(&data_file_path)? {
+            info!("Encrypting data file {:?}", data_file_path);
+            upgrade_data_file(file_id, &data_file_path, &hint_file_path, keys, checksum)?;
+        } else if !is_current_hint_file(&hint_file_path) {
+            info!("Upgrading hint file {:?}", hint_file_path);
+            upgrade_hint_file(&data_file_path, &hint_file_path)?;
+        }
+    }
+
+    sync_dir(path)?;
+
+    lock_file.unlock()?;
+
+    Ok(())
+}
+
+fn upgrade_data_file(file_id: u32,
+                     data_file_path: &Path,
+                     hint_file_path: &Path,
+                     keys: &Keys,
+                     checksum: ChecksumType)
+                     -> Result<()> {
+    let temp_data_file_path = get_temp_file_path(data_file_path);
+    let temp_hint_file_path = get_temp_file_path(hint_file_path);
+
+    let written = {
+        let mut log_writer = LogWriter::create(temp_data_file_path.clone(),
+                                               &temp_hint_file_path,
+                                               file_id,
+                                               false,
+                                               keys.clone(),
+                                               checksum,
+                                               DEFAULT_WRITE_BUFFER_SIZE);
+        let mut summary = EntriesSummary::new();
+
+        for e in Entries::open(data_file_path)? {
+            let (entry_pos, entry) = e?;
+            let entry = keys.decrypt_upgrade(entry, file_id, entry_pos)?;
+            summary.add(&entry);
+            log_writer.write(&entry);
+        }
+
+        summary
+    };
+
+    File::open(&temp_data_file_path)?.sync_all()?;
+    File::open(&temp_hint_file_path)?.sync_all()?;
+
+    let mut read = EntriesSummary::new();
+    for e in Entries::open(&temp_data_file_path)? {
+        let (entry_pos, entry) = e?;
+        read.add(&keys.decrypt(entry, file_id, entry_pos)?);
+    }
+
+    if read != written || !is_current_hint_file(&temp_hint_file_path) {
+        return Err(Error::UpgradeVerificationFailed(file_id));
+    }
+
+    // the old hint file is removed first since its entry positions are not valid for the new data
+    // file, a missing hint file is re-created when the database is opened.
+    if hint_file_path.exists() {
+        fs::remove_file(hint_file_path)?;
+    }
+
+    fs::rename(temp_data_file_path, data_file_path)?;
+    fs::rename(temp_hint_file_path, hint_file_path)?;
+
+    Ok(())
+}
+
+fn has_plaintext_values(data_file_path: &Path) -> Result<bool> {
+    for e in Entries::open(data_file_path)? {
+        let (_, entry) = e?;
+        if !entry.encrypted && encryption::has_value(&entry) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn upgrade_hint_file(data_file_path: &Path, hint_file_path: &Path) -> Result<()> {
+    let mut entries = Entries::open(data_file_path)?;
+    let mut hint_writer = HintWriter::create(hint_file_path,
+                                             entries.header.checksum,
+                                             DEFAULT_WRITE_BUFFER_SIZE);
+
+    while let Some(e) = entries.next() {
+        let (entry_pos, entry) = e?;
+        hint_writer.write(&Hint::new(&entry, entry_pos, entries.pos() - entry_pos));
+    }
+
+    hint_writer.finish()?;
+
+    if !is_current_hint_file(hint_file_path) {
+        return Err(Error::InvalidFileHeader);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Eq, PartialEq)]
+struct EntriesSummary {
+    entries: u64,
+    max_sequence: SequenceNumber,
+    checksum: u32,
+}
+
+impl EntriesSummary {
+    fn new() -> EntriesSummary {
+        EntriesSummary {
+            entries: 0,
+            max_sequence: 0,
+            checksum: 0,
+        }
+    }
+
+    fn add(&mut self, entry: &Entry) {
+        self.entries += 1;
+        if entry.sequence > self.max_sequence {
+            self.max_sequence = entry.sequence;
+        }
+        let header = FileHeader::new(FileKind::Data, ChecksumType::XxHash32);
+        self.checksum ^= xxhash32(&entry.to_bytes(&header));
+    }
+}
+
+fn remove_temp_files(path: &Path) -> Result<()> {
+    for f in fs::read_dir(path)? {
+        let file_path = f?.path();
+        if file_path.extension().is_some_and(|ext| ext == TEMP_FILE_EXTENSION) {
+            warn!("Removing stale temporary file: {:?}", file_path);
+            fs::remove_file(file_path)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    use data::{Entry, Hint};
+    use encryption::Keys;
+    use log::{get_data_file_path, get_free_file_path, get_hint_file_path, get_temp_file_path,
+              HintReader, HintWriter, HintsStatus, Log, LogOptions, LogWriter,
+              DEFAULT_SIZE_THRESHOLD, DEFAULT_WRITE_BUFFER_SIZE, HINT_BLOCK_SIZE};
+    use util::{ChecksumType, TestDir};
+
+    #[test]
+    fn test_hint_blocks() {
+        let test_dir = TestDir::new("hint-blocks");
+        let path = test_dir.path();
+
+        let hint_file_path = path.join("0.cask.hint");
+        // hints take at least 25 bytes
+        let hints = 3 * HINT_BLOCK_SIZE / 25;
+
+        {
+            let mut hint_writer = HintWriter::create(&hint_file_path,
+                                                     ChecksumType::Crc32c,
+                                                     DEFAULT_WRITE_BUFFER_SIZE);
+            for i in 0..hints {
+                let key = format!("key{}", i);
+                let entry = Entry::new(i as u64, key.as_bytes(), &b"value"[..]);
+                hint_writer.write(&Hint::new(&entry, 0, 0));
+            }
+        }
+
+        let read = |path| {
+            let mut hint_reader = HintReader::open(path).unwrap();
+            let mut sequences = Vec::new();
+            while let Some(block) = hint_reader.next_block() {
+                sequences.extend(block.iter().map(|hint| hint.sequence));
+            }
+            (sequences, hint_reader.complete)
+        };
+
+        let (sequences, complete) = read(&hint_file_path);
+        assert!(complete);
+        assert_eq!(sequences, (0..hints as u64).collect::<Vec<_>>());
+
+        // only the blocks preceding a corrupt block are returned
+        {
+            let mut hint_file = OpenOptions::new().write(true).open(&hint_file_path).unwrap();
+            hint_file.seek(SeekFrom::Start(HINT_BLOCK_SIZE as u64 + 1024)).unwrap();
+            hint_file.write_all(&[0xff; 8]).unwrap();
+        }
+
+        let (sequences, complete) = read(&hint_file_path);
+        assert!(!complete);
+        assert!(!sequences.is_empty() && sequences.len() < hints);
+        assert_eq!(sequences, (0..sequences.len() as u64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_scan_hints() {
+        let test_dir = TestDir::new("scan-hints");
+        let path = test_dir.path();
 
-        buf.len() >= 4 &&
         {
-            let hash = xxhash32(&buf[..buf.len() - 4]);
+            let mut log_writer =
+                LogWriter::new(path, 0, false, &Keys::new(), ChecksumType::XxHash32, 0);
+            for i in 0..10 {
+                log_writer.write(&Entry::new(i, &b"key"[..], &[0u8; 100][..]));
+            }
+        }
 
-            let mut cursor = Cursor::new(&buf[buf.len() - 4..]);
-            let checksum = cursor.read_u32::<LittleEndian>().unwrap();
+        // the last entry is still being written
+        let data_file_path = get_data_file_path(path, 0);
+        let data = fs::read(&data_file_path).unwrap();
+        fs::write(&data_file_path, &data[..data.len() - 10]).unwrap();
 
-            let valid = hash == checksum;
+        let log =
+            Log::open_read_only(path.to_str().unwrap(), Keys::new(), 1, DEFAULT_SIZE_THRESHOLD)
+                .unwrap();
 
-            if !valid {
-                warn!("Found corrupt hint file: {:?}", &path);
+        let mut scan_hints = log.scan_hints(0, None).unwrap();
+        let sequences: Vec<u64> = scan_hints.by_ref().map(|hint| hint.sequence).collect();
+        assert_eq!(sequences, (0..9).collect::<Vec<_>>());
+
+        fs::write(&data_file_path, &data).unwrap();
+
+        let mut scan_hints = log.scan_hints(0, Some(scan_hints.data_file_pos())).unwrap();
+        let sequences: Vec<u64> = scan_hints.by_ref().map(|hint| hint.sequence).collect();
+        assert_eq!(sequences, vec![9]);
+        assert_eq!(scan_hints.data_file_pos(), data.len() as u64);
+    }
+
+    #[test]
+    fn test_recycled_file() {
+        let test_dir = TestDir::new("recycled-file");
+        let path = test_dir.path();
+
+        let open = || {
+            Log::open(test_dir.path_str(),
+                      LogOptions {
+                          sync: false,
+                          keys: Keys::new(),
+                          checksum: ChecksumType::XxHash32,
+                          max_open_files: 1,
+                          mmap: false,
+                          write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+                          size_threshold: DEFAULT_SIZE_THRESHOLD,
+                          preallocate: false,
+                          max_free_files: 1,
+                      })
+        };
+
+        // a compacted data file kept as a free file
+        {
+            let mut log_writer =
+                LogWriter::new(path, 0, false, &Keys::new(), ChecksumType::XxHash32, 0);
+            for i in 0..100 {
+                log_writer.write(&Entry::new(i, &b"key"[..], &[0u8; 1000][..]));
             }
+        }
+        fs::rename(get_data_file_path(path, 0), get_free_file_path(path, 0)).unwrap();
+        fs::remove_file(get_hint_file_path(path, 0)).unwrap();
+        let free_file_size = fs::metadata(get_free_file_path(path, 0)).unwrap().len();
+
+        let data_file_path = get_data_file_path(path, 0);
+        let hint_file_path = get_hint_file_path(path, 0);
+        let temp_hint_file_path = get_temp_file_path(&hint_file_path);
+
+        // the free file is reused as the active data file without being truncated
+        let (data, hints) = {
+            let log = open();
+            assert_eq!(log.active_file_id(), 0);
+            assert_eq!(fs::metadata(&data_file_path).unwrap().len(), free_file_size);
+
+            for i in 0..3 {
+                log.append_entry(&Entry::new(100 + i, &b"key"[..], &b"value"[..]));
+            }
+            log.flush();
+
+            (fs::read(&data_file_path).unwrap(), fs::read(&temp_hint_file_path).unwrap())
+        };
 
-            valid
+        // the data file is truncated to its logical end once closed
+        let end = fs::metadata(&data_file_path).unwrap().len();
+        assert!(end < free_file_size);
+
+        // the entries of the previous file are ignored after a crash and the data file is
+        // truncated when its hint file is finished
+        assert_eq!(data.len() as u64, free_file_size);
+        fs::write(&data_file_path, &data).unwrap();
+        fs::write(&temp_hint_file_path, &hints).unwrap();
+        fs::remove_file(&hint_file_path).unwrap();
+
+        let log = open();
+        assert_eq!(log.active_file_id(), 1);
+
+        let mut load_hints = log.load_hints(0);
+        let sequences: Vec<u64> = load_hints.by_ref().map(|hint| hint.sequence).collect();
+        assert_eq!(sequences, vec![100, 101, 102]);
+        assert_eq!(load_hints.status(), HintsStatus::Finished);
+        drop(load_hints);
+        assert_eq!(fs::metadata(&data_file_path).unwrap().len(), end);
+        assert!(hint_file_path.exists() && !temp_hint_file_path.exists());
+
+        let mut load_hints = log.load_hints(0);
+        assert_eq!(load_hints.by_ref().count(), 3);
+        assert_eq!(load_hints.status(), HintsStatus::Loaded);
+
+        // a missing data file has no hints
+        assert_eq!(log.load_hints(42).count(), 0);
+    }
+
+    #[test]
+    fn test_preallocate() {
+        let test_dir = TestDir::new("preallocate");
+        let path = test_dir.path();
+        let size_threshold = 64 * 1024;
+
+        let log = Log::open(test_dir.path_str(),
+                            LogOptions {
+                                sync: false,
+                                keys: Keys::new(),
+                                checksum: ChecksumType::XxHash32,
+                                max_open_files: 1,
+                                mmap: false,
+                                write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+                                size_threshold,
+                                preallocate: true,
+                                max_free_files: 0,
+                            });
+
+        // data files are preallocated up to the configured size, including the first one
+        let data_file_size =
+            |file_id| fs::metadata(get_data_file_path(path, file_id)).unwrap().len();
+        assert_eq!(data_file_size(0), size_threshold as u64);
+
+        while log.active_file_id() == 0 {
+            log.append_entry(&Entry::new(0, &b"key"[..], &[0u8; 1000][..]));
         }
+        assert!(data_file_size(0) <= size_threshold as u64);
+        assert_eq!(data_file_size(1), size_threshold as u64);
+    }
+
+    #[test]
+    fn test_corrupt_sealed_file() {
+        let test_dir = TestDir::new("corrupt-sealed-file");
+        let path = test_dir.path();
+
+        let mut positions = Vec::new();
+        {
+            let mut log_writer =
+                LogWriter::new(path, 0, false, &Keys::new(), ChecksumType::XxHash32, 0);
+            for i in 0..10 {
+                positions.push(log_writer.write(&Entry::new(i, &b"key"[..], &[0u8; 100][..])).0);
+            }
+        }
+
+        // an entry in the middle of a data file which is no longer written to is corrupt, and its
+        // hint file is missing
+        let data_file_path = get_data_file_path(path, 0);
+        let mut data = fs::read(&data_file_path).unwrap();
+        let len = data.len();
+        data[positions[5] as usize + 30] ^= 0xff;
+        fs::write(&data_file_path, &data).unwrap();
+        fs::remove_file(get_hint_file_path(path, 0)).unwrap();
+
+        let log = Log::open(test_dir.path_str(),
+                            LogOptions {
+                                sync: false,
+                                keys: Keys::new(),
+                                checksum: ChecksumType::XxHash32,
+                                max_open_files: 1,
+                                mmap: false,
+                                write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+                                size_threshold: DEFAULT_SIZE_THRESHOLD,
+                                preallocate: false,
+                                max_free_files: 0,
+                            });
+
+        // the hints stop at the corrupt entry, but the entries following it are kept
+        let sequences: Vec<u64> = log.load_hints(0).map(|hint| hint.sequence).collect();
+        assert_eq!(sequences, (0..5).collect::<Vec<_>>());
+        assert_eq!(fs::metadata(&data_file_path).unwrap().len(), len as u64);
     }
 }
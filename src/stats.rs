@@ -1,9 +1,13 @@
 use std::collections::HashMap;
-use std::collections::hash_map::Entry as HashMapEntry;
+use std::io::{Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 use cask::IndexEntry;
+use data::BlobRef;
+use errors::Result;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Default)]
 struct StatsEntry {
     entries: u64,
     dead_entries: u64,
@@ -11,49 +15,165 @@ struct StatsEntry {
     dead_bytes: u64,
 }
 
-#[derive(Debug)]
+impl StatsEntry {
+    fn merge(&mut self, other: &StatsEntry) {
+        self.entries += other.entries;
+        self.dead_entries += other.dead_entries;
+        self.total_bytes += other.total_bytes;
+        self.dead_bytes += other.dead_bytes;
+    }
+}
+
+fn file_entry(map: &mut HashMap<u32, StatsEntry>, file_id: u32) -> &mut StatsEntry {
+    map.entry(file_id).or_default()
+}
+
+/// A point-in-time snapshot of the database statistics, returned by `Cask::stats`.
+#[derive(Clone, Debug, Default)]
+pub struct StatsSnapshot {
+    /// The number of live keys.
+    pub live_entries: u64,
+    /// The size of the live entries in the data files, as stored on disk. Compressed values are
+    /// accounted with their compressed size and values stored in blob files aren't included.
+    pub live_bytes: u64,
+    /// The size of the live values as returned by `get`, i.e. before compression.
+    pub live_value_bytes: u64,
+    /// The size of the live values stored in blob files.
+    pub live_blob_bytes: u64,
+    /// The memory used by the index in bytes, approximate in `IndexMode::Full`.
+    pub index_memory: u64,
+    /// The number of index entries spilled to disk, see `CaskOptions::index_memory_budget`.
+    pub index_spilled_entries: u64,
+    /// The number of reads served by the value cache, see `CaskOptions::value_cache_size`.
+    pub value_cache_hits: u64,
+    /// The number of reads of values which weren't in the value cache.
+    pub value_cache_misses: u64,
+    /// The memory used by the values in the value cache in bytes.
+    pub value_cache_bytes: u64,
+}
+
+#[derive(Clone, Debug)]
 pub struct Stats {
     map: HashMap<u32, StatsEntry>,
+    blob_map: HashMap<u32, StatsEntry>,
+    live: StatsSnapshot,
 }
 
 impl Stats {
     pub fn new() -> Stats {
-        Stats { map: HashMap::new() }
+        Stats {
+            map: HashMap::new(),
+            blob_map: HashMap::new(),
+            live: StatsSnapshot::default(),
+        }
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.live.clone()
     }
 
     pub fn add_entry(&mut self, entry: &IndexEntry) {
-        match self.map.entry(entry.file_id) {
-            HashMapEntry::Occupied(mut o) => {
-                o.get_mut().entries += 1;
-                o.get_mut().total_bytes += entry.entry_size;
-            }
-            HashMapEntry::Vacant(e) => {
-                e.insert(StatsEntry {
-                    entries: 1,
-                    dead_entries: 0,
-                    total_bytes: entry.entry_size,
-                    dead_bytes: 0,
-                });
-            }
-        }
+        self.add(entry, true);
     }
 
     pub fn remove_entry(&mut self, entry: &IndexEntry) {
-        match self.map.entry(entry.file_id) {
-            HashMapEntry::Occupied(mut o) => {
-                o.get_mut().dead_entries += 1;
-                o.get_mut().dead_bytes += entry.entry_size;
-            }
-            HashMapEntry::Vacant(_) => {
-                warn!("Tried to reclaim non-existant entry {:?}", entry);
-            }
+        self.remove(entry, true);
+    }
+
+    /// Accounts `entry` replacing `current` as the entry of a key. A blob referenced by both, e.g.
+    /// once compaction copied the entry to a new data file, stays live in its blob file.
+    pub fn replace_entry(&mut self, current: &IndexEntry, entry: &IndexEntry) {
+        let same_blob = entry.blob_ref.is_some() && current.blob_ref == entry.blob_ref;
+        self.remove(current, !same_blob);
+        self.add(entry, !same_blob);
+    }
+
+    /// Accounts a blob file, which might hold no live values, so that it gets compacted.
+    pub fn add_blob_file(&mut self, file_id: u32) {
+        file_entry(&mut self.blob_map, file_id);
+    }
+
+    /// Accounts a live entry loaded from a checkpoint, whose data file is already accounted.
+    pub fn add_live_entry(&mut self, entry: &IndexEntry) {
+        self.live.live_entries += 1;
+        self.live.live_bytes += entry.entry_size;
+        self.live.live_value_bytes += entry.value_size;
+
+        if let Some(ref blob_ref) = entry.blob_ref {
+            self.live.live_blob_bytes += blob_ref.value_size;
         }
     }
 
-    pub fn dead_bytes(&self) -> Vec<(u32, u64)> {
-        let mut vec: Vec<_> = self.map.iter().map(|e| (*e.0, e.1.dead_bytes)).collect();
-        vec.sort_by_key(|e| e.1);
-        vec
+    /// Resets the live stats, e.g. to account the entries of a checkpoint with `add_live_entry`.
+    pub fn clear_live(&mut self) {
+        self.live = StatsSnapshot::default();
+    }
+
+    /// Drops the stats of the data files from `watermark` on.
+    pub fn retain_files(&mut self, watermark: u32) {
+        self.map.retain(|&file_id, _| file_id < watermark);
+    }
+
+    /// Reverts the accounting of a live value in a blob file by `add_entry`.
+    pub fn unaccount_blob(&mut self, blob_ref: &BlobRef) {
+        let blob_entry = file_entry(&mut self.blob_map, blob_ref.file_id);
+        blob_entry.entries = blob_entry.entries.saturating_sub(1);
+        blob_entry.total_bytes = blob_entry.total_bytes.saturating_sub(blob_ref.value_size);
+    }
+
+    /// Adds the stats of `other`. The index keeps stats per shard, an entry might be removed from
+    /// a data file in the stats of another shard than the one it was added to.
+    pub fn merge(&mut self, other: &Stats) {
+        self.live.live_entries += other.live.live_entries;
+        self.live.live_bytes += other.live.live_bytes;
+        self.live.live_value_bytes += other.live.live_value_bytes;
+        self.live.live_blob_bytes += other.live.live_blob_bytes;
+
+        for (&file_id, other) in &other.map {
+            file_entry(&mut self.map, file_id).merge(other);
+        }
+
+        for (&file_id, other) in &other.blob_map {
+            file_entry(&mut self.blob_map, file_id).merge(other);
+        }
+    }
+
+    fn add(&mut self, entry: &IndexEntry, blob: bool) {
+        self.live.live_entries += 1;
+        self.live.live_bytes += entry.entry_size;
+        self.live.live_value_bytes += entry.value_size;
+
+        if let (true, Some(ref blob_ref)) = (blob, entry.blob_ref) {
+            self.live.live_blob_bytes += blob_ref.value_size;
+
+            let blob_entry = file_entry(&mut self.blob_map, blob_ref.file_id);
+            blob_entry.entries += 1;
+            blob_entry.total_bytes += blob_ref.value_size;
+        }
+
+        let file_entry = file_entry(&mut self.map, entry.file_id);
+        file_entry.entries += 1;
+        file_entry.total_bytes += entry.entry_size;
+    }
+
+    fn remove(&mut self, entry: &IndexEntry, blob: bool) {
+        // saturating, so that inconsistent stats don't underflow
+        self.live.live_entries = self.live.live_entries.saturating_sub(1);
+        self.live.live_bytes = self.live.live_bytes.saturating_sub(entry.entry_size);
+        self.live.live_value_bytes = self.live.live_value_bytes.saturating_sub(entry.value_size);
+
+        if let (true, Some(ref blob_ref)) = (blob, entry.blob_ref) {
+            self.live.live_blob_bytes =
+                self.live.live_blob_bytes.saturating_sub(blob_ref.value_size);
+
+            let blob_entry = file_entry(&mut self.blob_map, blob_ref.file_id);
+            blob_entry.dead_entries += 1;
+            blob_entry.dead_bytes += blob_ref.value_size;
+        }
+
+        let file_entry = file_entry(&mut self.map, entry.file_id);
+        file_entry.dead_entries += 1;
+        file_entry.dead_bytes += entry.entry_size;
     }
 
     pub fn fragmentation(&self) -> Vec<(u32, f64)> {
@@ -62,4 +182,139 @@ impl Stats {
         vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
         vec
     }
+
+    /// Returns the ratio of dead bytes in each blob file. Blob values vary widely in size so the
+    /// ratio is computed from bytes rather than from the number of entries. Blob files without
+    /// any entry are entirely dead.
+    pub fn blob_fragmentation(&self) -> Vec<(u32, f64)> {
+        let mut vec: Vec<_> = self.blob_map
+            .iter()
+            .map(|e| {
+                let fragmentation = if e.1.total_bytes > 0 {
+                    e.1.dead_bytes as f64 / e.1.total_bytes as f64
+                } else if e.1.entries > 0 {
+                    e.1.dead_entries as f64 / e.1.entries as f64
+                } else {
+                    1.0
+                };
+                (*e.0, fragmentation)
+            })
+            .collect();
+        vec.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        vec
+    }
+
+    pub fn remove_blob_file(&mut self, file_id: u32) {
+        self.blob_map.remove(&file_id);
+    }
+
+    pub fn write_bytes<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u64::<LittleEndian>(self.live.live_entries)?;
+        writer.write_u64::<LittleEndian>(self.live.live_bytes)?;
+        writer.write_u64::<LittleEndian>(self.live.live_value_bytes)?;
+        writer.write_u64::<LittleEndian>(self.live.live_blob_bytes)?;
+
+        for map in &[&self.map, &self.blob_map] {
+            writer.write_u32::<LittleEndian>(map.len() as u32)?;
+
+            for (&file_id, entry) in map.iter() {
+                writer.write_u32::<LittleEndian>(file_id)?;
+                writer.write_u64::<LittleEndian>(entry.entries)?;
+                writer.write_u64::<LittleEndian>(entry.dead_entries)?;
+                writer.write_u64::<LittleEndian>(entry.total_bytes)?;
+                writer.write_u64::<LittleEndian>(entry.dead_bytes)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn from_read<R: Read>(reader: &mut R) -> Result<Stats> {
+        let live = StatsSnapshot {
+            live_entries: reader.read_u64::<LittleEndian>()?,
+            live_bytes: reader.read_u64::<LittleEndian>()?,
+            live_value_bytes: reader.read_u64::<LittleEndian>()?,
+            live_blob_bytes: reader.read_u64::<LittleEndian>()?,
+            ..StatsSnapshot::default()
+        };
+
+        let mut maps = Vec::with_capacity(2);
+
+        for _ in 0..2 {
+            let len = reader.read_u32::<LittleEndian>()?;
+            let mut map = HashMap::with_capacity(len as usize);
+
+            for _ in 0..len {
+                let file_id = reader.read_u32::<LittleEndian>()?;
+                map.insert(file_id,
+                           StatsEntry {
+                               entries: reader.read_u64::<LittleEndian>()?,
+                               dead_entries: reader.read_u64::<LittleEndian>()?,
+                               total_bytes: reader.read_u64::<LittleEndian>()?,
+                               dead_bytes: reader.read_u64::<LittleEndian>()?,
+                           });
+            }
+
+            maps.push(map);
+        }
+
+        let blob_map = maps.pop().unwrap();
+        let map = maps.pop().unwrap();
+
+        Ok(Stats {
+            map,
+            blob_map,
+            live,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cask::IndexEntry;
+    use data::BlobRef;
+    use stats::Stats;
+
+    fn index_entry(file_id: u32, blob_file_id: Option<u32>) -> IndexEntry {
+        IndexEntry {
+            file_id,
+            entry_pos: 0,
+            entry_size: 50,
+            sequence: 0,
+            blob_ref: blob_file_id.map(|file_id| {
+                BlobRef {
+                    file_id,
+                    value_pos: 0,
+                    value_size: 1000,
+                }
+            }),
+            value_size: 1000,
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut stats = Stats::new();
+
+        stats.add_entry(&index_entry(0, Some(0)));
+        // the entry was copied to another data file along with its blob reference
+        stats.replace_entry(&index_entry(0, Some(0)), &index_entry(1, Some(0)));
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.live_entries, 1);
+        assert_eq!(snapshot.live_blob_bytes, 1000);
+        assert_eq!(stats.fragmentation(), vec![(0, 1.0), (1, 0.0)]);
+        assert_eq!(stats.blob_fragmentation(), vec![(0, 0.0)]);
+
+        stats.add_blob_file(1);
+        assert_eq!(stats.blob_fragmentation(), vec![(0, 0.0), (1, 1.0)]);
+
+        // removing an entry which was never added doesn't underflow
+        stats.remove_entry(&index_entry(1, Some(0)));
+        stats.remove_entry(&index_entry(1, Some(0)));
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.live_entries, 0);
+        assert_eq!(snapshot.live_bytes, 0);
+        assert_eq!(snapshot.live_blob_bytes, 0);
+    }
 }
@@ -1,29 +1,38 @@
 use std::fs::{File, OpenOptions};
-use std::io::{Result, Write};
+use std::io::{Read, Result, Write};
 use std::path::Path;
 use std::result::Result::Ok;
 
-use xxhash2::{hash32, State32};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc32c::crc32c_append;
+use xxhash2::{hash32, hash64, State32, State64};
 
-pub struct XxHash32(State32);
-
-impl XxHash32 {
-    pub fn new() -> XxHash32 {
-        let mut state = State32::new();
-        state.reset(0);
-        XxHash32(state)
-    }
+/// A running checksum over the bytes of a record.
+pub enum Hasher {
+    XxHash32(State32),
+    Crc32c(u32),
+    XxHash64(State64),
+}
 
+impl Hasher {
     pub fn update(&mut self, buf: &[u8]) {
-        self.0.update(buf);
+        match *self {
+            Hasher::XxHash32(ref mut state) => state.update(buf),
+            Hasher::Crc32c(ref mut crc) => *crc = crc32c_append(*crc, buf),
+            Hasher::XxHash64(ref mut state) => state.update(buf),
+        }
     }
 
-    pub fn get(&self) -> u32 {
-        self.0.finish()
+    pub fn get(&self) -> u64 {
+        match *self {
+            Hasher::XxHash32(ref state) => state.finish() as u64,
+            Hasher::Crc32c(crc) => crc as u64,
+            Hasher::XxHash64(ref state) => state.finish(),
+        }
     }
 }
 
-impl Write for XxHash32 {
+impl Write for Hasher {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
         self.update(buf);
         Ok(buf.len())
@@ -34,10 +43,175 @@ impl Write for XxHash32 {
     }
 }
 
+/// The checksum algorithm used to verify the entries of a file, it is recorded in the file header.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ChecksumType {
+    /// 32-bit xxHash, used by all files written before checksums were selectable.
+    #[default]
+    XxHash32,
+    /// CRC-32C, hardware accelerated on x86-64 and aarch64.
+    Crc32c,
+    /// 64-bit xxHash.
+    XxHash64,
+}
+
+impl ChecksumType {
+    pub fn id(&self) -> u8 {
+        match *self {
+            ChecksumType::XxHash32 => 0,
+            ChecksumType::Crc32c => 1,
+            ChecksumType::XxHash64 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<ChecksumType> {
+        match id {
+            0 => Some(ChecksumType::XxHash32),
+            1 => Some(ChecksumType::Crc32c),
+            2 => Some(ChecksumType::XxHash64),
+            _ => None,
+        }
+    }
+
+    /// The number of bytes used to store a checksum.
+    pub fn size(&self) -> usize {
+        match *self {
+            ChecksumType::XxHash32 | ChecksumType::Crc32c => 4,
+            ChecksumType::XxHash64 => 8,
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match *self {
+            ChecksumType::XxHash32 => {
+                let mut state = State32::new();
+                state.reset(0);
+                Hasher::XxHash32(state)
+            }
+            ChecksumType::Crc32c => Hasher::Crc32c(0),
+            ChecksumType::XxHash64 => {
+                let mut state = State64::new();
+                state.reset(0);
+                Hasher::XxHash64(state)
+            }
+        }
+    }
+
+    pub fn checksum(&self, buf: &[u8]) -> u64 {
+        let mut hasher = self.hasher();
+        hasher.update(buf);
+        hasher.get()
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W, checksum: u64) -> Result<()> {
+        match self.size() {
+            4 => writer.write_u32::<LittleEndian>(checksum as u32),
+            _ => writer.write_u64::<LittleEndian>(checksum),
+        }
+    }
+
+    pub fn read<R: Read>(&self, reader: &mut R) -> Result<u64> {
+        match self.size() {
+            4 => reader.read_u32::<LittleEndian>().map(|c| c as u64),
+            _ => reader.read_u64::<LittleEndian>(),
+        }
+    }
+}
+
 pub fn xxhash32(buf: &[u8]) -> u32 {
     hash32(buf, 0)
 }
 
+pub fn xxhash64(buf: &[u8]) -> u64 {
+    hash64(buf, 0)
+}
+
+/// Reads from `reader` until `buf` is full or the end of the reader is reached, returning the
+/// number of bytes read.
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    Ok(read)
+}
+
+/// Computes a running checksum over the bytes read from `reader`.
+pub struct ChecksumReader<R> {
+    reader: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub fn new(reader: R, hasher: Hasher) -> ChecksumReader<R> {
+        ChecksumReader {
+            reader,
+            hasher,
+        }
+    }
+
+    /// The checksum of the bytes read so far.
+    pub fn checksum(&self) -> u64 {
+        self.hasher.get()
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Reads from `file` starting at `pos` with positional reads, leaving the file cursor untouched so
+/// that the file can be shared between threads.
+pub struct PositionalReader<'a> {
+    file: &'a File,
+    pos: u64,
+}
+
+impl<'a> PositionalReader<'a> {
+    pub fn new(file: &'a File, pos: u64) -> PositionalReader<'a> {
+        PositionalReader {
+            file,
+            pos,
+        }
+    }
+}
+
+impl<'a> Read for PositionalReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = read_at(self.file, buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], pos: u64) -> Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, pos)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], pos: u64) -> Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, pos)
+}
+
 pub fn get_file_handle(path: &Path, write: bool) -> File {
     if write {
         OpenOptions::new()
@@ -53,3 +227,36 @@ pub fn get_file_handle(path: &Path, write: bool) -> File {
             .unwrap()
     }
 }
+
+/// A directory for the files of a test, emptied when created and removed when dropped. The
+/// directory name includes the process id so that concurrent test runs don't collide.
+#[cfg(test)]
+pub struct TestDir {
+    path: ::std::path::PathBuf,
+}
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> TestDir {
+        let path = ::std::env::temp_dir()
+            .join(format!("cask-test-{}-{}", name, ::std::process::id()));
+        let _ = ::std::fs::remove_dir_all(&path);
+        ::std::fs::create_dir_all(&path).unwrap();
+        TestDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn path_str(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = ::std::fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use data::SequenceNumber;
+
+struct CacheSlot {
+    /// Shared with the map of the cache.
+    key: Arc<[u8]>,
+    sequence: SequenceNumber,
+    value: Arc<[u8]>,
+    /// Set when the value is read, cleared as the clock hand passes over the slot.
+    referenced: bool,
+}
+
+impl CacheSlot {
+    fn size(&self) -> usize {
+        mem::size_of::<CacheSlot>() + self.key.len() + self.value.len()
+    }
+}
+
+struct ValueCacheInner {
+    map: HashMap<Arc<[u8]>, usize>,
+    slots: Vec<Option<CacheSlot>>,
+    free_slots: Vec<usize>,
+    hand: usize,
+    size: usize,
+}
+
+/// A cache of values, bounded by their size in bytes, evicting values with the CLOCK algorithm.
+/// Every value is cached along with the sequence number of its entry, so a value is only returned
+/// while the index still points to the same entry, even if it was moved by compaction.
+pub struct ValueCache {
+    capacity: usize,
+    inner: Mutex<ValueCacheInner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ValueCache {
+    pub fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            inner: Mutex::new(ValueCacheInner {
+                map: HashMap::new(),
+                slots: Vec::new(),
+                free_slots: Vec::new(),
+                hand: 0,
+                size: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached value of `key` if it was read from the entry numbered `sequence`. The
+    /// value is shared with the cache rather than copied.
+    pub fn get(&self, key: &[u8], sequence: SequenceNumber) -> Option<Arc<[u8]>> {
+        let value = {
+            let mut inner = self.inner.lock().unwrap();
+
+            match inner.map.get(key).copied() {
+                Some(idx) => {
+                    let slot = inner.slots[idx].as_mut().unwrap();
+                    if slot.sequence == sequence {
+                        slot.referenced = true;
+                        Some(slot.value.clone())
+                    } else {
+                        None
+                    }
+                }
+                None => None,
+            }
+        };
+
+        if value.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        value
+    }
+
+    /// Caches `value`, read from the entry of `key` numbered `sequence`, replacing any value
+    /// cached for `key`. Values which don't fit in the cache are not cached.
+    pub fn insert(&self, key: &[u8], sequence: SequenceNumber, value: &[u8]) {
+        let size = mem::size_of::<CacheSlot>() + key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+
+        let key: Arc<[u8]> = Arc::from(key);
+        let slot = CacheSlot {
+            key: key.clone(),
+            sequence,
+            value: Arc::from(value),
+            referenced: false,
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.remove(&key);
+
+        while inner.size + size > self.capacity {
+            inner.evict();
+        }
+
+        let idx = match inner.free_slots.pop() {
+            Some(idx) => idx,
+            None => {
+                inner.slots.push(None);
+                inner.slots.len() - 1
+            }
+        };
+
+        inner.slots[idx] = Some(slot);
+        inner.map.insert(key, idx);
+        inner.size += size;
+    }
+
+    /// Removes the value cached for `key`, if any.
+    pub fn remove(&self, key: &[u8]) {
+        self.inner.lock().unwrap().remove(key);
+    }
+
+    /// The number of lookups which found a value, and of those which didn't.
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+
+    /// The size in bytes of the cached values, along with their keys and some overhead.
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().size
+    }
+}
+
+impl ValueCacheInner {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(idx) = self.map.remove(key) {
+            let slot = self.slots[idx].take().unwrap();
+            self.size -= slot.size();
+            self.free_slots.push(idx);
+        }
+    }
+
+    /// Evicts the first value found by the clock hand which wasn't read since the hand last passed
+    /// over it.
+    fn evict(&mut self) {
+        loop {
+            self.hand = (self.hand + 1) % self.slots.len();
+
+            let evict = match self.slots[self.hand] {
+                Some(ref mut slot) if slot.referenced => {
+                    slot.referenced = false;
+                    false
+                }
+                Some(_) => true,
+                None => false,
+            };
+
+            if evict {
+                let key = self.slots[self.hand].as_ref().unwrap().key.clone();
+                self.remove(&key);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use value_cache::ValueCache;
+
+    #[test]
+    fn test_value_cache() {
+        let value = vec![0u8; 100];
+        let cache = ValueCache::new(2000);
+
+        for i in 0..10u8 {
+            cache.insert(&[i], 1, &value);
+        }
+
+        assert_eq!(cache.get(&[9], 1).as_deref(), Some(&value[..]));
+        assert_eq!(cache.get(&[9], 2), None);
+        assert!(cache.size() <= 2000);
+
+        // keys which are read survive eviction
+        for i in 10..100u8 {
+            assert!(cache.get(&[9], 1).is_some());
+            cache.insert(&[i], 1, &value);
+            assert!(cache.size() <= 2000);
+        }
+
+        assert!(cache.get(&[0], 1).is_none());
+        assert!(cache.get(&[99], 1).is_some());
+
+        cache.insert(&[9], 2, &[1u8; 10]);
+        assert_eq!(cache.get(&[9], 1), None);
+        assert_eq!(cache.get(&[9], 2).as_deref(), Some(&[1u8; 10][..]));
+
+        cache.remove(&[9]);
+        assert_eq!(cache.get(&[9], 2), None);
+
+        // values larger than the cache are not cached
+        cache.insert(&[9], 3, &[0u8; 2000]);
+        assert_eq!(cache.get(&[9], 3), None);
+
+        let (hits, misses) = cache.hits_and_misses();
+        assert_eq!(hits, 93);
+        assert_eq!(misses, 5);
+    }
+}